@@ -0,0 +1,112 @@
+//! Fluent configuration for `TwiBootloader`.
+//!
+//! Every option forwards to the setter of the same name (`set_` prefix
+//! dropped), see there for the details. Options that aren't set keep the
+//! defaults of `TwiBootloader::new()`.
+
+use std::time::Duration;
+
+use crate::i2c::I2CTransport;
+use crate::protocol::{AddressEndian, Radix, TwiBootloader, VerifyOrder};
+
+pub struct TwiBootloaderBuilder<T: I2CTransport> {
+    bootloader: TwiBootloader<T>,
+}
+
+// One forwarding method per setter, the doc comment names the default
+macro_rules! options {
+    ($($(#[$doc:meta])* $name:ident: $ty:ty => $setter:ident;)*) => {
+        $(
+            $(#[$doc])*
+            pub fn $name(mut self, value: $ty) -> Self {
+                self.bootloader.$setter(value);
+                self
+            }
+        )*
+    };
+}
+
+impl<T: I2CTransport> TwiBootloaderBuilder<T> {
+    pub fn new(i2c: T) -> Self {
+        TwiBootloaderBuilder {
+            bootloader: TwiBootloader::new(i2c),
+        }
+    }
+
+    pub fn build(self) -> TwiBootloader<T> {
+        self.bootloader
+    }
+
+    /// Where messages go instead of stdout. Default: printed to stdout
+    pub fn output(mut self, output: impl Fn(&str) + Send + 'static) -> Self {
+        self.bootloader.set_output(output);
+        self
+    }
+
+    options! {
+        /// Default: 100ms
+        switch_to_bootloader_delay: Duration => set_switch_to_bootloader_delay;
+        /// Default: none
+        connect_timeout: Option<Duration> => set_connect_timeout;
+        /// Default: 50ms
+        reentry_delay: Duration => set_reentry_delay;
+        /// How often connecting repeats the switch into the bootloader. Default: 1
+        switch_retries: usize => set_switch_retries;
+        /// Default: 256KB
+        max_flash_size: u32 => set_max_flash_size;
+        /// Default: 12 bytes
+        version_length: usize => set_version_length;
+        /// Default: none, any version is accepted
+        expect_version: Option<String> => set_expect_version;
+        /// Default: on
+        mask_version_msb: bool => set_mask_version_msb;
+        /// Default: off
+        show_raw_chipinfo: bool => set_show_raw_chipinfo;
+        /// Default: off
+        read_fuses: bool => set_read_fuses;
+        /// Default: off, a mismatch with the known part is only warned about
+        use_part_defaults: bool => set_use_part_defaults;
+        /// Default: none, the page size from chipinfo
+        write_page_size: Option<u32> => set_write_page_size;
+        /// Default: big endian
+        address_endian: AddressEndian => set_address_endian;
+        /// Default: 5ms
+        write_delay: Duration => set_write_delay;
+        /// Default: off
+        adaptive_write_delay: bool => set_adaptive_write_delay;
+        /// Default: off
+        reset_vector_last: bool => set_reset_vector_last;
+        /// Default: off
+        skip_unchanged: bool => set_skip_unchanged;
+        /// Default: 0xFF
+        flash_fill: u8 => set_flash_fill;
+        /// Default: off, the last page is padded with the flash fill byte
+        no_pad: bool => set_no_pad;
+        /// Default: off
+        no_final_pad: bool => set_no_final_pad;
+        /// Default: off
+        write_status: bool => set_write_status;
+        /// Default: off
+        lenient_erased: bool => set_lenient_erased;
+        /// Default: off
+        interleave_verify: bool => set_interleave_verify;
+        /// Default: 128 bytes
+        read_block_size: usize => set_read_block_size;
+        /// Default: off
+        verify_matches_write: bool => set_verify_matches_write;
+        /// Default: ascending
+        verify_order: VerifyOrder => set_verify_order;
+        /// Default: 2
+        verify_read_retries: usize => set_verify_read_retries;
+        /// Default: 10ms
+        verify_read_retry_delay: Duration => set_verify_read_retry_delay;
+        /// Default: off, verify stops at the first bad block
+        verify_report_all: bool => set_verify_report_all;
+        /// Default: on
+        switch_on_drop: bool => set_switch_on_drop;
+        /// Default: off
+        quiet: bool => set_quiet;
+        /// Default: hex and decimal mixed
+        radix: Radix => set_radix;
+    }
+}
@@ -0,0 +1,44 @@
+use std::ops::Range;
+
+/// Run of consecutive bytes that differ between the device and an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub addr: usize,
+    /// Bytes currently on the device
+    pub old: Vec<u8>,
+    /// Bytes from the image
+    pub new: Vec<u8>,
+}
+
+/// Compare `current` (device flash) with `image` inside the `defined` ranges of
+/// the image and return the differing runs in address order.
+///
+/// Bytes the image doesn't define (gaps between HEX records) are never reported,
+/// neither is anything past the end of `current`.
+pub fn diff(current: &[u8], image: &[u8], defined: &[Range<usize>]) -> Vec<Change> {
+    let mut changes: Vec<Change> = Vec::new();
+
+    for range in defined {
+        let end = range.end.min(image.len()).min(current.len());
+        for addr in range.start..end {
+            let (old, new) = (current[addr], image[addr]);
+            if old == new {
+                continue;
+            }
+
+            match changes.last_mut() {
+                Some(last) if last.addr + last.new.len() == addr => {
+                    last.old.push(old);
+                    last.new.push(new);
+                }
+                _ => changes.push(Change {
+                    addr,
+                    old: vec![old],
+                    new: vec![new],
+                }),
+            }
+        }
+    }
+
+    changes
+}
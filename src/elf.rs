@@ -0,0 +1,120 @@
+//! ELF reader for firmware images (`elf` feature), on top of the `object` crate.
+//!
+//! Only the program headers are looked at: every PT_LOAD segment with file
+//! content is placed at its physical (load) address, like `objcopy -O binary`
+//! does. 32 and 64 bit, little and big endian files are supported.
+
+use anyhow::{anyhow, Result};
+use object::elf::{FileClass, FileHeader32, FileHeader64, ELFCLASS32, ELFCLASS64, PT_LOAD};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::Endianness;
+use std::ops::Range;
+
+use crate::file_ops::{
+    merge_ranges, sort_segments, FirmwareImage, Segment, AVR_EEPROM_END, AVR_EEPROM_OFFSET,
+};
+
+// avr-gcc puts RAM, EEPROM, fuses, lock bits and signature at these offsets,
+// they're separate address spaces and never part of the flash image. EEPROM
+// segments are kept aside in `FirmwareImage::eeprom`, the rest is dropped.
+const AVR_NON_FLASH_START: u64 = 0x0080_0000;
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7FELF")
+}
+
+// A loadable segment: physical address and file content
+struct LoadSegment<'a> {
+    paddr: u64,
+    filesz: u64,
+    content: Result<&'a [u8], ()>,
+}
+
+// Entry point and PT_LOAD segments with file content, for either ELF class
+fn load_segments<Elf: FileHeader<Endian = Endianness>>(
+    data: &[u8],
+) -> Result<(u64, Vec<LoadSegment<'_>>)> {
+    let invalid = |e: object::Error| anyhow!("Invalid ELF file: {}", e);
+    let header = Elf::parse(data).map_err(invalid)?;
+    let endian = header.endian().map_err(invalid)?;
+
+    let segments = header
+        .program_headers(endian, data)
+        .map_err(invalid)?
+        .iter()
+        .filter(|ph| ph.p_type(endian) == PT_LOAD && ph.p_filesz(endian).into() != 0)
+        .map(|ph| LoadSegment {
+            paddr: ph.p_paddr(endian).into(),
+            filesz: ph.p_filesz(endian).into(),
+            content: ph.data(endian, data),
+        })
+        .collect();
+
+    Ok((header.e_entry(endian).into(), segments))
+}
+
+/// Flatten the loadable segments of an ELF file into a flash image starting at 0
+pub fn parse_elf_file(data: &[u8], flash_limit: u32) -> Result<FirmwareImage> {
+    if !is_elf(data) || data.len() < 16 {
+        return Err(anyhow!("Not an ELF file"));
+    }
+
+    let (entry, segments) = match FileClass(data[4]) {
+        ELFCLASS32 => load_segments::<FileHeader32<Endianness>>(data)?,
+        ELFCLASS64 => load_segments::<FileHeader64<Endianness>>(data)?,
+        FileClass(class) => return Err(anyhow!("Unknown ELF class {}", class)),
+    };
+
+    let mut result = vec![0xFF; flash_limit as usize];
+    let mut defined: Vec<Range<usize>> = Vec::new();
+    let mut eeprom: Vec<Segment> = Vec::new();
+
+    for segment in segments {
+        let paddr = segment.paddr;
+        // Offset and size come straight from the file, object checks them against its length
+        let content = segment
+            .content
+            .map_err(|()| anyhow!("ELF segment at 0x{:04X} lies outside the file", paddr));
+
+        if (AVR_EEPROM_OFFSET as u64..AVR_EEPROM_END as u64).contains(&paddr) {
+            eeprom.push(Segment {
+                start: (paddr - AVR_EEPROM_OFFSET as u64) as u32,
+                bytes: content?.to_vec(),
+            });
+            continue;
+        }
+        if paddr >= AVR_NON_FLASH_START {
+            continue;
+        }
+
+        let end = paddr.checked_add(segment.filesz).ok_or_else(|| {
+            anyhow!(
+                "ELF segment at 0x{:04X} has an invalid size of {} bytes",
+                paddr,
+                segment.filesz
+            )
+        })?;
+        if end > flash_limit as u64 {
+            return Err(anyhow!(
+                "ELF segment at 0x{:04X}..0x{:04X} exceeds available flash space (limit: 0x{:04X}).",
+                paddr,
+                end,
+                flash_limit
+            ));
+        }
+
+        let range = paddr as usize..end as usize;
+        result[range.clone()].copy_from_slice(content?);
+        defined.push(range);
+    }
+
+    let defined = merge_ranges(defined);
+    result.truncate(defined.last().map_or(0, |r| r.end));
+
+    Ok(FirmwareImage {
+        data: result,
+        defined,
+        start_address: u32::try_from(entry).ok().filter(|&entry| entry != 0),
+        eeprom: sort_segments(eeprom),
+    })
+}
@@ -1,11 +1,19 @@
 use anyhow::{Context, Result};
 use std::fs;
+use std::ops::Range;
 use std::path::Path;
 
+/// Where avr-gcc puts the `.eeprom` section in HEX and ELF files
+pub const AVR_EEPROM_OFFSET: u32 = 0x81_0000;
+/// End of the EEPROM address space (exclusive), fuses follow at 0x820000
+pub const AVR_EEPROM_END: u32 = 0x82_0000;
+
 #[derive(Debug, Clone, Copy)]
 pub enum FileFormat {
     Binary,
     Hex,
+    /// Loadable segments of an ELF file, needs the `elf` feature
+    Elf,
     Auto,
 }
 
@@ -14,49 +22,352 @@ impl FileFormat {
         match path.extension().and_then(|s| s.to_str()) {
             Some("hex") => FileFormat::Hex,
             Some("bin") => FileFormat::Binary,
+            Some("elf") => FileFormat::Elf,
             _ => FileFormat::Auto,
         }
     }
 }
 
+/// Flat flash image as it will be written, starting at address 0
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FirmwareImage {
+    pub data: Vec<u8>,
+    /// Address ranges the file actually provides data for (sorted, non-overlapping).
+    /// Everything else in `data` is 0xFF fill for gaps between HEX records.
+    pub defined: Vec<Range<usize>>,
+    /// Entry point from a HEX start address record (type 0x03 or 0x05), if any
+    pub start_address: Option<u32>,
+    /// EEPROM content found at the AVR EEPROM offset (0x810000), with addresses
+    /// relative to EEPROM address 0. Empty for files without an `.eeprom` section.
+    pub eeprom: Vec<Segment>,
+}
+
+impl FirmwareImage {
+    fn from_binary(data: Vec<u8>) -> Self {
+        let defined = std::iter::once(0..data.len())
+            .filter(|r| !r.is_empty())
+            .collect();
+        FirmwareImage {
+            data,
+            defined,
+            ..Default::default()
+        }
+    }
+
+    /// End of the highest data record (exclusive), i.e. the unpadded image length.
+    /// `data` is truncated here, the writer pads the last page on its own.
+    pub fn end_address(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Length of the image once the last page is filled up, as it ends up in flash
+    pub fn page_aligned_len(&self, pagesize: usize) -> usize {
+        self.data.len().next_multiple_of(pagesize.max(1))
+    }
+
+    /// Extend the image with `fill` up to `end`. The fill counts as defined, so it's
+    /// written and verified even with gap skipping or padding-ignoring verify.
+    pub fn fill_to(&mut self, end: usize, fill: u8) {
+        if end <= self.data.len() {
+            return;
+        }
+
+        let range = self.data.len()..end;
+        self.data.resize(end, fill);
+        self.defined.push(range);
+        self.defined = merge_ranges(std::mem::take(&mut self.defined));
+    }
+
+    /// Move the image up so it starts at `offset` instead of 0, e.g. a binary
+    /// dump of a high flash region. The new bytes below it are `fill` and not defined.
+    pub fn rebase(&mut self, offset: usize, fill: u8) {
+        self.data.splice(0..0, std::iter::repeat_n(fill, offset));
+        for range in &mut self.defined {
+            *range = range.start + offset..range.end + offset;
+        }
+    }
+
+    /// Set every byte the file doesn't define (the gaps between HEX records,
+    /// 0xFF after parsing) to `fill`
+    pub fn fill_gaps(&mut self, fill: u8) {
+        let mut pos = 0;
+        for range in &self.defined {
+            self.data[pos..range.start].fill(fill);
+            pos = range.end;
+        }
+        self.data[pos..].fill(fill);
+    }
+
+    /// The EEPROM segments as one block from the first to the last, gaps set to `fill`
+    pub fn eeprom_block(&self, fill: u8) -> Option<Segment> {
+        let start = self.eeprom.first()?.start;
+        let end = self.eeprom.iter().map(Segment::end).max()?;
+
+        let mut bytes = vec![fill; (end - start) as usize];
+        for segment in &self.eeprom {
+            let offset = (segment.start - start) as usize;
+            bytes[offset..offset + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+
+        Some(Segment { start, bytes })
+    }
+
+    /// Number of fill bytes in `data` that don't come from the file
+    pub fn padding_bytes(&self) -> usize {
+        self.data.len() - self.defined.iter().map(|r| r.len()).sum::<usize>()
+    }
+}
+
+/// Problems with an image that don't stop it from being written, but most
+/// likely leave a device that doesn't boot. Each entry is a readable message.
+pub fn image_warnings(image: &FirmwareImage) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    match image.data.get(..2) {
+        Some([0xFF, 0xFF]) => warnings.push(
+            "Reset vector at address 0 is erased (0xFF 0xFF), the vector table may be missing"
+                .to_string(),
+        ),
+        Some([0x00, 0x00]) => warnings.push(
+            "Reset vector at address 0 is all zero, the vector table may be missing".to_string(),
+        ),
+        None if !image.data.is_empty() => {
+            warnings.push("Image is too short to contain a reset vector".to_string())
+        }
+        _ => {}
+    }
+
+    warnings
+}
+
 pub fn read_file_with_bootloader_info(
     path: &Path,
     format: FileFormat,
     flash_limit: u32,
-) -> Result<Vec<u8>> {
+    max_flash_size: u32,
+) -> Result<FirmwareImage> {
+    if flash_limit > max_flash_size {
+        return Err(anyhow::anyhow!(
+            "Flash limit of {} bytes exceeds the maximum flash size of {} bytes",
+            flash_limit,
+            max_flash_size
+        ));
+    }
+
     let data =
         fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
 
-    match format {
-        FileFormat::Binary => Ok(data),
-        FileFormat::Hex => parse_hex_file(&data, Some(flash_limit)),
-        FileFormat::Auto => {
-            // Try to detect format
-            if data.starts_with(b":") {
-                parse_hex_file(&data, Some(flash_limit))
-            } else {
-                Ok(data)
-            }
+    let image = match format {
+        FileFormat::Binary => FirmwareImage::from_binary(data),
+        FileFormat::Hex => parse_hex_file(&data, Some(flash_limit))?,
+        FileFormat::Elf => parse_elf_file(&data, flash_limit)?,
+        FileFormat::Auto if data.is_empty() => FirmwareImage::default(),
+        FileFormat::Auto => match detect_format(&data)? {
+            FileFormat::Hex => parse_hex_file(&data, Some(flash_limit))?,
+            FileFormat::Elf => parse_elf_file(&data, flash_limit)?,
+            _ => FirmwareImage::from_binary(data),
+        },
+    };
+
+    // Flashing nothing would "succeed" and hide the real problem with the file
+    if image.data.is_empty() {
+        return Err(anyhow::anyhow!(
+            "File contains no data to flash: {}",
+            path.display()
+        ));
+    }
+
+    Ok(image)
+}
+
+// Number of non-blank lines looked at when sniffing a text file
+const DETECT_LINES: usize = 5;
+// Number of bytes checked for binary content
+const DETECT_BYTES: usize = 512;
+
+/// Figure out whether `data` is Intel HEX or raw binary.
+///
+/// Anything with non-text bytes near the start is binary. Text needs at least one
+/// well-formed HEX record (checksum included) among the first few non-blank lines,
+/// otherwise we refuse to guess: flashing a text file raw would brick the app.
+fn detect_format(data: &[u8]) -> Result<FileFormat> {
+    // Flashing an ELF file raw would never work, so it's recognized even without the feature
+    if data.starts_with(b"\x7FELF") {
+        return Ok(FileFormat::Elf);
+    }
+
+    let text = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+
+    let is_text = text
+        .iter()
+        .take(DETECT_BYTES)
+        .all(|&b| b.is_ascii_graphic() || b" \t\r\n".contains(&b));
+    if !is_text {
+        return Ok(FileFormat::Binary);
+    }
+
+    let lines = text
+        .split(|&b| b == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty())
+        .take(DETECT_LINES);
+
+    for line in lines {
+        if is_hex_record(line) {
+            return Ok(FileFormat::Hex);
+        }
+        if is_srec_record(line) {
+            return Err(anyhow::anyhow!(
+                "File looks like Motorola S-record, which is not supported. Convert it to Intel HEX or binary."
+            ));
         }
     }
+
+    Err(anyhow::anyhow!(
+        "Cannot detect file format (text file without valid Intel HEX records). Use --format hex or --format bin."
+    ))
 }
 
-fn parse_hex_file(data: &[u8], flash_limit: Option<u32>) -> Result<Vec<u8>> {
-    let content = String::from_utf8(data.to_vec()).context("Invalid UTF-8 in hex file")?;
+fn is_hex_record(line: &[u8]) -> bool {
+    let Some(digits) = line.strip_prefix(b":") else {
+        return false;
+    };
+    if digits.len() < 10 || digits.len() % 2 != 0 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return false;
+    }
 
-    // Use provided flash limit or default to ATtiny84 layout for backward compatibility
-    let flash_limit = flash_limit.unwrap_or(0x1C00);
-    let max_app_size = flash_limit as usize;
+    let bytes: Vec<u8> = digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect();
 
-    let mut result = vec![0xFF; max_app_size]; // Initialize with 0xFF (erased flash)
-    let mut max_address = 0u32;
+    // byte count + address + type + data + checksum, all bytes sum to zero
+    bytes.len() == bytes[0] as usize + 5
+        && bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn is_srec_record(line: &[u8]) -> bool {
+    line.len() >= 4
+        && line[0] == b'S'
+        && line[1].is_ascii_digit()
+        && line[2..].iter().all(u8::is_ascii_hexdigit)
+}
+
+/// Contiguous run of bytes from a HEX file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub start: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> u32 {
+        self.start + self.bytes.len() as u32
+    }
+}
+
+/// Parse Intel HEX into its address segments without flattening them.
+///
+/// Adjacent data records are joined, gaps between them start a new segment.
+/// The result is sorted by start address. Unlike the flattening parser there is
+/// no flash limit, the caller decides what to do with each segment.
+pub fn parse_hex_segments(data: &[u8]) -> Result<Vec<Segment>> {
+    let mut segments: Vec<Segment> = Vec::new();
 
-    for line in content.lines() {
-        let line = line.trim();
-        if line.is_empty() || !line.starts_with(':') {
+    for record in parse_hex_records(data)? {
+        if record.record_type != 0x00 || record.data.is_empty() {
             continue;
         }
 
+        match segments.last_mut() {
+            Some(last) if last.end() == record.address => last.bytes.extend(record.data),
+            _ => segments.push(Segment {
+                start: record.address,
+                bytes: record.data,
+            }),
+        }
+    }
+
+    Ok(sort_segments(segments))
+}
+
+pub(crate) fn sort_segments(mut segments: Vec<Segment>) -> Vec<Segment> {
+    segments.sort_by_key(|segment| segment.start);
+    segments
+}
+
+// Data bytes per record written by to_hex(), what avr-objcopy uses
+const HEX_RECORD_LEN: usize = 16;
+
+/// Encode `data` as Intel HEX starting at address 0.
+///
+/// Digits are upper case as the format convention has it (the parser takes
+/// either case). Extended linear address records are emitted at every 64KB
+/// boundary, the file ends with the EOF record.
+pub fn to_hex(data: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (i, chunk) in data.chunks(HEX_RECORD_LEN).enumerate() {
+        let addr = i * HEX_RECORD_LEN;
+        if addr > 0 && addr.is_multiple_of(0x10000) {
+            let upper = ((addr >> 16) as u16).to_be_bytes();
+            push_hex_record(&mut out, 0x04, 0, &upper);
+        }
+        push_hex_record(&mut out, 0x00, addr as u16, chunk);
+    }
+
+    push_hex_record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+fn push_hex_record(out: &mut String, record_type: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    bytes.push(checksum);
+
+    out.push(':');
+    for byte in bytes {
+        out.push_str(&format!("{:02X}", byte));
+    }
+    out.push('\n');
+}
+
+struct HexRecord {
+    record_type: u8,
+    address: u32,
+    data: Vec<u8>,
+}
+
+// All records up to the EOF record, lines that aren't records or are cut short are skipped.
+// Extended segment (0x02) and linear (0x04) address records are applied to the
+// data record addresses and are not returned themselves.
+//
+// Scans bytes rather than requiring UTF-8, so a BOM, non-ASCII comment lines or
+// binary junk after the EOF record don't matter. Record lines must be plain ASCII.
+fn parse_hex_records(data: &[u8]) -> Result<Vec<HexRecord>> {
+    let content = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    let mut records = Vec::new();
+    let mut base = 0u32;
+
+    for (number, line) in content.split(|&b| b == b'\n').enumerate() {
+        let line = line.trim_ascii();
+        if line.is_empty() || !line.starts_with(b":") {
+            continue;
+        }
+
+        let line = std::str::from_utf8(line)
+            .ok()
+            .filter(|line| line.is_ascii())
+            .ok_or_else(|| {
+                anyhow::anyhow!("Invalid characters in hex record on line {}", number + 1)
+            })?;
+
         let hex_data = &line[1..]; // Remove ':'
         if hex_data.len() < 8 {
             continue; // Skip invalid lines
@@ -71,13 +382,73 @@ fn parse_hex_file(data: &[u8], flash_limit: Option<u32>) -> Result<Vec<u8>> {
         let record_type =
             u8::from_str_radix(&hex_data[6..8], 16).context("Invalid record type in hex file")?;
 
-        match record_type {
+        if record_type == 0x01 {
+            // End of file record
+            break;
+        }
+
+        let data_start = 8;
+        let data_end = data_start + (byte_count as usize * 2);
+        if hex_data.len() < data_end {
+            continue; // Skip truncated records
+        }
+
+        let mut data = Vec::with_capacity(byte_count as usize);
+        for byte_offset in (data_start..data_end).step_by(2) {
+            let byte_str = &hex_data[byte_offset..byte_offset + 2];
+            data.push(u8::from_str_radix(byte_str, 16).context("Invalid data byte in hex file")?);
+        }
+
+        match (record_type, data.as_slice()) {
+            (0x02, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u32) << 4,
+            (0x04, &[high, low]) => base = (u16::from_be_bytes([high, low]) as u32) << 16,
+            (0x02 | 0x04, _) => {
+                return Err(anyhow::anyhow!(
+                    "Invalid extended address record (type 0x{:02X}) on line {}",
+                    record_type,
+                    number + 1
+                ))
+            }
+            _ => records.push(HexRecord {
+                record_type,
+                address: base.wrapping_add(address),
+                data,
+            }),
+        }
+    }
+
+    Ok(records)
+}
+
+fn parse_hex_file(data: &[u8], flash_limit: Option<u32>) -> Result<FirmwareImage> {
+    // Use provided flash limit or default to ATtiny84 layout for backward compatibility
+    let flash_limit = flash_limit.unwrap_or(0x1C00);
+    let max_app_size = flash_limit as usize;
+
+    let mut result = vec![0xFF; max_app_size]; // Initialize with 0xFF (erased flash)
+    let mut max_address = 0u32;
+    let mut defined: Vec<Range<usize>> = Vec::new();
+    let mut start_address = None;
+    let mut eeprom: Vec<Segment> = Vec::new();
+
+    for record in parse_hex_records(data)? {
+        let address = record.address;
+        let byte_count = record.data.len() as u32;
+
+        match record.record_type {
+            0x00 if (AVR_EEPROM_OFFSET..AVR_EEPROM_END).contains(&address) => {
+                // EEPROM data from a combined avr-gcc HEX, kept apart from the flash image
+                let start = address - AVR_EEPROM_OFFSET;
+                match eeprom.last_mut() {
+                    Some(last) if last.end() == start => last.bytes.extend(record.data),
+                    _ => eeprom.push(Segment {
+                        start,
+                        bytes: record.data,
+                    }),
+                }
+            }
             0x00 => {
                 // Data record
-                if hex_data.len() < (8 + byte_count as usize * 2) {
-                    continue; // Skip invalid data records
-                }
-
                 // Check if address conflicts with bootloader space
                 if address >= flash_limit {
                     return Err(anyhow::anyhow!(
@@ -86,25 +457,35 @@ fn parse_hex_file(data: &[u8], flash_limit: Option<u32>) -> Result<Vec<u8>> {
                     ));
                 }
 
-                let data_start = 8;
-                let data_end = data_start + (byte_count as usize * 2);
-
-                for (i, byte_offset) in (data_start..data_end).step_by(2).enumerate() {
-                    let byte_str = &hex_data[byte_offset..byte_offset + 2];
-                    let byte = u8::from_str_radix(byte_str, 16)
-                        .context("Invalid data byte in hex file")?;
-
+                for (i, &byte) in record.data.iter().enumerate() {
                     let target_addr = address + i as u32;
                     if target_addr < flash_limit {
                         result[target_addr as usize] = byte;
                     }
                 }
 
-                max_address = max_address.max(address + byte_count as u32);
+                let end = (address + byte_count).min(flash_limit);
+                defined.push(address as usize..end as usize);
+
+                max_address = max_address.max(address + byte_count);
             }
-            0x01 => {
-                // End of file record
-                break;
+            0x03 | 0x05 => {
+                // Start Segment Address (CS:IP) / Start Linear Address (EIP), 4 data bytes.
+                // Irrelevant for flashing, only reported.
+                let Ok(bytes) = <[u8; 4]>::try_from(record.data.as_slice()) else {
+                    return Err(anyhow::anyhow!(
+                        "Invalid start address record (type 0x{:02X}) in hex file",
+                        record.record_type
+                    ));
+                };
+
+                let value = u32::from_be_bytes(bytes);
+                start_address = Some(if record.record_type == 0x03 {
+                    // CS:IP real mode address -> linear
+                    ((value >> 16) << 4) + (value & 0xFFFF)
+                } else {
+                    value
+                });
             }
             _ => {
                 // Skip other record types
@@ -117,5 +498,37 @@ fn parse_hex_file(data: &[u8], flash_limit: Option<u32>) -> Result<Vec<u8>> {
     let actual_size = (max_address as usize).min(max_app_size);
     result.truncate(actual_size);
 
-    Ok(result)
+    Ok(FirmwareImage {
+        data: result,
+        defined: merge_ranges(defined),
+        start_address,
+        eeprom: sort_segments(eeprom),
+    })
+}
+
+#[cfg(feature = "elf")]
+fn parse_elf_file(data: &[u8], flash_limit: u32) -> Result<FirmwareImage> {
+    crate::elf::parse_elf_file(data, flash_limit)
+}
+
+#[cfg(not(feature = "elf"))]
+fn parse_elf_file(_data: &[u8], _flash_limit: u32) -> Result<FirmwareImage> {
+    Err(anyhow::anyhow!(
+        "ELF support is not compiled in, rebuild with `--features elf` or convert the file with objcopy"
+    ))
+}
+
+pub(crate) fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.retain(|r| !r.is_empty());
+    ranges.sort_by_key(|r| r.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
 }
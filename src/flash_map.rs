@@ -0,0 +1,60 @@
+use std::ops::Range;
+
+/// Run of consecutive pages that are either all used or all free
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub used: bool,
+}
+
+impl Region {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Page granular map of the application flash `0..flash_end`.
+///
+/// A page counts as used as soon as one of the `used` ranges touches it, since
+/// twiboot can only write whole pages. Adjacent pages with the same state are
+/// merged into one region.
+pub fn page_map(used: &[Range<usize>], pagesize: usize, flash_end: usize) -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+
+    for start in (0..flash_end).step_by(pagesize.max(1)) {
+        let end = (start + pagesize).min(flash_end);
+        let page_used = used
+            .iter()
+            .any(|range| range.start < end && start < range.end);
+
+        match regions.last_mut() {
+            Some(last) if last.used == page_used => last.end = end,
+            _ => regions.push(Region {
+                start,
+                end,
+                used: page_used,
+            }),
+        }
+    }
+
+    regions
+}
+
+/// Page ranges of a flash dump that contain anything but erased (0xFF) bytes
+pub fn used_pages(flash: &[u8], pagesize: usize) -> Vec<Range<usize>> {
+    flash
+        .chunks(pagesize.max(1))
+        .enumerate()
+        .filter(|(_, page)| page.iter().any(|&b| b != 0xFF))
+        .map(|(i, page)| i * pagesize..i * pagesize + page.len())
+        .collect()
+}
+
+pub fn free_bytes(regions: &[Region]) -> usize {
+    regions.iter().filter(|r| !r.used).map(Region::len).sum()
+}
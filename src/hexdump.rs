@@ -0,0 +1,52 @@
+use std::fmt::Write;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Classic addressed hexdump, 16 bytes per line with an ASCII column.
+///
+/// Runs of identical lines (typically erased 0xFF flash) are collapsed into a
+/// single `*` line like `hexdump -C` does. `base` is the address of `data[0]`.
+pub fn hexdump(data: &[u8], base: u32) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+
+    for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let is_last = (i + 1) * BYTES_PER_LINE >= data.len();
+
+        if previous == Some(line) && !is_last {
+            if !collapsed {
+                out.push_str("*\n");
+                collapsed = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        collapsed = false;
+
+        let _ = write!(out, "{:08X}: ", base as usize + i * BYTES_PER_LINE);
+        for col in 0..BYTES_PER_LINE {
+            match line.get(col) {
+                Some(byte) => {
+                    let _ = write!(out, "{:02X} ", byte);
+                }
+                None => out.push_str("   "),
+            }
+            if col == BYTES_PER_LINE / 2 - 1 {
+                out.push(' ');
+            }
+        }
+
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+
+    out
+}
@@ -1,57 +1,478 @@
 use anyhow::{Result, Context};
-use i2cdev::linux::LinuxI2CDevice;
-use i2cdev::core::I2CDevice;
+use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 use std::thread;
 
+use crate::hexdump::hexdump;
+
 const WRITE_RETRY_COUNT: usize = 50;
 const WRITE_RETRY_DELAY_MS: u64 = 2;
+const READ_RETRY_COUNT: usize = 3;
+const READ_RETRY_DELAY_MS: u64 = 2;
+// Retry delay after losing arbitration, long enough for the other master to finish a transfer
+const ARBITRATION_RETRY_DELAY_MS: u64 = 10;
+
+// ioctl from <linux/i2c-dev.h>, argument is in units of 10ms
+const I2C_TIMEOUT: libc::c_ulong = 0x0702;
+// ioctl from <linux/i2c-dev.h>, fills in the adapter's I2C_FUNC_* bits
+const I2C_FUNCS: libc::c_ulong = 0x0705;
+// Plain I2C transfers of any length, SMBus-only adapters lack it
+const I2C_FUNC_I2C: libc::c_ulong = 0x0000_0001;
+
+/// Byte-level transport the bootloader protocol runs on.
+///
+/// `TwiI2CDevice` talks to a real `/dev/i2c-N` adapter; other implementations
+/// (e.g. the simulator) let the protocol run without hardware.
+pub trait I2CTransport {
+    /// 7-bit slave address of the bootloader
+    fn address(&self) -> u8;
+
+    /// Write a complete transaction, retrying while the slave doesn't acknowledge
+    fn write_with_retry(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Fill `buffer` from the slave, retrying transient failures
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+    /// Single write+read attempt without any retries, used for presence checks
+    fn write_then_read_once(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<()>;
+
+    fn write_then_read(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<usize> {
+        self.write_with_retry(write_data)?;
+        self.read(read_buffer)
+    }
+
+    fn write_large_data(&mut self, data: &[u8]) -> Result<()> {
+        self.write_with_retry(data)
+    }
+
+    /// Total number of retries spent so far, callers diff it around an operation
+    fn retries(&self) -> usize {
+        0
+    }
+
+    /// How many of `retries()` were caused by another master winning the bus
+    fn arbitration_losses(&self) -> usize {
+        0
+    }
+}
+
+impl<T: I2CTransport + ?Sized> I2CTransport for Box<T> {
+    fn address(&self) -> u8 {
+        (**self).address()
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> Result<()> {
+        (**self).write_with_retry(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read(buffer)
+    }
+
+    fn write_then_read_once(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<()> {
+        (**self).write_then_read_once(write_data, read_buffer)
+    }
+
+    fn write_then_read(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<usize> {
+        (**self).write_then_read(write_data, read_buffer)
+    }
+
+    fn write_large_data(&mut self, data: &[u8]) -> Result<()> {
+        (**self).write_large_data(data)
+    }
+
+    fn retries(&self) -> usize {
+        (**self).retries()
+    }
+
+    fn arbitration_losses(&self) -> usize {
+        (**self).arbitration_losses()
+    }
+}
+
+/// The raw transfers `TwiI2CDevice` retries: a `/dev/i2c-N` handle, or a
+/// stand-in that fails with chosen errno values to exercise the retry logic
+pub trait RawI2CDevice {
+    fn write(&mut self, data: &[u8]) -> Result<(), LinuxI2CError>;
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), LinuxI2CError>;
+}
 
-pub struct TwiI2CDevice {
-    device: LinuxI2CDevice,
+impl RawI2CDevice for LinuxI2CDevice {
+    fn write(&mut self, data: &[u8]) -> Result<(), LinuxI2CError> {
+        i2cdev::core::I2CDevice::write(self, data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), LinuxI2CError> {
+        i2cdev::core::I2CDevice::read(self, buffer)
+    }
+}
+
+pub struct TwiI2CDevice<D: RawI2CDevice = LinuxI2CDevice> {
+    device: D,
     pub address: u8,
+    retries: usize,
+    arbitration_losses: usize,
+    retry_limit: Option<usize>,
+    retry_delay: Option<Duration>,
+    retry_budget: Option<usize>,
+    hexdump_on_error: bool,
+    _lock: Option<Arc<BusLock>>,
+}
+
+// The advisory lock on a bus node. A flock() belongs to the open file it was
+// taken on, so a second handle would find the bus locked by its own process;
+// all handles to a bus share one lock instead, released with the last of them.
+struct BusLock {
+    _file: File,
+}
+
+static BUS_LOCKS: Mutex<Vec<(String, Weak<BusLock>)>> = Mutex::new(Vec::new());
+
+fn lock_bus(device_path: &str) -> Result<Arc<BusLock>> {
+    let mut locks = BUS_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    locks.retain(|(_, lock)| lock.strong_count() > 0);
+    if let Some(lock) = locks.iter().filter(|(path, _)| path == device_path).find_map(|(_, lock)| lock.upgrade()) {
+        return Ok(lock);
+    }
+
+    let file = File::open(device_path)
+        .with_context(|| format!("Failed to open I2C device: {}", device_path))?;
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| format!(
+            "{} is in use by another process (bus lock taken), try again later or use --no-lock",
+            device_path
+        ));
+    }
+
+    let lock = Arc::new(BusLock { _file: file });
+    locks.push((device_path.to_string(), Arc::downgrade(&lock)));
+    Ok(lock)
 }
 
 impl TwiI2CDevice {
+    /// Open the bus and take its advisory lock, see `open()`
     pub fn new(device_path: &str, address: u8) -> Result<Self> {
+        Self::open(device_path, address, true)
+    }
+
+    /// Open the bus for `address`. With `lock`, an exclusive `flock()` on the device
+    /// node keeps other processes that lock it too from interleaving transactions.
+    /// Handles of this process to the same bus (a mux and the devices behind it,
+    /// several monitored devices) share the lock; it's released when the last of
+    /// them is dropped. Fails right away if another process holds it.
+    pub fn open(device_path: &str, address: u8, lock: bool) -> Result<Self> {
+        let lock = if lock { Some(lock_bus(device_path)?) } else { None };
         let device = LinuxI2CDevice::new(device_path, address as u16)
             .with_context(|| format!("Failed to open I2C device: {}", device_path))?;
 
-        Ok(TwiI2CDevice { device, address })
+        let i2c = TwiI2CDevice { device, address, retries: 0, arbitration_losses: 0, retry_limit: None, retry_delay: None, retry_budget: None, hexdump_on_error: false, _lock: lock };
+        i2c.check_functionality(device_path)?;
+
+        Ok(i2c)
+    }
+
+    // Page writes are single transfers of pagesize + header bytes, which only adapters
+    // with full I2C support can do. Fail here instead of on the first page write.
+    fn check_functionality(&self, device_path: &str) -> Result<()> {
+        let mut funcs: libc::c_ulong = 0;
+        let ret = unsafe { libc::ioctl(self.device.as_raw_fd(), I2C_FUNCS as _, &mut funcs) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to query I2C adapter functionality: {}", device_path));
+        }
+
+        if funcs & I2C_FUNC_I2C == 0 {
+            return Err(anyhow::anyhow!(
+                "I2C adapter {} only supports SMBus transfers, flashing needs plain I2C writes of a whole page",
+                device_path
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether something acknowledges a one byte read at the address, the read-only
+    /// probe of `i2cdetect -r`. Nothing is written and nothing is retried.
+    pub fn is_present(&mut self) -> bool {
+        let mut buffer = [0u8];
+        self.device.read(&mut buffer).is_ok()
+    }
+
+    /// Override the adapter timeout (`I2C_TIMEOUT`), so a stuck transfer gives up
+    /// after `timeout` instead of the driver's default. The setting belongs to the
+    /// adapter, it applies to every user of the bus until changed again.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        // Round up to the 10ms granularity of the kernel, but never pass 0 (= driver default)
+        let units = timeout.as_millis().div_ceil(10).max(1) as libc::c_ulong;
+
+        let ret = unsafe { libc::ioctl(self.device.as_raw_fd(), I2C_TIMEOUT as _, units) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to set I2C adapter timeout");
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: RawI2CDevice> TwiI2CDevice<D> {
+    /// Use an already open `device` for `address`, nothing is checked or locked
+    pub fn with_device(device: D, address: u8) -> Self {
+        TwiI2CDevice { device, address, retries: 0, arbitration_losses: 0, retry_limit: None, retry_delay: None, retry_budget: None, hexdump_on_error: false, _lock: None }
+    }
+
+    /// Override how often a single transaction is retried, `None` keeps the
+    /// defaults of 50 retries per write and 3 per read
+    pub fn set_retries(&mut self, retries: Option<usize>) {
+        self.retry_limit = retries;
+    }
+
+    /// Override the pause before a retry (default 2ms). After lost arbitration
+    /// the longer pause for the other master stays.
+    pub fn set_retry_delay(&mut self, delay: Option<Duration>) {
+        self.retry_delay = delay;
+    }
+
+    /// Cap the retries of all transactions together, on top of the per-call limits.
+    /// Once used up, the next failure aborts right away.
+    pub fn set_retry_budget(&mut self, budget: Option<usize>) {
+        self.retry_budget = budget;
+    }
+
+    fn check_retry_budget(&self) -> Result<()> {
+        match self.retry_budget {
+            Some(budget) if self.retries >= budget => Err(RetryBudgetExhausted(budget).into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Count a retryable error and wait before the next attempt
+    fn back_off(&mut self, error: &LinuxI2CError, default_delay_ms: u64) {
+        self.retries += 1;
+        if is_arbitration_lost(error) {
+            self.arbitration_losses += 1;
+            thread::sleep(Duration::from_millis(ARBITRATION_RETRY_DELAY_MS));
+        } else {
+            thread::sleep(self.retry_delay.unwrap_or(Duration::from_millis(default_delay_ms)));
+        }
     }
 
-    pub fn write_with_retry(&mut self, data: &[u8]) -> Result<()> {
-        let mut retries = WRITE_RETRY_COUNT;
+    /// Append a hexdump of the bytes sent (and how many were to be read back) to
+    /// the message of a failed transfer, to tell from a log which command it was
+    pub fn set_hexdump_on_error(&mut self, enabled: bool) {
+        self.hexdump_on_error = enabled;
+    }
+
+    // The error with what was on the wire, when enabled
+    fn annotate(&self, error: anyhow::Error, sent: &[u8], read_len: Option<usize>) -> anyhow::Error {
+        // These have to stay recognizable, see retry_is_pointless()
+        if !self.hexdump_on_error || retry_is_pointless(&error) {
+            return error;
+        }
+
+        let dump = || hexdump(sent, 0).trim_end().to_string();
+        let wire = match (sent.is_empty(), read_len) {
+            (true, len) => format!("Reading {} bytes", len.unwrap_or(0)),
+            (false, None) => format!("Sent {} bytes:\n{}", sent.len(), dump()),
+            (false, Some(len)) => format!("Sent {} bytes, expected {} back:\n{}", sent.len(), len, dump()),
+        };
+        anyhow::anyhow!("{:#}\n{}", error, wire)
+    }
+
+    fn write_inner(&mut self, data: &[u8]) -> Result<()> {
+        let limit = self.retry_limit.unwrap_or(WRITE_RETRY_COUNT);
+        let mut retries = limit;
         
         loop {
             match self.device.write(data) {
                 Ok(_) => return Ok(()),
+                // A signal interrupted the syscall, nothing happened on the bus
+                Err(e) if is_interrupted(&e) => continue,
+                Err(e) if is_fatal(&e) => return Err(FatalI2CError(format!("I2C write failed: {}", e)).into()),
+                Err(e) => {
+                    // Most errors are retryable (slave not acknowledging, timeouts)
+                    if retries == 0 {
+                        return Err(anyhow::anyhow!("I2C write failed after {} retries: {}", limit, e));
+                    }
+                    self.check_retry_budget().with_context(|| format!("I2C write failed: {}", e))?;
+                    self.back_off(&e, WRITE_RETRY_DELAY_MS);
+                }
+            }
+
+            retries -= 1;
+        }
+    }
+
+    fn read_inner(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let limit = self.retry_limit.unwrap_or(READ_RETRY_COUNT);
+        let mut retries = limit;
+
+        loop {
+            match self.device.read(buffer) {
+                Ok(()) => return Ok(buffer.len()),
+                Err(e) if is_interrupted(&e) => continue,
+                Err(e) if is_fatal(&e) => return Err(FatalI2CError(format!("I2C read failed: {}", e)).into()),
                 Err(e) => {
-                    // For I2C, most errors are retryable (slave not acknowledging, etc.)
-                    // Only fail immediately for truly fatal errors
+                    // Timeouts end up here as well, once the adapter's timeout (see set_timeout()) expires
                     if retries == 0 {
-                        return Err(anyhow::anyhow!("I2C write failed after {} retries: {}", WRITE_RETRY_COUNT, e));
+                        return Err(anyhow::anyhow!("I2C read failed after {} retries: {}", limit, e));
                     }
+                    self.check_retry_budget().with_context(|| format!("I2C read failed: {}", e))?;
+                    self.back_off(&e, READ_RETRY_DELAY_MS);
                 }
             }
 
             retries -= 1;
-            thread::sleep(Duration::from_millis(WRITE_RETRY_DELAY_MS));
         }
     }
+}
+
+/// The retry budget of `TwiI2CDevice::set_retry_budget()` is used up
+#[derive(Debug)]
+pub struct RetryBudgetExhausted(pub usize);
 
-    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
-        self.device.read(buffer)
-            .context("Failed to read from I2C device")?;
-        Ok(buffer.len())
+impl std::fmt::Display for RetryBudgetExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "I2C retry budget of {} exhausted", self.0)
     }
+}
 
-    pub fn write_then_read(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<usize> {
-        self.write_with_retry(write_data)?;
-        self.read(read_buffer)
+impl std::error::Error for RetryBudgetExhausted {}
+
+/// Whether `error` is (or wraps) `RetryBudgetExhausted`. Retries above the
+/// transport have to give up on it too, the budget covers the whole run.
+pub fn is_retry_budget_exhausted(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<RetryBudgetExhausted>())
+}
+
+/// A transfer failed in a way no retry can fix (see `is_fatal()`), e.g. the
+/// adapter is gone
+#[derive(Debug)]
+pub struct FatalI2CError(pub String);
+
+impl std::fmt::Display for FatalI2CError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
+}
 
-    pub fn write_large_data(&mut self, data: &[u8]) -> Result<()> {
-        self.write_with_retry(data)
+impl std::error::Error for FatalI2CError {}
+
+/// Whether retrying the step that failed with `error` can't help: the adapter
+/// can't do the transfer at all, or the retry budget of the run is used up.
+/// Retry loops above the transport give up on these.
+pub fn retry_is_pointless(error: &anyhow::Error) -> bool {
+    is_retry_budget_exhausted(error) || error.chain().any(|cause| cause.is::<FatalI2CError>())
+}
+
+/// Whether `error` comes from `TwiI2CDevice::open()` finding the bus locked
+pub fn is_bus_locked(error: &anyhow::Error) -> bool {
+    error
+        .root_cause()
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+// EINTR from a signal arriving during the syscall (e.g. SIGWINCH), not a bus error
+fn is_interrupted(error: &LinuxI2CError) -> bool {
+    match error {
+        LinuxI2CError::Errno(errno) => *errno == libc::EINTR,
+        LinuxI2CError::Io(e) => e.kind() == std::io::ErrorKind::Interrupted,
+    }
+}
+
+// Errors no retry can fix (see Documentation/i2c/fault-codes.rst): the adapter is
+// gone (ENODEV, e.g. an unplugged USB adapter), can't do this kind of transfer
+// (EOPNOTSUPP) or refuses the message itself (EINVAL, e.g. too long). Everything
+// else is retried, including ENXIO and EREMOTEIO, which adapters use for a slave
+// that didn't acknowledge: the bootloader does that while it's busy or starting up.
+fn is_fatal(error: &LinuxI2CError) -> bool {
+    matches!(errno(error), Some(libc::ENODEV | libc::EOPNOTSUPP | libc::EINVAL))
+}
+
+// Another master is using the bus: EAGAIN is lost arbitration, EBUSY a bus that
+// stayed busy too long (fault-codes.rst). Retryable, but worth counting on their own.
+fn is_arbitration_lost(error: &LinuxI2CError) -> bool {
+    matches!(errno(error), Some(libc::EAGAIN | libc::EBUSY))
+}
+
+fn errno(error: &LinuxI2CError) -> Option<i32> {
+    match error {
+        LinuxI2CError::Errno(errno) => Some(*errno),
+        LinuxI2CError::Io(e) => e.raw_os_error(),
+    }
+}
+
+impl<D: RawI2CDevice> I2CTransport for TwiI2CDevice<D> {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> Result<()> {
+        self.write_inner(data).map_err(|e| self.annotate(e, data, None))
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.read_inner(buffer).map_err(|e| self.annotate(e, &[], Some(buffer.len())))
+    }
+
+    fn write_then_read(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<usize> {
+        let len = read_buffer.len();
+        self.write_inner(write_data)
+            .and_then(|_| self.read_inner(read_buffer))
+            .map_err(|e| self.annotate(e, write_data, Some(len)))
+    }
+
+    fn write_then_read_once(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<()> {
+        let len = read_buffer.len();
+        self.device
+            .write(write_data)
+            .context("I2C write not acknowledged")
+            .and_then(|_| self.device.read(read_buffer).context("I2C read not acknowledged"))
+            .map_err(|e| self.annotate(e, write_data, Some(len)))
+    }
+
+    fn retries(&self) -> usize {
+        self.retries
+    }
+
+    fn arbitration_losses(&self) -> usize {
+        self.arbitration_losses
+    }
+}
+
+/// TCA9548A style I2C multiplexer: a single control register where bit N enables channel N.
+/// The selection stays active until it's changed, so selecting once covers the whole session.
+pub struct I2CMux {
+    device: TwiI2CDevice,
+}
+
+impl I2CMux {
+    /// Open the mux and take the bus lock, see `TwiI2CDevice::open()`
+    pub fn new(device_path: &str, address: u8) -> Result<Self> {
+        Self::open(device_path, address, true)
+    }
+
+    pub fn open(device_path: &str, address: u8, lock: bool) -> Result<Self> {
+        let device = TwiI2CDevice::open(device_path, address, lock)
+            .context("Failed to open I2C mux")?;
+
+        Ok(I2CMux { device })
+    }
+
+    pub fn select(&mut self, channel: u8) -> Result<()> {
+        self.device.write_with_retry(&[1 << channel])
+            .with_context(|| format!("Failed to select mux channel {}", channel))
+    }
+
+    /// Disconnect all channels
+    pub fn clear(&mut self) -> Result<()> {
+        self.device.write_with_retry(&[0x00])
+            .context("Failed to clear mux channel selection")
     }
 }
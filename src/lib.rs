@@ -0,0 +1,20 @@
+//! TWI/I2C bootloader flasher library.
+//!
+//! The `twiboot-flasher` binary is a thin CLI on top of these modules.
+
+pub mod builder;
+pub mod checksum;
+pub mod diff;
+#[cfg(feature = "elf")]
+pub mod elf;
+pub mod file_ops;
+pub mod flash_map;
+pub mod hexdump;
+pub mod i2c;
+pub mod json;
+pub mod parts;
+pub mod protocol;
+pub mod remote;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod simulator;
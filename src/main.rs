@@ -1,39 +1,762 @@
-use anyhow::Result;
-use clap::Parser;
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-mod i2c;
-mod protocol;
-mod file_ops;
+use twiboot_flasher::i2c::{is_bus_locked, I2CMux, I2CTransport, TwiI2CDevice};
+use twiboot_flasher::remote::TcpI2CDevice;
+use twiboot_flasher::protocol::{
+    AddressEndian, FlashReport, Radix, TwiBootloader, VerifyError, VerifyOrder, VerifySummary, DEFAULT_MAX_FLASH_SIZE, DEFAULT_READ_BLOCK_SIZE, DEFAULT_SWITCH_RETRIES, DEFAULT_VERIFY_READ_RETRIES,
+    DEFAULT_VERIFY_READ_RETRY_DELAY_MS, DEFAULT_VERSION_LENGTH, DEFAULT_WRITE_DELAY_MS,
+};
+use twiboot_flasher::file_ops::{FileFormat, FirmwareImage, Segment, image_warnings, read_file_with_bootloader_info, to_hex};
+use twiboot_flasher::checksum::{crc32, sha256, to_hex_string};
+use twiboot_flasher::diff::diff;
+use twiboot_flasher::flash_map::{free_bytes, page_map, used_pages};
+use twiboot_flasher::hexdump::hexdump;
+use twiboot_flasher::json::json_string;
+use twiboot_flasher::simulator::SimulatedBootloader;
 
-use i2c::TwiI2CDevice;
-use protocol::TwiBootloader;
-use file_ops::{FileFormat, read_file_with_bootloader_info};
+// println!() unless --quiet is given. With JSON output stdout carries only the
+// JSON lines, so the text goes to stderr then.
+macro_rules! say {
+    ($cli:expr, $($arg:tt)*) => {
+        if !$cli.output.quiet {
+            if $cli.output.output_format == OutputFormat::Json {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Auto,
+    Hex,
+    Bin,
+    Elf,
+}
+
+impl FormatArg {
+    fn file_format(self, path: &Path) -> FileFormat {
+        match self {
+            FormatArg::Auto => FileFormat::from_extension(path),
+            FormatArg::Hex => FileFormat::Hex,
+            FormatArg::Bin => FileFormat::Binary,
+            FormatArg::Elf => FileFormat::Elf,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RadixArg {
+    Hex,
+    Dec,
+}
+
+impl From<RadixArg> for Radix {
+    fn from(radix: RadixArg) -> Self {
+        match radix {
+            RadixArg::Hex => Radix::Hex,
+            RadixArg::Dec => Radix::Dec,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EndianArg {
+    Big,
+    Little,
+}
+
+impl From<EndianArg> for AddressEndian {
+    fn from(endian: EndianArg) -> Self {
+        match endian {
+            EndianArg::Big => AddressEndian::Big,
+            EndianArg::Little => AddressEndian::Little,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum VerifyOrderArg {
+    Asc,
+    Desc,
+}
+
+impl From<VerifyOrderArg> for VerifyOrder {
+    fn from(order: VerifyOrderArg) -> Self {
+        match order {
+            VerifyOrderArg::Asc => VerifyOrder::Ascending,
+            VerifyOrderArg::Desc => VerifyOrder::Descending,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SimulatedDevice {
+    /// AVR twiboot v3.x, 16-bit addresses (defaults: ATtiny84)
+    Avr,
+    /// CH32V twiboot v4.x, 32-bit addresses (defaults: CH32V003)
+    Ch32v,
+}
+
+#[derive(Args)]
+struct DeviceArgs {
+    /// I2C bus number (e.g., 0 for /dev/i2c-0)
+    bus: u8,
+
+    /// I2C slave address (0x01-0x7F), or a comma separated list to handle several devices in turn
+    #[arg(value_parser = parse_addresses)]
+    address: Addresses,
+}
+
+/// How much is printed and in which format
+#[derive(Args)]
+struct OutputArgs {
+    /// Format of the verification report
+    #[arg(long = "output-format", value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
+    /// Print additional details
+    #[arg(short = 'v', long = "verbose", conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Print nothing but errors, the exit code tells the result
+    #[arg(short = 'q', long = "quiet")]
+    quiet: bool,
+
+    /// Add KEY=VALUE to every JSON output line, under "meta" (repeatable)
+    #[arg(long = "meta", value_name = "KEY=VALUE", value_parser = parse_meta)]
+    meta: Vec<(String, String)>,
+}
+
+/// Options of a local `/dev/i2c-N` bus, shared with `scan`
+#[derive(Args)]
+struct LocalBusArgs {
+    /// Don't take the advisory lock on /dev/i2c-N that keeps other instances off the bus
+    #[arg(long = "no-lock")]
+    no_lock: bool,
+
+    /// Set the I2C adapter timeout, applies to every user of the bus (default: the driver's)
+    #[arg(long = "i2c-timeout-ms", value_name = "MS")]
+    i2c_timeout_ms: Option<u64>,
+}
+
+/// Where the I2C transactions go: local bus, mux, remote agent or simulator
+#[derive(Args)]
+struct TransportArgs {
+    #[command(flatten)]
+    local: LocalBusArgs,
+
+    /// Talk to a remote I2C agent instead of the local bus
+    #[arg(long = "remote", value_name = "HOST:PORT", conflicts_with = "i2c_timeout_ms")]
+    remote: Option<String>,
+
+    /// Talk to an in-memory simulated bootloader instead of real hardware
+    #[arg(long = "simulate", value_name = "DEVICE", value_enum,
+          conflicts_with_all = ["remote", "mux_address", "i2c_timeout_ms"])]
+    simulate: Option<SimulatedDevice>,
+
+    /// Signature (AVR, 3 bytes) or chip ID (CH32V) of the simulated device
+    #[arg(long = "sim-signature", value_name = "ID", value_parser = parse_u32, requires = "simulate")]
+    sim_signature: Option<u32>,
+
+    /// Application flash size (= bootloader start) of the simulated device
+    #[arg(long = "sim-flash-size", value_name = "BYTES", value_parser = parse_u32, requires = "simulate")]
+    sim_flash_size: Option<u32>,
+
+    /// Page size of the simulated device
+    #[arg(long = "sim-page-size", value_name = "BYTES", default_value_t = 64,
+          value_parser = clap::value_parser!(u8).range(1..), requires = "simulate")]
+    sim_page_size: u8,
+
+    /// Address of a TCA9548A style I2C mux in front of the device
+    #[arg(long = "mux-address", value_name = "ADDR", value_parser = parse_address,
+          requires = "mux_channel", conflicts_with = "remote")]
+    mux_address: Option<u8>,
+
+    /// Mux channel (0-7) the device is connected to
+    #[arg(long = "mux-channel", value_name = "N", value_parser = clap::value_parser!(u8).range(0..8),
+          requires = "mux_address")]
+    mux_channel: Option<u8>,
+
+    /// Deselect all mux channels when done
+    #[arg(long = "mux-clear", requires = "mux_address")]
+    mux_clear: bool,
+
+    /// Retry each I2C write and read up to N times (default: 50 per write, 3 per read)
+    #[arg(long = "i2c-retries", value_name = "N", env = "TWIBOOT_RETRIES")]
+    i2c_retries: Option<usize>,
+
+    /// Pause before each I2C retry, in ms (default: 2)
+    #[arg(long = "i2c-retry-delay-ms", value_name = "MS", env = "TWIBOOT_RETRY_DELAY_MS")]
+    i2c_retry_delay_ms: Option<u64>,
+
+    /// Abort once all I2C transactions together needed this many retries
+    #[arg(long = "total-retries", value_name = "N", conflicts_with_all = ["remote", "simulate"])]
+    total_retries: Option<usize>,
+
+    /// Add a hexdump of the bytes sent to the message of a failed I2C transfer
+    #[arg(long = "hexdump-on-error", conflicts_with_all = ["remote", "simulate"])]
+    hexdump_on_error: bool,
+}
+
+/// How the version string is read and checked, shared with `scan`
+#[derive(Args)]
+struct VersionArgs {
+    /// Abort unless the bootloader version string contains SUBSTR
+    #[arg(long = "expect-version", value_name = "SUBSTR")]
+    expect_version: Option<String>,
+
+    /// Number of bytes to read for the bootloader version string
+    #[arg(long = "version-length", value_name = "BYTES", default_value_t = DEFAULT_VERSION_LENGTH as u8,
+          value_parser = clap::value_parser!(u8).range(1..))]
+    version_length: u8,
+
+    /// Don't clear bit 7 of the version bytes (for bootloaders sending clean ASCII)
+    #[arg(long = "raw-version")]
+    raw_version: bool,
+}
+
+/// Connecting, device checks and everything around the action itself
+#[derive(Args)]
+struct SessionArgs {
+    #[command(flatten)]
+    version: VersionArgs,
+
+    /// Wait for device to be powered on and bootloader to start
+    #[arg(short = 'w', long = "wait")]
+    wait: bool,
+
+    /// Give up connecting after MS milliseconds, also ends --wait
+    #[arg(long = "connect-timeout-ms", value_name = "MS")]
+    connect_timeout_ms: Option<u64>,
+
+    /// Delay after switching into the bootloader, in ms
+    #[arg(long = "switch-delay-ms", value_name = "MS", default_value_t = 100, env = "TWIBOOT_CONNECT_DELAY_MS")]
+    switch_delay_ms: u64,
+
+    /// Repeat a failed switch into the bootloader (and version read) this often while connecting
+    #[arg(long = "switch-retries", value_name = "N", default_value_t = DEFAULT_SWITCH_RETRIES as u32,
+          value_parser = clap::value_parser!(u32).range(0..=10))]
+    switch_retries: u32,
+
+    /// Read version and chipinfo N times (default 10) and abort if the answers differ
+    #[arg(long = "link-test", value_name = "N", num_args = 0..=1, default_missing_value = "10",
+          value_parser = clap::value_parser!(u32).range(2..))]
+    link_test: Option<u32>,
+
+    /// Time N short and N long flash reads (default 20) and print the effective bus clock
+    #[arg(long = "measure-clock", value_name = "N", num_args = 0..=1, default_missing_value = "20",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    measure_clock: Option<u32>,
+
+    /// Read a serial number from EEPROM and print it with the device info
+    #[arg(long = "read-serial", value_name = "OFFSET:LEN", value_parser = parse_eeprom_range)]
+    read_serial: Option<(u32, usize)>,
+
+    /// Print all device addresses and sizes in one radix (default: hex and decimal mixed)
+    #[arg(long = "radix", value_enum)]
+    radix: Option<RadixArg>,
+
+    /// Byte order of the addresses in read and write commands, for non-standard bootloader variants
+    #[arg(long = "address-endian", value_enum, default_value = "big")]
+    address_endian: EndianArg,
+
+    /// Refuse devices reporting more flash than this many bytes
+    #[arg(long = "max-flash-size", value_name = "BYTES", default_value_t = DEFAULT_MAX_FLASH_SIZE)]
+    max_flash_size: u32,
+
+    /// Abort if the device's usable flash (bootloader start) is smaller than this many bytes
+    #[arg(long = "expect-flash-min", value_name = "BYTES", value_parser = parse_u32)]
+    expect_flash_min: Option<u32>,
+
+    /// Abort if the device's usable flash (bootloader start) is larger than this many bytes
+    #[arg(long = "expect-flash-max", value_name = "BYTES", value_parser = parse_u32)]
+    expect_flash_max: Option<u32>,
+
+    /// Print the chipinfo bytes as read from the device, before parsing
+    #[arg(long = "raw-chipinfo")]
+    raw_chipinfo: bool,
+
+    /// Read fuse and lock bytes while connecting (variant extension, memtype 0x04)
+    #[arg(long = "read-fuses")]
+    read_fuses: bool,
+
+    /// Use the known page size and write delay of a recognized part when chipinfo disagrees
+    #[arg(long = "part-defaults")]
+    part_defaults: bool,
+
+    /// Bytes per read transaction when verifying or dumping
+    #[arg(long = "read-block-size", value_name = "BYTES", default_value_t = DEFAULT_READ_BLOCK_SIZE as u16,
+          value_parser = clap::value_parser!(u16).range(1..=4096))]
+    read_block_size: u16,
+
+    /// Leave the device in the bootloader when a run fails instead of starting the application
+    #[arg(long = "stay-on-error")]
+    stay_on_error: bool,
+
+    /// Print a timeline of the protocol phases and every page to stderr (needs the trace feature)
+    #[arg(long = "trace")]
+    trace: bool,
+
+    /// Wait for Enter before starting the application
+    #[arg(long = "pause-before-run")]
+    pause_before_run: bool,
+
+    /// Run the whole connect/write/verify/disconnect cycle N times (burn-in test)
+    #[arg(long = "repeat", value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: u32,
+
+    /// Stop repeating at the first failed iteration
+    #[arg(long = "stop-on-fail")]
+    stop_on_fail: bool,
+}
+
+/// The options every device command takes
+#[derive(Args)]
+struct CommonArgs {
+    #[command(flatten)]
+    output: OutputArgs,
+
+    #[command(flatten)]
+    transport: TransportArgs,
+
+    #[command(flatten)]
+    session: SessionArgs,
+}
+
+#[derive(Args)]
+struct InputArgs {
+    /// Firmware file format (auto detects from extension, then content)
+    #[arg(short = 'f', long = "format", value_enum, default_value_t = FormatArg::Auto)]
+    format: FormatArg,
+}
+
+/// What is written (or compared) and how the image is laid out
+#[derive(Args)]
+struct ImageArgs {
+    /// Bytes to write instead of a file, e.g. 0xEF,0xCD,12
+    #[arg(long = "data", value_name = "BYTES", value_parser = parse_data_list, conflicts_with = "data_hex")]
+    data: Option<InlineData>,
+
+    /// Bytes to write instead of a file as one hex string, e.g. DEADBEEF
+    #[arg(long = "data-hex", value_name = "HEX", value_parser = parse_data_hex)]
+    data_hex: Option<InlineData>,
+
+    /// Flash address for --data/--data-hex or a binary FILE
+    #[arg(long = "offset", value_name = "ADDR", value_parser = parse_u32, default_value = "0")]
+    offset: u32,
+
+    /// Don't write pages that only contain fill bytes between HEX records
+    #[arg(long = "skip-gaps")]
+    skip_gaps: bool,
+
+    /// Extend the image with --flash-fill up to the bootloader start, so no old code remains
+    #[arg(long = "fill-app-region")]
+    fill_app_region: bool,
+
+    /// Also write the EEPROM part (at 0x810000) of a combined HEX or ELF file to EEPROM
+    #[arg(long = "split-eeprom")]
+    split_eeprom: bool,
+
+    /// Byte for flash not covered by the image (gaps, last page, --fill-app-region) and for erase checks
+    #[arg(long = "flash-fill", value_name = "BYTE", value_parser = parse_u8, default_value = "0xFF")]
+    flash_fill: u8,
+
+    /// Write the EEPROM data of a combined file as one block, gaps between records set to BYTE
+    #[arg(long = "eeprom-fill", value_name = "BYTE", value_parser = parse_u8, requires = "split_eeprom", conflicts_with = "skip_gaps")]
+    eeprom_fill: Option<u8>,
+}
+
+/// How each page is written, shared by everything that writes
+#[derive(Args)]
+struct PageWriteArgs {
+    /// Delay after each page write, in ms
+    #[arg(long = "write-delay-ms", value_name = "MS", default_value_t = DEFAULT_WRITE_DELAY_MS, env = "TWIBOOT_WRITE_DELAY_MS")]
+    write_delay_ms: u64,
+
+    /// Read a status byte after each page write and abort on the error code it reports
+    #[arg(long = "write-status")]
+    write_status: bool,
+
+    /// Page size used for writes instead of the device-reported one
+    #[arg(long = "write-page-size", value_name = "BYTES",
+          value_parser = clap::value_parser!(u32).range(1..=4096))]
+    write_page_size: Option<u32>,
+}
+
+/// Flashing an image. Always comes with `ImageArgs` and `VerifyArgs`.
+#[derive(Args)]
+struct WriteArgs {
+    #[command(flatten)]
+    page: PageWriteArgs,
+
+    /// Disable verify after write
+    #[arg(short = 'n', long = "no-verify", conflicts_with = "verify_reread")]
+    no_verify: bool,
+
+    /// Flash even if the image fails the sanity checks (e.g. missing reset vector) or --require-erased
+    #[arg(long = "force")]
+    force: bool,
+
+    /// Read every page first and only write the ones that changed
+    #[arg(long = "skip-unchanged", conflicts_with_all = ["resume", "erase"])]
+    skip_unchanged: bool,
+
+    /// Erase the whole application region before writing
+    #[arg(long = "erase", conflicts_with = "resume")]
+    erase: bool,
+
+    /// Erase page 0 first and write it last, so an interrupted flash doesn't boot
+    #[arg(long = "reset-vector-last")]
+    reset_vector_last: bool,
+
+    /// Write and verify again, up to N more times, when the verify fails
+    #[arg(long = "retry-flash", value_name = "N", default_value_t = 0)]
+    retry_flash: u32,
+
+    /// Record write progress in <FILE>.resume and continue from there after an interruption
+    #[arg(long = "resume",
+          conflicts_with_all = ["reset_vector_last", "data", "data_hex", "offset", "skip_gaps"])]
+    resume: bool,
+
+    /// Before writing, save the current application flash to OUT (Intel HEX for .hex, else binary)
+    #[arg(long = "backup", value_name = "OUT")]
+    backup: Option<String>,
+
+    /// Before writing, check that the application region is all 0xFF (--force to write anyway)
+    #[arg(long = "require-erased")]
+    require_erased: bool,
+
+    /// Fail if the image doesn't end on a page boundary instead of padding the last page
+    #[arg(long = "no-pad")]
+    no_pad: bool,
+
+    /// Send a short last page with only the image bytes, for variants that accept partial pages
+    #[arg(long = "no-final-pad", conflicts_with_all = ["no_pad", "verify_pagesum"])]
+    no_final_pad: bool,
+
+    /// Verify each page right after writing it instead of in a second pass, without leaving the bootloader
+    #[arg(long = "interleave-verify", visible_alias = "single-pass",
+          conflicts_with_all = ["no_verify", "verify_pagesum", "verify_matches_write", "verify_reread", "resume"])]
+    interleave_verify: bool,
+
+    /// Read back every page and adapt the write delay to the device
+    #[arg(long = "page-delay-scaling")]
+    page_delay_scaling: bool,
+
+    /// Verify against the file read and parsed again from disk instead of the image in memory
+    #[arg(long = "verify-reread")]
+    verify_reread: bool,
+
+    /// After writing, read back the reset vector (bytes 0-3) and compare it with the image
+    #[arg(long = "check-vector")]
+    check_vector: bool,
+
+    /// After flashing, read the image's range back into OUT and print the SHA-256 of image and flash
+    #[arg(long = "flash-and-capture", value_name = "OUT")]
+    flash_and_capture: Option<String>,
+}
+
+/// How the flash is read back and compared
+#[derive(Args)]
+struct VerifyArgs {
+    /// Report every mismatching byte instead of stopping at the first bad block
+    #[arg(long = "verify-report-all")]
+    verify_report_all: bool,
+
+    /// Verify with checksums computed on the device, one per page (needs bootloader support)
+    #[arg(long = "verify-pagesum",
+          conflicts_with_all = ["ignore_padding", "verify_matches_write", "skip_gaps", "offset", "data", "data_hex"])]
+    verify_pagesum: bool,
 
+    /// Verify in page sized, page aligned reads, exactly like the writes
+    #[arg(long = "verify-matches-write")]
+    verify_matches_write: bool,
+
+    /// Don't verify fill bytes between HEX records
+    #[arg(long = "ignore-padding")]
+    ignore_padding: bool,
+
+    /// Ignore what the last page reads back past the end of the image
+    #[arg(long = "lenient-erased")]
+    lenient_erased: bool,
+
+    /// Order in which verify reads the flash back, independent of the write order
+    #[arg(long = "verify-order", value_enum, default_value = "asc")]
+    verify_order: VerifyOrderArg,
+
+    /// Delay after re-entering the bootloader before verify, in ms
+    #[arg(long = "reentry-delay-ms", value_name = "MS", default_value_t = 50)]
+    reentry_delay_ms: u64,
+
+    /// Repeat a failed verify read this often before re-entering the bootloader
+    #[arg(long = "verify-read-retries", value_name = "N", default_value_t = DEFAULT_VERIFY_READ_RETRIES as u32, env = "TWIBOOT_RETRIES")]
+    verify_read_retries: u32,
+
+    /// Pause before each verify read retry, in ms
+    #[arg(long = "verify-read-retry-delay-ms", value_name = "MS", default_value_t = DEFAULT_VERIFY_READ_RETRY_DELAY_MS, env = "TWIBOOT_RETRY_DELAY_MS")]
+    verify_read_retry_delay_ms: u64,
+}
+
+/// Extras of info mode, the device stays in the bootloader
+#[derive(Args)]
+struct InfoArgs {
+    /// In info mode, read the application region and print its CRC32 and SHA-256
+    #[arg(long = "device-hash")]
+    device_hash: bool,
+
+    /// Print which flash pages are used and free (by FILE if given, else the device content)
+    #[arg(long = "free-map")]
+    free_map: bool,
+
+    /// Ping the device every MS milliseconds (default 100) and print when it appears or disappears
+    #[arg(long = "monitor", value_name = "MS", num_args = 0..=1, default_missing_value = "100",
+          value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "repeat")]
+    monitor: Option<u64>,
+}
+
+#[derive(Args)]
+struct DumpArgs {
+    /// Dump up to --flash-end, including the bootloader region
+    #[arg(long = "full", requires = "flash_end")]
+    full: bool,
+
+    /// Real end of flash (chipinfo only reports the bootloader start)
+    #[arg(long = "flash-end", value_name = "ADDR", value_parser = parse_u32, requires = "full")]
+    flash_end: Option<u32>,
+}
+
+#[derive(Args)]
+struct ScanArgs {
+    /// I2C bus number (e.g., 0 for /dev/i2c-0)
+    bus: u8,
+
+    /// Also send the bootloader version command to every address that answers.
+    /// This writes to devices that aren't bootloaders, see the README
+    #[arg(long = "identify")]
+    identify: bool,
+
+    #[command(flatten)]
+    version: VersionArgs,
+
+    #[command(flatten)]
+    local: LocalBusArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Device(Box<DeviceCommand>),
+    /// List the addresses on a bus where a device answers, read-only unless --identify
+    Scan(ScanArgs),
+}
+
+/// The subcommands that talk to one device (or a list of them) at a time
+#[derive(Subcommand)]
+enum DeviceCommand {
+    /// Show bootloader info (what a bare `BUS ADDRESS` does)
+    Info {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        common: CommonArgs,
+        #[command(flatten)]
+        inspect: InfoArgs,
+    },
+    /// Write a firmware file and verify it (what `BUS ADDRESS FILE` does)
+    Flash {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[arg(value_name = "FILE", required_unless_present_any = ["data", "data_hex"])]
+        file: Option<String>,
+        #[command(flatten)]
+        common: CommonArgs,
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        image: ImageArgs,
+        #[command(flatten)]
+        write: WriteArgs,
+        #[command(flatten)]
+        verify: VerifyArgs,
+    },
+    /// Compare the flash against a firmware file without writing
+    Verify {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[arg(value_name = "FILE", required_unless_present_any = ["data", "data_hex"])]
+        file: Option<String>,
+        #[command(flatten)]
+        common: CommonArgs,
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        image: ImageArgs,
+        #[command(flatten)]
+        verify: VerifyArgs,
+    },
+    /// Read the flash into a binary file
+    Dump {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[arg(value_name = "OUT")]
+        output: String,
+        #[command(flatten)]
+        common: CommonArgs,
+        #[command(flatten)]
+        range: DumpArgs,
+    },
+    /// Show which bytes a firmware file would change, nothing is written
+    Diff {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[arg(value_name = "FILE")]
+        file: String,
+        #[command(flatten)]
+        common: CommonArgs,
+        #[command(flatten)]
+        input: InputArgs,
+    },
+    /// Erase the application region, the device stays in the bootloader
+    Erase {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        common: CommonArgs,
+        #[command(flatten)]
+        page: PageWriteArgs,
+    },
+    /// Start the application
+    Run {
+        #[command(flatten)]
+        device: DeviceArgs,
+        #[command(flatten)]
+        common: CommonArgs,
+    },
+}
+
+/// What `run()` does after connecting, from the subcommand or the legacy arguments
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum Action {
+    #[default]
+    Info,
+    Flash,
+    Verify,
+    Dump,
+    Diff,
+    /// Flash a `--backup` file, without the image sanity checks
+    Restore,
+    Erase,
+    Run,
+}
+
+// The legacy form without subcommand takes every option group. A subcommand
+// only takes its own groups, apply_command() moves them here afterwards.
 #[derive(Parser)]
 #[command(name = "twiboot-flasher")]
 #[command(about = "TWI/I2C bootloader flasher for AVR microcontrollers")]
 #[command(version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(skip)]
+    action: Action,
+
     /// I2C bus number (e.g., 0 for /dev/i2c-0)
-    bus: u8,
+    #[arg(required_unless_present_any = ["show", "list_buses"])]
+    bus: Option<u8>,
 
-    /// I2C slave address (0x01-0x7F)
-    #[arg(value_parser = parse_address)]
-    address: u8,
+    /// I2C slave address (0x01-0x7F), or a comma separated list to handle several devices in turn
+    #[arg(value_parser = parse_addresses, required_unless_present_any = ["show", "list_buses"])]
+    address: Option<Addresses>,
 
     /// Firmware file to flash (optional - if not provided, shows bootloader info)
     #[arg(value_name = "FILE")]
     file: Option<String>,
 
-    /// Disable verify after write
-    #[arg(short = 'n', long = "no-verify")]
-    no_verify: bool,
+    /// Parse a firmware file and print it as hexdump, no device needed
+    #[arg(long = "show", value_name = "FILE", conflicts_with_all = ["file", "quiet"])]
+    show: Option<String>,
 
-    /// Wait for device to be powered on and bootloader to start
-    #[arg(short = 'w', long = "wait")]
-    wait: bool,
+    /// List the I2C buses with their adapter names, no device needed
+    #[arg(long = "list-buses", conflicts_with_all = ["show", "file"])]
+    list_buses: bool,
+
+    /// Keep the connection open and serve info, dumps and flashes over HTTP on ADDR:PORT
+    #[cfg(feature = "serve")]
+    #[arg(long = "serve", value_name = "ADDR:PORT",
+          conflicts_with_all = ["file", "dump", "diff", "info", "restore", "data", "data_hex", "erase",
+                                "free_map", "device_hash", "monitor"])]
+    serve: Option<String>,
+
+    /// Let --serve listen on an address other hosts can reach (there's no authentication)
+    #[cfg(feature = "serve")]
+    #[arg(long = "serve-allow-remote", requires = "serve")]
+    serve_allow_remote: bool,
+
+    /// Print the bytes FILE would change on the device instead of writing
+    #[arg(long = "diff", value_name = "FILE", conflicts_with_all = ["file", "dump"])]
+    diff: Option<String>,
+
+    /// Only connect and show the bootloader info, the device stays in the bootloader
+    #[arg(long = "info", conflicts_with_all = ["file", "dump", "diff", "restore", "data", "data_hex"])]
+    info: bool,
+
+    /// Write a file saved with --backup back to the device
+    #[arg(long = "restore", value_name = "FILE", conflicts_with_all = ["file", "dump", "diff", "data", "data_hex"])]
+    restore: Option<String>,
+
+    /// Read the flash into a binary file instead of writing
+    #[arg(long = "dump", value_name = "OUT", conflicts_with = "file")]
+    dump: Option<String>,
+
+    /// Bootloader start address (= usable flash size) used by --show
+    #[arg(long = "bootloader-start", value_name = "ADDR", value_parser = parse_u32, requires = "show")]
+    bootloader_start: Option<u32>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    #[command(flatten)]
+    transport: TransportArgs,
+
+    #[command(flatten)]
+    session: SessionArgs,
+
+    #[command(flatten)]
+    input: InputArgs,
 
+    #[command(flatten)]
+    image: ImageArgs,
+
+    #[command(flatten)]
+    write: WriteArgs,
+
+    #[command(flatten)]
+    verify: VerifyArgs,
+
+    #[command(flatten)]
+    inspect: InfoArgs,
+
+    #[command(flatten)]
+    dump_range: DumpArgs,
 }
 
 fn parse_address(s: &str) -> Result<u8, String> {
@@ -46,55 +769,1349 @@ fn parse_address(s: &str) -> Result<u8, String> {
     }
 }
 
+/// One or more device addresses on the same bus
+#[derive(Clone)]
+struct Addresses(Vec<u8>);
+
+fn parse_addresses(s: &str) -> Result<Addresses, String> {
+    s.split(',')
+        .map(|address| parse_address(address.trim()))
+        .collect::<Result<Vec<u8>, String>>()
+        .map(Addresses)
+}
+
+fn parse_meta(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE: {}", s)),
+    }
+}
+
+fn parse_u8(s: &str) -> Result<u8, String> {
+    let value = parse_u32(s)?;
+    u8::try_from(value).map_err(|_| format!("Not a byte value: {}", s))
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    if let Some(hex_str) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex_str, 16)
+            .map_err(|_| format!("Invalid hex number: {}", s))
+    } else {
+        s.parse::<u32>()
+            .map_err(|_| format!("Invalid number: {}", s))
+    }
+}
+
+fn parse_eeprom_range(s: &str) -> Result<(u32, usize), String> {
+    let (offset, len) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Expected OFFSET:LEN, got: {}", s))?;
+    let len = parse_u32(len)?;
+    if len == 0 {
+        return Err("Serial length must not be 0".to_string());
+    }
+
+    Ok((parse_u32(offset)?, len as usize))
+}
+
+fn show_file(cli: &Cli, filename: &str) -> Result<()> {
+    let filepath = PathBuf::from(filename);
+    let flash_limit = cli.bootloader_start.unwrap_or(cli.session.max_flash_size);
+
+    let image = read_file_with_bootloader_info(&filepath, cli.input.format.file_format(&filepath), flash_limit, cli.session.max_flash_size)?;
+    say!(cli,
+        "{}: {} bytes ({} padding), limit 0x{:04X}",
+        filepath.display(),
+        image.data.len(),
+        image.padding_bytes(),
+        flash_limit
+    );
+    if let Some(start) = image.start_address {
+        say!(cli, "Start address: 0x{:08X}", start);
+    }
+    print!("{}", hexdump(&image.data, 0));
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+
+    // Device-free diagnostics, bus and address aren't needed
+    if let Some(filename) = &cli.show {
+        return show_file(&cli, filename);
+    }
+    if cli.list_buses {
+        return list_buses();
+    }
+
+    let (bus, Addresses(addresses)) = match cli.command.take() {
+        Some(Command::Scan(args)) => return scan(&args),
+        Some(Command::Device(command)) => apply_command(&mut cli, *command),
+        None => {
+            // Legacy form without subcommand: BUS ADDRESS [FILE] [--info | --dump OUT | --diff FILE]
+            cli.action = match (&cli.dump, &cli.file) {
+                _ if cli.info => Action::Info,
+                _ if cli.diff.is_some() => Action::Diff,
+                _ if cli.restore.is_some() => Action::Restore,
+                (Some(_), _) => Action::Dump,
+                (None, Some(_)) => Action::Flash,
+                (None, None) if cli.inline_data().is_some() => Action::Flash,
+                (None, None) => Action::Info,
+            };
+            (
+                cli.bus.expect("bus is required by clap"),
+                cli.address.take().expect("address is required by clap"),
+            )
+        }
+    };
 
-    if cli.address < 0x01 || cli.address > 0x7F {
+    if addresses.iter().any(|address| !(0x01..=0x7F).contains(address)) {
         eprintln!("Error: I2C address must be between 0x01 and 0x7F");
         std::process::exit(1);
     }
+    // `requires = "repeat"` can't catch this, --repeat always has its default of 1
+    if cli.session.stop_on_fail && cli.session.repeat < 2 {
+        eprintln!("Error: --stop-on-fail needs --repeat N with N greater than 1");
+        std::process::exit(1);
+    }
+    if addresses.len() > 1 && cli.action == Action::Dump {
+        eprintln!("Error: Dump reads a single device, give one address");
+        std::process::exit(1);
+    }
 
-    // Create device path from bus number
-    let device_path = format!("/dev/i2c-{}", cli.bus);
+    if cli.session.trace {
+        init_trace()?;
+    }
 
-    // Create I2C device
-    let i2c = TwiI2CDevice::new(&device_path, cli.address)?;
-    
-    // Create bootloader instance (addressing mode will be auto-detected by version)
-    let mut bootloader = TwiBootloader::new(i2c);
+    // Select the mux channel once, it stays selected for all following transactions
+    let mut mux = match (cli.transport.mux_address, cli.transport.mux_channel) {
+        (Some(mux_address), Some(channel)) => {
+            let mut mux = I2CMux::open(&bus_device_path(bus)?, mux_address, !cli.transport.local.no_lock)?;
+            mux.select(channel)?;
+            Some(mux)
+        }
+        _ => None,
+    };
 
-    // Connect to bootloader
-    bootloader.connect(cli.wait)?;
+    let result = match (cli.inspect.monitor, addresses.as_slice()) {
+        (Some(interval), _) => monitor(&cli, bus, &addresses, Duration::from_millis(interval)),
+        (None, [address]) => run_device(&cli, bus, *address),
+        (None, _) => run_each(&cli, bus, &addresses),
+    };
 
+    match (&mut mux, cli.transport.mux_clear) {
+        // A failed run is the more interesting error
+        (Some(mux), true) => result.and(mux.clear()),
+        _ => result,
+    }
+}
 
-    // If no file specified, just show info and exit
-    if cli.file.is_none() {
-        // Info is already displayed in connect(), just exit
-        return Ok(());
+/// Bytes given on the command line with --data/--data-hex
+#[derive(Clone)]
+struct InlineData(Vec<u8>);
+
+fn parse_data_list(s: &str) -> Result<InlineData, String> {
+    s.split(',')
+        .map(|byte| {
+            let byte = byte.trim();
+            match byte.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => byte.parse::<u8>(),
+            }
+            .map_err(|_| format!("Invalid byte: {}", byte))
+        })
+        .collect::<Result<Vec<u8>, String>>()
+        .map(InlineData)
+}
+
+fn parse_data_hex(s: &str) -> Result<InlineData, String> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(format!("Expected an even number of hex digits: {}", s));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| format!("Invalid hex: {}", s))?;
+            u8::from_str_radix(pair, 16).map_err(|_| format!("Invalid hex byte: {}", pair))
+        })
+        .collect::<Result<Vec<u8>, String>>()
+        .map(InlineData)
+}
+
+impl Cli {
+    fn inline_data(&self) -> Option<&[u8]> {
+        self.image.data.as_ref().or(self.image.data_hex.as_ref()).map(|data| data.0.as_slice())
     }
+}
 
-    // Process write operation
-    if let Some(filename) = &cli.file {
-        let filepath = PathBuf::from(filename);
-        
-        if !filepath.exists() {
-            eprintln!("Error: File not found: {}", filepath.display());
-            std::process::exit(1);
+// Move the subcommand's arguments to where run() expects them
+fn apply_command(cli: &mut Cli, command: DeviceCommand) -> (u8, Addresses) {
+    let (action, device, common) = match command {
+        DeviceCommand::Info { device, common, inspect } => {
+            cli.inspect = inspect;
+            (Action::Info, device, common)
+        }
+        DeviceCommand::Flash { device, file, common, input, image, write, verify } => {
+            cli.file = file;
+            cli.input = input;
+            cli.image = image;
+            cli.write = write;
+            cli.verify = verify;
+            (Action::Flash, device, common)
+        }
+        DeviceCommand::Verify { device, file, common, input, image, verify } => {
+            cli.file = file;
+            cli.input = input;
+            cli.image = image;
+            cli.verify = verify;
+            (Action::Verify, device, common)
+        }
+        DeviceCommand::Dump { device, output, common, range } => {
+            cli.dump = Some(output);
+            cli.dump_range = range;
+            (Action::Dump, device, common)
+        }
+        DeviceCommand::Diff { device, file, common, input } => {
+            cli.diff = Some(file);
+            cli.input = input;
+            (Action::Diff, device, common)
+        }
+        DeviceCommand::Erase { device, common, page } => {
+            cli.write.page = page;
+            (Action::Erase, device, common)
         }
+        DeviceCommand::Run { device, common } => (Action::Run, device, common),
+    };
 
-        println!("Writing flash from {}", filepath.display());
-        let flash_size = bootloader.flash_size();
-        let data = read_file_with_bootloader_info(&filepath, FileFormat::from_extension(&filepath), flash_size)?;
-        bootloader.write_flash(&data)?;
+    cli.action = action;
+    cli.output = common.output;
+    cli.transport = common.transport;
+    cli.session = common.session;
+    (device.bus, device.address)
+}
+
+/// Look for devices on every regular 7-bit address of the bus.
+///
+/// By default each address only gets a one byte read, like `i2cdetect -r`, so
+/// nothing is written to whatever answers. With --identify the addresses that
+/// answer also get the bootloader version command.
+fn scan(args: &ScanArgs) -> Result<()> {
+    if args.version.expect_version.is_some() && !args.identify {
+        return Err(anyhow::anyhow!("--expect-version needs --identify, only that reads the version"));
+    }
+
+    let device_path = bus_device_path(args.bus)?;
+    let mut present = 0;
+    let mut found = 0;
+    let mut mismatches = 0;
 
-        if !cli.no_verify {
-            println!("Verifying flash...");
-            bootloader.verify_flash(&data)?;
+    for address in 0x08..=0x77 {
+        let mut i2c = match open_device(&args.local, &device_path, address) {
+            Ok(i2c) => i2c,
+            // Someone is flashing on this bus, probing now would disturb it
+            Err(e) if is_bus_locked(&e) => return Err(e),
+            // Addresses claimed by a kernel driver can't be opened, skip them
+            Err(_) => continue,
+        };
+        if !i2c.is_present() {
+            continue;
+        }
+        present += 1;
+        if !args.identify {
+            say!(args, "0x{:02X}: present", address);
+            continue;
+        }
+
+        let mut bootloader = TwiBootloader::new(i2c);
+        bootloader.set_version_length(args.version.version_length as usize);
+        bootloader.set_mask_version_msb(!args.version.raw_version);
+        let Some(version) = bootloader.probe() else {
+            say!(args, "0x{:02X}: no bootloader", address);
+            continue;
+        };
+        found += 1;
+        match &args.version.expect_version {
+            Some(expected) if version.contains(expected.as_str()) => {
+                say!(args, "0x{:02X}: {:<16} ok", address, version)
+            }
+            Some(_) => {
+                say!(args, "0x{:02X}: {:<16} MISMATCH", address, version);
+                mismatches += 1;
+            }
+            None => say!(args, "0x{:02X}: {}", address, version),
         }
     }
 
-    // Disconnect (switch to application)
-    bootloader.disconnect()?;
+    if !args.identify {
+        say!(args, "Found {} device(s) on {}", present, device_path);
+        return Ok(());
+    }
+    say!(args, "Found {} device(s), {} bootloader(s) on {}", present, found, device_path);
+    // Fails the audit, so scripts can tell without parsing the list
+    if let Some(expected) = args.version.expect_version.as_ref().filter(|_| mismatches > 0) {
+        return Err(anyhow::anyhow!(
+            "{} of {} bootloader(s) don't report version \"{}\"",
+            mismatches, found, expected
+        ));
+    }
+
+    Ok(())
+}
+
+const NO_BUSES_HINT: &str = "No I2C buses found, is the i2c-dev module loaded? Try: sudo modprobe i2c-dev";
+
+/// Numbers of all `/dev/i2c-N` device nodes, sorted
+fn available_buses() -> Vec<u32> {
+    let mut buses: Vec<u32> = std::fs::read_dir("/dev")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().to_str()?.strip_prefix("i2c-")?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    buses.sort();
+    buses
+}
+
+/// Print every I2C bus with the adapter name the kernel reports for it
+fn list_buses() -> Result<()> {
+    let buses = available_buses();
+    if buses.is_empty() {
+        return Err(anyhow::anyhow!(NO_BUSES_HINT));
+    }
+
+    println!("Bus  Adapter");
+    for bus in buses {
+        // Not every system has sysfs mounted, the bus number alone still helps
+        let name = std::fs::read_to_string(format!("/sys/class/i2c-dev/i2c-{}/name", bus))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_else(|_| "?".to_string());
+        println!("{:<4} {}", bus, name);
+    }
+
+    Ok(())
+}
+
+/// `/dev/i2c-<bus>`, with a hint about the available buses if it doesn't exist
+fn bus_device_path(bus: u8) -> Result<String> {
+    let device_path = format!("/dev/i2c-{}", bus);
+    if Path::new(&device_path).exists() {
+        return Ok(device_path);
+    }
+
+    let available: Vec<String> = available_buses()
+        .iter()
+        .map(|bus| format!("/dev/i2c-{}", bus))
+        .collect();
+
+    let hint = if available.is_empty() {
+        NO_BUSES_HINT.to_string()
+    } else {
+        format!("Available buses: {}", available.join(", "))
+    };
+
+    Err(anyhow::anyhow!("{} does not exist. {}", device_path, hint))
+}
+
+type Bootloader = TwiBootloader<Box<dyn I2CTransport>>;
+
+fn open_device(bus: &LocalBusArgs, device_path: &str, address: u8) -> Result<TwiI2CDevice> {
+    let mut device = TwiI2CDevice::open(device_path, address, !bus.no_lock)?;
+    // Not every adapter driver supports the ioctl, the run works without it
+    if let Some(ms) = bus.i2c_timeout_ms {
+        if let Err(e) = device.set_timeout(Duration::from_millis(ms)) {
+            eprintln!("Warning: {:#}, keeping the adapter's default timeout", e);
+        }
+    }
+    Ok(device)
+}
+
+fn open_bootloader(cli: &Cli, bus: u8, address: u8) -> Result<Bootloader> {
+    // Create I2C device, either local or proxied by a remote agent
+    let i2c: Box<dyn I2CTransport> = match (&cli.transport.remote, cli.transport.simulate) {
+        (_, Some(device)) => Box::new(simulated_device(cli, device, address)),
+        (Some(remote), None) => Box::new(TcpI2CDevice::new(remote, address)?),
+        (None, None) => {
+            // Create device path from bus number
+            let device_path = bus_device_path(bus)?;
+            let mut device = open_device(&cli.transport.local, &device_path, address)?;
+            device.set_retries(cli.transport.i2c_retries);
+            device.set_retry_delay(cli.transport.i2c_retry_delay_ms.map(Duration::from_millis));
+            device.set_retry_budget(cli.transport.total_retries);
+            device.set_hexdump_on_error(cli.transport.hexdump_on_error);
+            Box::new(device)
+        }
+    };
+
+    // Create bootloader instance (addressing mode will be auto-detected by version)
+    let mut builder = TwiBootloader::builder(i2c)
+        .switch_to_bootloader_delay(Duration::from_millis(cli.session.switch_delay_ms))
+        .reentry_delay(Duration::from_millis(cli.verify.reentry_delay_ms))
+        .connect_timeout(cli.session.connect_timeout_ms.map(Duration::from_millis))
+        .reset_vector_last(cli.write.reset_vector_last)
+        .max_flash_size(cli.session.max_flash_size)
+        .version_length(cli.session.version.version_length as usize)
+        .expect_version(cli.session.version.expect_version.clone())
+        .mask_version_msb(!cli.session.version.raw_version)
+        .show_raw_chipinfo(cli.session.raw_chipinfo)
+        .read_fuses(cli.session.read_fuses)
+        .verify_report_all(cli.verify.verify_report_all)
+        .write_delay(Duration::from_millis(cli.write.page.write_delay_ms))
+        .adaptive_write_delay(cli.write.page_delay_scaling)
+        .skip_unchanged(cli.write.skip_unchanged)
+        .quiet(cli.output.quiet)
+        .write_page_size(cli.write.page.write_page_size)
+        .use_part_defaults(cli.session.part_defaults)
+        .verify_matches_write(cli.verify.verify_matches_write)
+        .interleave_verify(cli.write.interleave_verify)
+        .no_pad(cli.write.no_pad)
+        .no_final_pad(cli.write.no_final_pad)
+        .write_status(cli.write.page.write_status)
+        .lenient_erased(cli.verify.lenient_erased)
+        .read_block_size(cli.session.read_block_size as usize)
+        .verify_read_retries(cli.verify.verify_read_retries as usize)
+        .switch_retries(cli.session.switch_retries as usize)
+        .switch_on_drop(!cli.session.stay_on_error)
+        .flash_fill(cli.image.flash_fill)
+        .verify_read_retry_delay(Duration::from_millis(cli.verify.verify_read_retry_delay_ms))
+        .radix(cli.session.radix.map(Radix::from).unwrap_or_default())
+        .address_endian(cli.session.address_endian.into())
+        .verify_order(cli.verify.verify_order.into());
+    if cli.output.output_format == OutputFormat::Json {
+        builder = builder.output(|line| eprintln!("{}", line));
+    }
+
+    Ok(builder.build())
+}
+
+// Phase spans with their duration when they close, pages and reads as events inside them
+#[cfg(feature = "trace")]
+fn init_trace() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(tracing::Level::DEBUG)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_target(false)
+        .try_init()
+        .map_err(|e| anyhow::anyhow!("Failed to set up --trace: {}", e))
+}
+
+#[cfg(not(feature = "trace"))]
+fn init_trace() -> Result<()> {
+    Err(anyhow::anyhow!(
+        "--trace is not compiled in, rebuild with `--features trace`"
+    ))
+}
+
+fn simulated_device(cli: &Cli, device: SimulatedDevice, address: u8) -> SimulatedBootloader {
+    let pagesize = cli.transport.sim_page_size;
+
+    let sim = match device {
+        SimulatedDevice::Avr => {
+            let signature = cli.transport.sim_signature.unwrap_or(0x1E930C).to_be_bytes();
+            let bootloader_start = cli.transport.sim_flash_size.unwrap_or(0x1C00).min(0xFFFF) as u16;
+            SimulatedBootloader::avr(
+                address,
+                [signature[1], signature[2], signature[3]],
+                pagesize,
+                bootloader_start,
+                bootloader_start as usize,
+            )
+        }
+        SimulatedDevice::Ch32v => SimulatedBootloader::ch32v(
+            address,
+            cli.transport.sim_signature.unwrap_or(0x0030_0500),
+            pagesize as u16,
+            cli.transport.sim_flash_size.unwrap_or(0x3800),
+        ),
+    };
+
+    // The simulated variant takes addresses the way they're sent
+    let sim = match cli.session.address_endian {
+        EndianArg::Big => sim,
+        EndianArg::Little => sim.with_little_endian_addresses(),
+    };
+    // and answers page writes with a status byte when one is expected
+    if cli.write.page.write_status {
+        sim.with_write_status()
+    } else {
+        sim
+    }
+}
+
+/// One complete connect -> write -> verify -> disconnect cycle
+fn run(cli: &Cli, bus: u8, address: u8) -> Result<FlashReport> {
+    let start = Instant::now();
+    let mut bootloader = open_bootloader(cli, bus, address)?;
+    let mut report = FlashReport::default();
+
+    let result = run_steps(cli, &mut bootloader, &mut report);
+    if result.is_err() {
+        // What dropping it would do anyway, but the report should tell
+        bootloader.release();
+    }
+    report.retries = bootloader.transport().retries();
+    report.arbitration_losses = bootloader.transport().arbitration_losses();
+    report.elapsed = start.elapsed();
+    report.final_mode = bootloader.mode();
+
+    match cli.output.output_format {
+        OutputFormat::Json => say_json(cli, report.to_json(result.as_ref().err())),
+        OutputFormat::Text if cli.output.verbose => say!(cli,
+            "Report: {} bytes written, {}, {} retries, {:.2}s, device in {}",
+            report.bytes_written,
+            if report.verified { "verified" } else { "not verified" },
+            report.retries,
+            report.elapsed.as_secs_f64(),
+            report.final_mode.name()
+        ),
+        OutputFormat::Text => {}
+    }
+    // Mostly lost arbitration means contention, not a flaky device or wiring
+    if report.arbitration_losses * 2 > report.retries && !cli.output.quiet {
+        eprintln!(
+            "Warning: {} of {} retries were lost bus arbitration, another I2C master is active on this bus",
+            report.arbitration_losses, report.retries
+        );
+    }
+
+    result.map(|()| report)
+}
+
+fn run_steps(cli: &Cli, bootloader: &mut Bootloader, report: &mut FlashReport) -> Result<()> {
+    bootloader.connect(cli.session.wait)?;
+    report.connected = true;
+
+    // Catches the wrong variant in a mixed fleet before any file is looked at
+    let flash_size = bootloader.flash_size();
+    if let Some(min) = cli.session.expect_flash_min.filter(|&min| flash_size < min) {
+        return Err(anyhow::anyhow!(
+            "Device has {} bytes of usable flash, less than --expect-flash-min {}", flash_size, min
+        ));
+    }
+    if let Some(max) = cli.session.expect_flash_max.filter(|&max| flash_size > max) {
+        return Err(anyhow::anyhow!(
+            "Device has {} bytes of usable flash, more than --expect-flash-max {}", flash_size, max
+        ));
+    }
+
+    if let Some(rounds) = cli.session.link_test {
+        let summary = bootloader.link_test(rounds as usize)?;
+        say!(cli,
+            "Link test: {} rounds, {} inconsistent, {} retries",
+            summary.rounds,
+            summary.mismatches,
+            summary.retries
+        );
+        if summary.mismatches > 0 {
+            return Err(anyhow::anyhow!(
+                "Unstable I2C link: {} of {} reads returned different data, check wiring and pull-ups",
+                summary.mismatches,
+                summary.rounds
+            ));
+        }
+        if summary.retries > 0 && !cli.output.quiet {
+            eprintln!("Warning: the link needed {} retries, the bus may be unreliable", summary.retries);
+        }
+    }
+
+    if let Some(rounds) = cli.session.measure_clock {
+        let estimate = bootloader.measure_clock(rounds as usize)?;
+        match estimate.clock_hz {
+            Some(hz) => say!(cli,
+                "Bus clock: ~{:.0} kHz effective, {:.2}ms overhead per read ({} rounds)",
+                hz / 1000.0,
+                estimate.overhead.as_secs_f64() * 1000.0,
+                estimate.rounds
+            ),
+            None => say!(cli, "Bus clock: too fast to measure ({} rounds)", estimate.rounds),
+        }
+    }
+
+    if let Some((offset, len)) = cli.session.read_serial {
+        let serial = bootloader.read_eeprom(offset, len)?;
+        let serial = if serial.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            String::from_utf8_lossy(&serial).into_owned()
+        } else {
+            serial.iter().map(|b| format!("{:02X}", b)).collect()
+        };
+        match cli.output.output_format {
+            OutputFormat::Json => say_json(cli, format!(r#"{{"serial":{}}}"#, json_string(&serial))),
+            OutputFormat::Text => say!(cli, "Serial: {}", serial),
+        }
+    }
+
+    #[cfg(feature = "serve")]
+    if let Some(addr) = &cli.serve {
+        // Runs until the process is stopped, the device is left as the last request left it
+        bootloader.set_switch_on_drop(false);
+        let listener = twiboot_flasher::serve::listen(addr, cli.serve_allow_remote)?;
+        say!(cli, "Serving on http://{}", addr);
+        return twiboot_flasher::serve::serve_on(bootloader, &listener);
+    }
+
+    if cli.inspect.free_map {
+        // Like info mode, the device stays in the bootloader
+        bootloader.set_switch_on_drop(false);
+        return print_free_map(cli, bootloader);
+    }
+
+    match cli.action {
+        // Info is already displayed in connect(), the device stays in the bootloader
+        Action::Info => {
+            bootloader.set_switch_on_drop(false);
+            if cli.inspect.device_hash {
+                print_device_hash(cli, bootloader)?;
+            }
+            return Ok(());
+        }
+        Action::Erase => {
+            // There's no application left to start
+            bootloader.set_switch_on_drop(false);
+            return erase_application(cli, bootloader);
+        }
+        Action::Dump => {
+            let output = cli.dump.as_deref().expect("dump output is set for dump");
+            dump_flash(cli, bootloader, output)?;
+        }
+        Action::Diff => {
+            let filename = cli.diff.as_deref().expect("diff file is set for diff");
+            diff_file(cli, bootloader, filename)?;
+        }
+        Action::Flash | Action::Verify => match (cli.file.as_deref(), cli.inline_data()) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow::anyhow!("Give either a FILE or --data/--data-hex, not both"));
+            }
+            (Some(filename), None) => flash_file(cli, bootloader, filename, report)?,
+            (None, Some(bytes)) => patch_flash(cli, bootloader, bytes, report)?,
+            (None, None) => unreachable!("flash and verify need a file or inline data"),
+        },
+        Action::Restore => {
+            let filename = cli.restore.as_deref().expect("backup file is set for restore");
+            flash_file(cli, bootloader, filename, report)?;
+        }
+        Action::Run => {}
+    }
+
+    if cli.session.pause_before_run && std::io::stdin().is_terminal() {
+        // On stderr, stdout may be JSON for a script
+        if !cli.output.quiet {
+            eprint!("Device is in the bootloader, press Enter to start the application...");
+            std::io::stderr().flush()?;
+        }
+        std::io::stdin().read_line(&mut String::new())?;
+    }
+
+    // Disconnect (switch to application)
+    bootloader.disconnect()?;
+    say!(cli, "Device switched to application");
+
+    Ok(())
+}
+
+/// Hash of the whole application region as it is, to look up which build a board runs
+fn print_device_hash(cli: &Cli, bootloader: &mut Bootloader) -> Result<()> {
+    let data = bootloader.read_flash(0, bootloader.flash_size() as usize)?;
+    let (crc, sha) = (crc32(&data), to_hex_string(&sha256(&data)));
+    match cli.output.output_format {
+        OutputFormat::Json => say_json(cli, format!(r#"{{"crc32":"{:08X}","sha256":"{}"}}"#, crc, sha)),
+        // What was asked for, so --quiet doesn't hide it
+        OutputFormat::Text => {
+            println!("Flash CRC32: 0x{:08X} ({} bytes)", crc, data.len());
+            println!("Flash SHA-256: {}", sha);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write (unless verify only) and verify the firmware file
+fn flash_file(cli: &Cli, bootloader: &mut Bootloader, filename: &str, report: &mut FlashReport) -> Result<()> {
+    let filepath = PathBuf::from(filename);
+    
+    if !filepath.exists() {
+        eprintln!("Error: File not found: {}", filepath.display());
+        std::process::exit(1);
+    }
+
+    match cli.action {
+        Action::Verify => say!(cli, "Comparing flash with {}", filepath.display()),
+        Action::Restore => say!(cli, "Restoring flash from backup {}", filepath.display()),
+        _ => say!(cli, "Writing flash from {}", filepath.display()),
+    }
+    let flash_size = bootloader.flash_size();
+    let image = load_image(cli, &filepath, flash_size)?;
+    if cli.image.offset > 0 {
+        check_offset(bootloader, &image)?;
+        say!(cli, "Binary placed at 0x{:08X}..0x{:08X}", cli.image.offset, image.end_address());
+    }
+    let padding = image.padding_bytes();
+    if padding > 0 {
+        say!(cli, "Image contains {} padding bytes (0x{:02X}) between HEX records", padding, cli.image.flash_fill);
+    }
+    if let (true, Some(start)) = (cli.output.verbose, image.start_address) {
+        say!(cli, "Start address: 0x{:08X}", start);
+    }
+    if !image.eeprom.is_empty() && !cli.image.split_eeprom {
+        return Err(anyhow::anyhow!(
+            "{} contains EEPROM data (at 0x810000), use --split-eeprom to write it too",
+            filepath.display()
+        ));
+    }
+    if cli.image.fill_app_region {
+        say!(cli, "Filled the image up to 0x{:08X} with 0x{:02X}", flash_size, cli.image.flash_fill);
+    }
+    if cli.output.verbose {
+        let pagesize = bootloader.page_size() as usize;
+        say!(cli,
+            "Image ends at 0x{:08X}, page aligned 0x{:08X} (only real bytes are verified)",
+            image.end_address(),
+            image.page_aligned_len(pagesize)
+        );
+    }
+
+    if matches!(cli.action, Action::Flash | Action::Restore) {
+        // A backup is whatever was on the device, erased or not, so it's trusted as is.
+        // A binary placed at --offset doesn't contain the vector table.
+        let warnings = match cli.action {
+            Action::Restore => Vec::new(),
+            _ if cli.image.offset > 0 => Vec::new(),
+            _ => image_warnings(&image),
+        };
+        for warning in &warnings {
+            if !cli.output.quiet {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+        if !warnings.is_empty() && !cli.write.force {
+            return Err(anyhow::anyhow!(
+                "Image failed the sanity checks, use --force to flash it anyway"
+            ));
+        }
+
+        if let Some(backup) = &cli.write.backup {
+            backup_flash(cli, bootloader, backup)?;
+        }
+
+        if cli.write.require_erased {
+            check_erased(cli, bootloader)?;
+        }
+
+        if cli.write.erase {
+            erase_application(cli, bootloader)?;
+        }
+
+        // A transient glitch can spoil a whole write, --retry-flash starts over from scratch
+        let attempts = cli.write.retry_flash + 1;
+        for attempt in 1..=attempts {
+            report.bytes_written = write_image(cli, bootloader, &image, &filepath)?;
+            if cli.write.interleave_verify {
+                say!(cli, "Verify OK (every page read back while writing)");
+                report.verified = true;
+                break;
+            }
+            if cli.write.no_verify {
+                bootloader.accept_unverified();
+                break;
+            }
+
+            let reread;
+            let expected = if cli.write.verify_reread {
+                say!(cli, "Re-reading {} for verify", filepath.display());
+                reread = load_image(cli, &filepath, flash_size)?;
+                &reread
+            } else {
+                &image
+            };
+            match verify_image(cli, bootloader, expected) {
+                Err(e) if attempt < attempts && e.downcast_ref::<VerifyError>().is_some() => {
+                    say!(cli, "Attempt {}/{}: {:#}, flashing again", attempt, attempts, e);
+                }
+                Err(e) => return Err(e),
+                Ok(()) => {
+                    if attempt > 1 {
+                        say!(cli, "Attempt {}/{}: verify OK", attempt, attempts);
+                    }
+                    report.verified = true;
+                    break;
+                }
+            }
+        }
+
+        if cli.image.split_eeprom {
+            write_eeprom_image(cli, bootloader, &image)?;
+        }
+
+        if let Some(capture) = &cli.write.flash_and_capture {
+            capture_flash(cli, bootloader, &image, capture)?;
+        }
+
+        return Ok(());
+    }
+
+    verify_image(cli, bootloader, &image)?;
+    report.verified = true;
+    if cli.image.split_eeprom {
+        verify_eeprom_image(cli, bootloader, &image)?;
+    }
+
+    Ok(())
+}
+
+/// Read and parse an image file and fill it the way it's written: gaps with
+/// --flash-fill and, with --fill-app-region, everything up to `flash_size`
+fn load_image(cli: &Cli, filepath: &Path, flash_size: u32) -> Result<FirmwareImage> {
+    let format = cli.input.format.file_format(filepath);
+    let mut image = read_file_with_bootloader_info(filepath, format, flash_size, cli.session.max_flash_size)?;
+    if cli.image.offset > 0 {
+        // HEX and ELF files carry their own addresses
+        if !matches!(format, FileFormat::Binary) {
+            return Err(anyhow::anyhow!(
+                "--offset only applies to binary files, use a .bin file or --format bin"
+            ));
+        }
+        image.rebase(cli.image.offset as usize, cli.image.flash_fill);
+    }
+    image.fill_gaps(cli.image.flash_fill);
+    if cli.image.fill_app_region {
+        // Overwrite whatever a previous, larger application left behind
+        image.fill_to(flash_size as usize, cli.image.flash_fill);
+    }
+
+    Ok(image)
+}
+
+// A binary at --offset must start on a page boundary (pages below it are never
+// touched) and end below the bootloader
+fn check_offset(bootloader: &Bootloader, image: &FirmwareImage) -> Result<()> {
+    let start = image.defined.first().map_or(0, |range| range.start);
+    let pagesize = bootloader.page_size() as usize;
+    if !start.is_multiple_of(pagesize) {
+        return Err(anyhow::anyhow!(
+            "--offset 0x{:04X} isn't a multiple of the page size ({} bytes)",
+            start, pagesize
+        ));
+    }
+    bootloader.check_app_region(start..image.end_address())
+}
+
+/// EEPROM data of the image as written: the records themselves, or with
+/// --eeprom-fill one block with the gaps set to the fill byte. EEPROM isn't
+/// rewritten by page like flash, so bytes between records are left alone.
+fn eeprom_segments(cli: &Cli, image: &FirmwareImage) -> Vec<Segment> {
+    match cli.image.eeprom_fill {
+        Some(fill) => image.eeprom_block(fill).into_iter().collect(),
+        None => image.eeprom.clone(),
+    }
+}
+
+fn write_eeprom_image(cli: &Cli, bootloader: &mut Bootloader, image: &FirmwareImage) -> Result<()> {
+    let segments = eeprom_segments(cli, image);
+    for segment in &segments {
+        bootloader.write_eeprom(segment.start, &segment.bytes)?;
+    }
+    let bytes: usize = segments.iter().map(|segment| segment.bytes.len()).sum();
+    say!(cli, "EEPROM complete ({} bytes)", bytes);
+
+    if !cli.write.no_verify {
+        verify_eeprom_image(cli, bootloader, image)?;
+    }
+
+    Ok(())
+}
+
+fn verify_eeprom_image(cli: &Cli, bootloader: &mut Bootloader, image: &FirmwareImage) -> Result<()> {
+    for segment in &eeprom_segments(cli, image) {
+        let current = bootloader.read_eeprom(segment.start, segment.bytes.len())?;
+        if let Some(i) = current.iter().zip(&segment.bytes).position(|(a, b)| a != b) {
+            return Err(anyhow::anyhow!(
+                "EEPROM verify failed at 0x{:04X}: expected 0x{:02X}, read 0x{:02X}",
+                segment.start as usize + i, segment.bytes[i], current[i]
+            ));
+        }
+    }
+    say!(cli, "EEPROM verify OK");
+
+    Ok(())
+}
+
+fn write_image(cli: &Cli, bootloader: &mut Bootloader, image: &FirmwareImage, filepath: &Path) -> Result<usize> {
+    let data = &image.data;
+    let progress = |done, total| {
+        if !cli.output.quiet {
+            print_progress(done, total)
+        }
+    };
+    let written = if cli.write.resume {
+        let state_path = PathBuf::from(format!("{}.resume", filepath.display()));
+        bootloader.write_flash_resumable(data, &state_path)?
+    } else if cli.image.skip_gaps || cli.image.offset > 0 {
+        bootloader.write_flash_ranges_with_progress(data, &image.defined, progress)?
+    } else {
+        bootloader.write_flash_with_progress(data, progress)?
+    };
+    say!(cli,
+        "Wrote {} bytes ({} pages) in {:.2}s, {} retries",
+        written.bytes_written,
+        written.pages_written,
+        written.elapsed.as_secs_f64(),
+        written.retries
+    );
+    if cli.write.skip_unchanged {
+        say!(cli,
+            "{} of {} pages changed, {} skipped{}",
+            written.written_pages.len(),
+            written.written_pages.len() + written.pages_skipped,
+            written.pages_skipped,
+            page_list(&written.written_pages, bootloader.page_size())
+        );
+    }
+    if cli.write.page_delay_scaling {
+        say!(cli, "Page write delay converged to {}ms", written.write_delay.as_millis());
+    }
+    say!(cli, "Flash complete ({} bytes)", written.bytes_written);
+
+    if cli.write.check_vector {
+        check_reset_vector(cli, bootloader, data)?;
+    }
+
+    Ok(written.bytes_written)
+}
+
+// Everything verify needs (bootloader re-entry, re-entry delay, read back) lives
+// in verify_flash(), so --no-verify goes straight from the last page to disconnect
+fn verify_image(cli: &Cli, bootloader: &mut Bootloader, image: &FirmwareImage) -> Result<()> {
+    let data = &image.data;
+    say!(cli, "Verifying flash...");
+    // Pages in the gaps were left alone by --skip-gaps and may still hold old code
+    let result = if cli.verify.ignore_padding || cli.image.skip_gaps || cli.image.offset > 0 {
+        bootloader.verify_flash_ranges(data, &image.defined)
+    } else if cli.verify.verify_pagesum {
+        bootloader.verify_flash_pagesum(data)
+    } else {
+        bootloader.verify_flash(data)
+    };
+
+    let failure = result.as_ref().err().and_then(|e| e.downcast_ref::<VerifyError>());
+    match (cli.output.output_format, &result, failure) {
+        (OutputFormat::Json, Ok(summary), _) => say_json(cli, verify_json(summary)),
+        (OutputFormat::Json, _, Some(failure)) => say_json(cli, verify_json(&failure.summary)),
+        (OutputFormat::Text, _, Some(failure)) if cli.verify.verify_report_all => {
+            for mismatch in &failure.summary.details {
+                say!(cli,
+                    "Mismatch at 0x{:08X}: expected 0x{:02X}, read 0x{:02X}",
+                    mismatch.addr, mismatch.expected, mismatch.actual
+                );
+            }
+        }
+        _ => {}
+    }
+
+    let verified = result?;
+    say!(cli,
+        "Verified {} bytes in {:.2}s",
+        verified.bytes_verified,
+        verified.elapsed.as_secs_f64()
+    );
+    say!(cli, "Verify OK");
+
+    Ok(())
+}
+
+/// Write (or with verify only, compare) inline bytes at --offset, the rest of
+/// their pages is kept (see `TwiBootloader::patch_flash()`)
+fn patch_flash(cli: &Cli, bootloader: &mut Bootloader, bytes: &[u8], report: &mut FlashReport) -> Result<()> {
+    let start = cli.image.offset as usize;
+    let end = start + bytes.len();
+    // Before the compare or the backup, patch_flash() would refuse it only then
+    bootloader.check_app_region(start..end)?;
+
+    if cli.action == Action::Verify {
+        say!(cli, "Comparing {} bytes at 0x{:08X}", bytes.len(), start);
+        let current = bootloader.read_flash(start as u32, bytes.len())?;
+        if let Some(i) = current.iter().zip(bytes).position(|(a, b)| a != b) {
+            return Err(anyhow::anyhow!(
+                "Verification failed at address 0x{:08X}: expected 0x{:02X}, read 0x{:02X}",
+                start + i, bytes[i], current[i]
+            ));
+        }
+        say!(cli, "Verify OK");
+        report.verified = true;
+        return Ok(());
+    }
+
+    if let Some(backup) = &cli.write.backup {
+        backup_flash(cli, bootloader, backup)?;
+    }
+
+    say!(cli, "Patching {} bytes at 0x{:08X}", bytes.len(), start);
+    let written = bootloader.patch_flash(start as u32, bytes)?;
+    say!(cli, "Flash complete ({} bytes in {} pages)", bytes.len(), written.pages_written);
+    report.bytes_written = bytes.len();
+
+    if !cli.write.no_verify {
+        say!(cli, "Verifying flash...");
+        let mut expected = vec![0xFF; start];
+        expected.extend_from_slice(bytes);
+        bootloader.verify_flash_ranges(&expected, std::slice::from_ref(&(start..end)))?;
+        say!(cli, "Verify OK");
+        report.verified = true;
+    } else {
+        bootloader.accept_unverified();
+    }
+
+    Ok(())
+}
+
+// Refuse to write over existing firmware unless --force
+fn check_erased(cli: &Cli, bootloader: &mut Bootloader) -> Result<()> {
+    let current = bootloader.read_flash(0, bootloader.flash_size() as usize)?;
+    let Some(addr) = current.iter().position(|&b| b != cli.image.flash_fill) else {
+        say!(cli, "Application region is erased");
+        return Ok(());
+    };
+
+    let message = format!(
+        "Application region is not erased, 0x{:08X} reads 0x{:02X}",
+        addr, current[addr]
+    );
+    if !cli.write.force {
+        return Err(anyhow::anyhow!("{}, use --force to flash anyway", message));
+    }
+    if !cli.output.quiet {
+        eprintln!("Warning: {}", message);
+    }
+
+    Ok(())
+}
+
+fn check_reset_vector(cli: &Cli, bootloader: &mut Bootloader, data: &[u8]) -> Result<()> {
+    bootloader.check_reset_vector(data)?;
+    say!(cli, "Reset vector OK");
+    Ok(())
+}
+
+fn erase_application(cli: &Cli, bootloader: &mut Bootloader) -> Result<()> {
+    let erased = bootloader.erase_flash()?;
+    say!(cli,
+        "Erased {} bytes ({} pages) in {:.2}s",
+        erased.bytes_written,
+        erased.pages_written,
+        erased.elapsed.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn dump_flash(cli: &Cli, bootloader: &mut Bootloader, output: &str) -> Result<()> {
+    let end = match cli.dump_range.flash_end {
+        Some(end) if cli.dump_range.full => end,
+        _ => bootloader.flash_size(),
+    };
+
+    say!(cli, "Reading flash 0x{:08X}..0x{:08X} into {}", 0, end, output);
+    let data = bootloader.read_flash(0, end as usize)?;
+    save_flash(output, &data).with_context(|| format!("Failed to write dump: {}", output))?;
+    say!(cli, "Read {} bytes", data.len());
+
+    Ok(())
+}
+
+/// Save the application region before anything is written, so a bad flash can be undone
+fn backup_flash(cli: &Cli, bootloader: &mut Bootloader, output: &str) -> Result<()> {
+    say!(cli, "Backing up flash 0x{:08X}..0x{:08X} to {}", 0, bootloader.flash_size(), output);
+    let data = bootloader.read_flash(0, bootloader.flash_size() as usize)?;
+    save_flash(output, &data)
+        .with_context(|| format!("Failed to write backup {}, nothing was flashed", output))?;
+
+    Ok(())
+}
+
+/// Read back what was just written, save it and compare its SHA-256 with the image's.
+///
+/// Only the bytes the image defines are hashed on both sides: gaps that
+/// `--skip-gaps` or `--offset` left alone hold whatever was there before. The
+/// capture is saved even if the hashes differ, it's the record of what the
+/// device really holds.
+fn capture_flash(cli: &Cli, bootloader: &mut Bootloader, image: &FirmwareImage, output: &str) -> Result<()> {
+    let readback = bootloader.read_flash(0, image.data.len())?;
+    save_flash(output, &readback).with_context(|| format!("Failed to write capture: {}", output))?;
+
+    let defined = |data: &[u8]| -> Vec<u8> {
+        image.defined.iter().flat_map(|range| data[range.clone()].iter().copied()).collect()
+    };
+    let image_bytes = defined(&image.data);
+    let image_hash = to_hex_string(&sha256(&image_bytes));
+    let flash_hash = to_hex_string(&sha256(&defined(&readback)));
+    match cli.output.output_format {
+        OutputFormat::Json => say_json(cli, format!(
+            r#"{{"capture":{},"bytes":{},"hashed":{},"image_sha256":"{}","flash_sha256":"{}","match":{}}}"#,
+            json_string(output), readback.len(), image_bytes.len(), image_hash, flash_hash, image_hash == flash_hash
+        )),
+        OutputFormat::Text => {
+            say!(cli, "Captured {} bytes of flash into {}", readback.len(), output);
+            say!(cli, "Image SHA-256: {} ({} bytes defined)", image_hash, image_bytes.len());
+            say!(cli, "Flash SHA-256: {}", flash_hash);
+        }
+    }
+
+    if image_hash != flash_hash {
+        return Err(anyhow::anyhow!("Flash read back into {} doesn't match the image", output));
+    }
+
+    Ok(())
+}
+
+// Intel HEX for a `.hex` name, raw binary otherwise
+fn save_flash(output: &str, data: &[u8]) -> Result<()> {
+    let content = match FileFormat::from_extension(Path::new(output)) {
+        FileFormat::Hex => to_hex(data).into_bytes(),
+        _ => data.to_vec(),
+    };
+    std::fs::write(output, content)?;
+
+    Ok(())
+}
+
+fn diff_file(cli: &Cli, bootloader: &mut Bootloader, filename: &str) -> Result<()> {
+    let filepath = PathBuf::from(filename);
+    let image = read_file_with_bootloader_info(&filepath, cli.input.format.file_format(&filepath), bootloader.flash_size(), cli.session.max_flash_size)?;
+    let current = bootloader.read_flash(0, image.data.len())?;
+    let changes = diff(&current, &image.data, &image.defined);
+    let changed: usize = changes.iter().map(|change| change.new.len()).sum();
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>();
+    if cli.output.output_format == OutputFormat::Json {
+        let changes: Vec<String> = changes
+            .iter()
+            .map(|c| format!(r#"{{"addr":{},"old":"{}","new":"{}"}}"#, c.addr, hex(&c.old).concat(), hex(&c.new).concat()))
+            .collect();
+        say_json(cli, format!(r#"{{"bytes_changed":{},"changes":[{}]}}"#, changed, changes.join(",")));
+        return Ok(());
+    }
+
+    for change in &changes {
+        say!(cli, "0x{:08X} -{}", change.addr, hex(&change.old).iter().map(|b| format!(" {}", b)).collect::<String>());
+        say!(cli, "{:10} +{}", "", hex(&change.new).iter().map(|b| format!(" {}", b)).collect::<String>());
+    }
+    say!(cli, "{} bytes differ in {} ranges", changed, changes.len());
+
+    Ok(())
+}
+
+fn print_free_map(cli: &Cli, bootloader: &mut Bootloader) -> Result<()> {
+    let flash_size = bootloader.flash_size();
+    let pagesize = bootloader.page_size() as usize;
+
+    let used = match &cli.file {
+        Some(filename) => {
+            let filepath = PathBuf::from(filename);
+            let format = cli.input.format.file_format(&filepath);
+            read_file_with_bootloader_info(&filepath, format, flash_size, cli.session.max_flash_size)?.defined
+        }
+        None => used_pages(&bootloader.read_flash(0, flash_size as usize)?, pagesize),
+    };
+
+    let regions = page_map(&used, pagesize, flash_size as usize);
+    let free = free_bytes(&regions);
+
+    if cli.output.output_format == OutputFormat::Json {
+        let regions: Vec<String> = regions
+            .iter()
+            .map(|r| format!(r#"{{"start":{},"end":{},"used":{}}}"#, r.start, r.end, r.used))
+            .collect();
+        say_json(cli, format!(
+            r#"{{"flash_size":{},"pagesize":{},"free_bytes":{},"regions":[{}]}}"#,
+            flash_size, pagesize, free, regions.join(",")
+        ));
+        return Ok(());
+    }
+
+    // The map is what was asked for, so --quiet doesn't hide it
+    for region in &regions {
+        println!(
+            "0x{:08X}-0x{:08X}  {}  {} bytes ({} pages)",
+            region.start,
+            region.end - 1,
+            if region.used { "used" } else { "free" },
+            region.len(),
+            region.len().div_ceil(pagesize)
+        );
+    }
+    println!("Free: {} of {} bytes", free, flash_size);
+
+    Ok(())
+}
+
+// Compact ": 0x0000-0x00FF, 0x0400-0x043F" list of the byte ranges covered by `pages`
+fn page_list(pages: &[u32], pagesize: u32) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &page in pages {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == page => *end = page + pagesize,
+            _ => ranges.push((page, page + pagesize)),
+        }
+    }
+
+    if ranges.is_empty() {
+        return String::new();
+    }
+    let list: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| format!("0x{:04X}-0x{:04X}", start, end - 1))
+        .collect();
+    format!(": {}", list.join(", "))
+}
+
+// Progress bar on stderr, only drawn for interactive terminals so logs stay clean
+fn print_progress(done: usize, total: usize) {
+    const WIDTH: usize = 40;
+
+    let mut stderr = std::io::stderr();
+    if total == 0 || !stderr.is_terminal() {
+        return;
+    }
+
+    let filled = done * WIDTH / total;
+    let _ = write!(
+        stderr,
+        "\r[{}{}] {:3}% ({}/{} bytes)",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        done * 100 / total,
+        done,
+        total
+    );
+    if done == total {
+        let _ = writeln!(stderr);
+    }
+}
+
+// Print a JSON object line with the --meta pairs merged in. Always on stdout and
+// also with --quiet: the JSON lines are the result, not progress text.
+fn say_json(cli: &Cli, json: String) {
+    if cli.output.meta.is_empty() {
+        println!("{}", json);
+        return;
+    }
+
+    let meta: Vec<String> = cli
+        .output
+        .meta
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect();
+    let body = json.strip_suffix('}').expect("JSON output is an object");
+    println!(r#"{},"meta":{{{}}}}}"#, body, meta.join(","));
+}
+
+fn verify_json(summary: &VerifySummary) -> String {
+    let mismatches: Vec<String> = summary
+        .details
+        .iter()
+        .map(|m| format!("{{\"addr\":{},\"expected\":{},\"actual\":{}}}", m.addr, m.expected, m.actual))
+        .collect();
+
+    format!(
+        "{{\"verified_bytes\":{},\"mismatches\":[{}],\"ok\":{}}}",
+        summary.bytes_verified,
+        mismatches.join(","),
+        summary.mismatches == 0
+    )
+}
+
+// Ping every `interval` until killed and print when a device starts or stops answering
+fn monitor(cli: &Cli, bus: u8, addresses: &[u8], interval: Duration) -> Result<()> {
+    let mut devices = addresses
+        .iter()
+        .map(|&address| Ok((address, open_bootloader(cli, bus, address)?, None)))
+        .collect::<Result<Vec<(u8, Bootloader, Option<bool>)>>>()?;
+
+    say!(cli, "Monitoring, Ctrl+C to stop");
+    let start = Instant::now();
+    loop {
+        for (address, bootloader, last) in devices.iter_mut() {
+            let up = bootloader.ping();
+            if *last != Some(up) {
+                let state = if up { "up (bootloader answers)" } else { "down" };
+                say!(cli, "[{:9.3}s] 0x{:02X} {}", start.elapsed().as_secs_f64(), address, state);
+                *last = Some(up);
+            }
+        }
+
+        std::thread::sleep(interval);
+    }
+}
+
+fn run_device(cli: &Cli, bus: u8, address: u8) -> Result<()> {
+    if cli.session.repeat > 1 {
+        run_repeated(cli, bus, address)
+    } else {
+        run(cli, bus, address).map(drop)
+    }
+}
+
+// Same job for several devices on one bus, one after the other
+fn run_each(cli: &Cli, bus: u8, addresses: &[u8]) -> Result<()> {
+    let mut results = Vec::with_capacity(addresses.len());
+
+    for &address in addresses {
+        say!(cli, "=== Device 0x{:02X} ===", address);
+        let result = run_device(cli, bus, address);
+        if let Err(e) = &result {
+            eprintln!("Error: 0x{:02X}: {:#}", address, e);
+        }
+        results.push((address, result));
+    }
+
+    say!(cli, "Summary:");
+    for (address, result) in &results {
+        match result {
+            Ok(()) => say!(cli, "  0x{:02X}: OK", address),
+            Err(e) => say!(cli, "  0x{:02X}: FAIL: {:#}", address, e),
+        }
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} of {} devices failed", failed, results.len()));
+    }
+
+    Ok(())
+}
+
+/// Burn-in mode: run the full cycle `--repeat` times and count the outcomes
+fn run_repeated(cli: &Cli, bus: u8, address: u8) -> Result<()> {
+    let mut passed = 0;
+    let mut failed = 0;
+
+    for iteration in 1..=cli.session.repeat {
+        say!(cli, "=== Iteration {}/{} ===", iteration, cli.session.repeat);
+        let start = Instant::now();
+
+        match run(cli, bus, address) {
+            Ok(_) => {
+                passed += 1;
+                say!(cli, "Iteration {}: PASS ({:.2}s)", iteration, start.elapsed().as_secs_f64());
+            }
+            Err(e) => {
+                failed += 1;
+                say!(cli, "Iteration {}: FAIL ({:.2}s): {:#}", iteration, start.elapsed().as_secs_f64(), e);
+                if cli.session.stop_on_fail {
+                    break;
+                }
+            }
+        }
+    }
+
+    say!(cli, "Burn-in finished: {} passed, {} failed", passed, failed);
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} of {} iterations failed", failed, passed + failed));
+    }
 
     Ok(())
 }
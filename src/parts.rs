@@ -0,0 +1,49 @@
+/// Datasheet values of an AVR part twiboot runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part {
+    pub name: &'static str,
+    pub signature: [u8; 3],
+    pub pagesize: u32,
+    /// Physical flash size, the bootloader start always lies below it
+    pub flash_size: u32,
+    /// Typical page erase + write time
+    pub write_delay_ms: u64,
+}
+
+const fn part(
+    name: &'static str,
+    signature: [u8; 3],
+    pagesize: u32,
+    flash_size: u32,
+    write_delay_ms: u64,
+) -> Part {
+    Part {
+        name,
+        signature,
+        pagesize,
+        flash_size,
+        write_delay_ms,
+    }
+}
+
+const PARTS: &[Part] = &[
+    part("ATtiny84", [0x1E, 0x93, 0x0C], 64, 8 * 1024, 5),
+    part("ATtiny85", [0x1E, 0x93, 0x0B], 64, 8 * 1024, 5),
+    part("ATtiny861", [0x1E, 0x93, 0x0D], 64, 8 * 1024, 5),
+    part("ATmega8", [0x1E, 0x93, 0x07], 64, 8 * 1024, 5),
+    part("ATmega88", [0x1E, 0x93, 0x0A], 64, 8 * 1024, 5),
+    part("ATmega88P", [0x1E, 0x93, 0x0F], 64, 8 * 1024, 5),
+    part("ATmega168", [0x1E, 0x94, 0x06], 128, 16 * 1024, 5),
+    part("ATmega168P", [0x1E, 0x94, 0x0B], 128, 16 * 1024, 5),
+    part("ATmega328", [0x1E, 0x95, 0x14], 128, 32 * 1024, 5),
+    part("ATmega328P", [0x1E, 0x95, 0x0F], 128, 32 * 1024, 5),
+    part("ATmega32U4", [0x1E, 0x95, 0x87], 128, 32 * 1024, 5),
+    part("ATmega644P", [0x1E, 0x96, 0x0A], 256, 64 * 1024, 5),
+    part("ATmega1284P", [0x1E, 0x97, 0x05], 256, 128 * 1024, 5),
+    part("ATmega2560", [0x1E, 0x98, 0x01], 256, 256 * 1024, 5),
+];
+
+/// Look up an AVR part by the signature bytes from chipinfo
+pub fn lookup(signature: &[u8]) -> Option<&'static Part> {
+    PARTS.iter().find(|part| part.signature == signature)
+}
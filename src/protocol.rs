@@ -1,8 +1,27 @@
 use anyhow::{Context, Result};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::i2c::TwiI2CDevice;
+use crate::builder::TwiBootloaderBuilder;
+use crate::checksum::crc32;
+use crate::i2c::{retry_is_pointless, I2CTransport, TwiI2CDevice};
+use crate::json::json_string;
+use crate::parts::{self, Part};
+
+// A `tracing` event inside the current phase span with the `trace` feature.
+// Without it the field values are still evaluated, so nothing turns unused.
+macro_rules! trace_event {
+    ($message:literal, $($field:ident = $value:expr),+ $(,)?) => {
+        #[cfg(feature = "trace")]
+        tracing::debug!($($field = $value),+, $message);
+        #[cfg(not(feature = "trace"))]
+        let _ = ($($value),+);
+    };
+}
 
 // TWI Commands (SLA+R)
 const CMD_READ_VERSION: u8 = 0x01;
@@ -19,9 +38,51 @@ const BOOTTYPE_APPLICATION: u8 = 0x80;
 // Memory type parameters
 const MEMTYPE_CHIPINFO: u8 = 0x00;
 const MEMTYPE_FLASH: u8 = 0x01;
+const MEMTYPE_EEPROM: u8 = 0x02;
+// Not in stock twiboot, some AVR variants expose low/high/extended fuse + lock here
+const MEMTYPE_FUSES: u8 = 0x04;
+// Not in stock twiboot either: reading at a page address returns the CRC-32 of
+// that page (big endian), computed on the device
+const MEMTYPE_PAGESUM: u8 = 0x08;
+// Also a variant extension: status byte read after a page write, anything else is an error code
+const WRITE_STATUS_OK: u8 = 0x00;
+
+// Bytes per EEPROM write transaction and the time to program each of them
+const EEPROM_WRITE_CHUNK: usize = 16;
+const EEPROM_BYTE_WRITE_TIME: Duration = Duration::from_micros(3400);
 
 // Block sizes
-const READ_BLOCK_SIZE: usize = 128;
+pub const DEFAULT_READ_BLOCK_SIZE: usize = 128;
+pub const DEFAULT_VERSION_LENGTH: usize = 12;
+
+// Upper bound for the device-reported flash size, guards against garbage chipinfo
+pub const DEFAULT_MAX_FLASH_SIZE: u32 = 256 * 1024;
+
+// Flash programming time per page
+pub const DEFAULT_WRITE_DELAY_MS: u64 = 5;
+
+// Repeats of a failed verify read before re-entering the bootloader
+pub const DEFAULT_VERIFY_READ_RETRIES: usize = 2;
+pub const DEFAULT_VERIFY_READ_RETRY_DELAY_MS: u64 = 10;
+
+// Repeats of the switch + version read in connect(), the pause doubles every time
+// up to the maximum
+pub const DEFAULT_SWITCH_RETRIES: usize = 1;
+const SWITCH_RETRY_DELAY_MS: u64 = 100;
+const SWITCH_RETRY_MAX_DELAY_MS: u64 = 2000;
+
+// Adaptive write delay (page delay scaling) limits
+const ADAPTIVE_MIN_DELAY_MS: u64 = 1;
+const ADAPTIVE_MAX_DELAY_MS: u64 = 100;
+const ADAPTIVE_MAX_ATTEMPTS: usize = 5;
+// Successful pages in a row before the delay is lowered again
+const ADAPTIVE_DECREASE_AFTER: usize = 8;
+
+// Application switch delays
+const DEFAULT_SWITCH_TO_BOOTLOADER_DELAY_MS: u64 = 100;
+const DEFAULT_REENTRY_DELAY_MS: u64 = 50;
+// Time the application needs to take over the bus after leaving the bootloader
+const APPLICATION_START_DELAY_MS: u64 = 50;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AddressWidth {
@@ -38,80 +99,920 @@ impl AddressWidth {
     }
 }
 
-pub struct TwiBootloader {
-    i2c: TwiI2CDevice,
+/// Order in which `verify_flash()` reads the blocks back
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyOrder {
+    #[default]
+    Ascending,
+    /// Highest address first, to catch bootloaders that assume reads follow
+    /// on from the previous address
+    Descending,
+}
+
+/// Byte order of the address in read and write commands
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressEndian {
+    /// Most significant byte first, what twiboot expects
+    #[default]
+    Big,
+    /// Least significant byte first, for bootloader variants that deviate
+    Little,
+}
+
+/// Address bytes of a read or write command
+pub fn encode_address(addr: u32, width: AddressWidth, endian: AddressEndian) -> Vec<u8> {
+    let bytes = &addr.to_be_bytes()[4 - width.bytes()..];
+    match endian {
+        AddressEndian::Big => bytes.to_vec(),
+        AddressEndian::Little => bytes.iter().rev().copied().collect(),
+    }
+}
+
+/// Outcome of a successful `write_flash()`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteSummary {
+    pub bytes_written: usize,
+    pub pages_written: usize,
+    /// 0xFF fill appended to short pages at the end of the image. Not part of
+    /// `bytes_written`, so verifying `bytes_written` bytes compares only real data.
+    pub padding_bytes: usize,
+    /// Pages left alone because the device already had the right content
+    pub pages_skipped: usize,
+    /// Addresses of the pages programmed with image data, in ascending order
+    pub written_pages: Vec<u32>,
+    /// Transport level retries needed while writing
+    pub retries: usize,
+    pub elapsed: Duration,
+    /// Page write delay in effect at the end, differs from the configured one
+    /// when page delay scaling adjusted it
+    pub write_delay: Duration,
+}
+
+/// Where a device was left at the end of a run, as far as the host knows
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeviceMode {
+    /// Never connected, or the run ended before that was clear
+    #[default]
+    Unknown,
+    Bootloader,
+    Application,
+}
+
+impl DeviceMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceMode::Unknown => "unknown",
+            DeviceMode::Bootloader => "bootloader",
+            DeviceMode::Application => "application",
+        }
+    }
+}
+
+/// Everything a whole connect -> write -> verify -> disconnect run did, for
+/// callers that present or check the result themselves
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlashReport {
+    /// `connect()` finished
+    pub connected: bool,
+    pub bytes_written: usize,
+    /// A verify pass over the written data found no mismatches
+    pub verified: bool,
+    /// Transport level retries over the whole run
+    pub retries: usize,
+    /// Retries caused by another master winning bus arbitration (part of `retries`)
+    pub arbitration_losses: usize,
+    pub elapsed: Duration,
+    pub final_mode: DeviceMode,
+}
+
+impl FlashReport {
+    /// The report as one JSON object, with `error` (the run's error, if it failed)
+    /// as its message and `ok` false
+    pub fn to_json(&self, error: Option<&anyhow::Error>) -> String {
+        let error = match error {
+            Some(e) => format!(r#","error":{}"#, json_string(&format!("{:#}", e))),
+            None => String::new(),
+        };
+        format!(
+            r#"{{"connected":{},"bytes_written":{},"verified":{},"retries":{},"arbitration_losses":{},"elapsed_ms":{},"final_mode":"{}","ok":{}{}}}"#,
+            self.connected,
+            self.bytes_written,
+            self.verified,
+            self.retries,
+            self.arbitration_losses,
+            self.elapsed.as_millis(),
+            self.final_mode.name(),
+            error.is_empty(),
+            error
+        )
+    }
+}
+
+/// Failed `flash()`, with how far the run got.
+/// Use `err.downcast_ref::<FlashError>()` to get at the report.
+#[derive(Debug)]
+pub struct FlashError {
+    pub report: FlashReport,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for FlashError {}
+
+/// How `connect()` prints addresses and sizes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Radix {
+    /// Hex and decimal side by side, e.g. `0x00002000 / 8192 bytes [8KB]`
+    #[default]
+    Mixed,
+    /// Only `0x` prefixed, zero padded upper case hex
+    Hex,
+    /// Only plain decimal
+    Dec,
+}
+
+impl Radix {
+    fn format(self, value: u32, hex_digits: usize) -> String {
+        match self {
+            Radix::Mixed => format!("0x{:0width$X} / {}", value, value, width = hex_digits),
+            Radix::Hex => format!("0x{:0width$X}", value, width = hex_digits),
+            Radix::Dec => value.to_string(),
+        }
+    }
+}
+
+/// A single byte that didn't read back as expected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub addr: u32,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// Outcome of `verify_flash()`. On mismatch it's carried inside `VerifyError`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifySummary {
+    pub bytes_verified: usize,
+    /// Number of bytes that differ from the expected image
+    pub mismatches: usize,
+    /// The differing bytes: all of them with report-all, otherwise those of the
+    /// bad read block with the lowest address
+    pub details: Vec<Mismatch>,
+    pub elapsed: Duration,
+}
+
+/// Returned (wrapped in `anyhow::Error`) when the flash content doesn't match.
+/// Use `err.downcast_ref::<VerifyError>()` to get at the details.
+#[derive(Clone, Debug)]
+pub struct VerifyError {
+    pub first_mismatch: u32,
+    pub summary: VerifySummary,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Verification failed at address 0x{:08X} ({} bytes differ)",
+            self.first_mismatch, self.summary.mismatches
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Returned (wrapped in `anyhow::Error`) by `connect()` when the bootloader
+/// doesn't report the version set with `set_expect_version()`
+#[derive(Clone, Debug)]
+pub struct UnexpectedVersion {
+    pub expected: String,
+    pub version: String,
+}
+
+impl fmt::Display for UnexpectedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unexpected bootloader version: expected \"{}\", got \"{}\"",
+            self.expected, self.version
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedVersion {}
+
+/// Outcome of `link_test()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkTestSummary {
+    pub rounds: usize,
+    /// Rounds where version or chipinfo differed from the first read
+    pub mismatches: usize,
+    /// Transport level retries needed during the test
+    pub retries: usize,
+}
+
+/// Outcome of `measure_clock()`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockEstimate {
+    /// Reads of each size that were timed
+    pub rounds: usize,
+    /// Effective SCL frequency, `None` if the long reads weren't measurably
+    /// slower than the short ones (simulated or very fast transports)
+    pub clock_hz: Option<f64>,
+    /// Time one read transaction costs on top of its data bytes
+    pub overhead: Duration,
+}
+
+/// AVR fuse and lock bytes as reported by bootloader variants that support it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fuses {
+    pub low: u8,
+    pub high: u8,
+    pub extended: u8,
+    pub lock: u8,
+}
+
+/// Chipinfo as read by `connect()`, layout depends on the bootloader generation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChipInfo {
+    /// twiboot v3.x on AVR, 8 bytes
+    Avr {
+        signature: [u8; 3],
+        page_size: u8,
+        /// Called flash size in twiboot, but it's the start of the bootloader,
+        /// i.e. the size of the application region, not the physical flash
+        boot_start: u16,
+        eeprom_size: u16,
+    },
+    /// twiboot v4.x on CH32V, 12 bytes
+    Ch32v {
+        chip_id: u32,
+        /// Application flash size, like `boot_start` on AVR
+        flash_size: u32,
+        /// 0 on some builds, 64 is assumed then
+        page_size: u16,
+    },
+}
+
+/// Receiver for the lines `TwiBootloader` would otherwise print, see `set_output()`
+pub type OutputFn = dyn Fn(&str) + Send;
+
+pub struct TwiBootloader<T: I2CTransport = TwiI2CDevice> {
+    i2c: T,
     pagesize: u32,
     flashsize: u32,
     address_width: AddressWidth,
+    address_endian: AddressEndian,
+    switch_to_bootloader_delay: Duration,
+    reentry_delay: Duration,
+    reset_vector_last: bool,
+    max_flash_size: u32,
+    version_length: usize,
+    expect_version: Option<String>,
+    version: String,
+    application_started: bool,
+    fuses: Option<Fuses>,
+    fuses_on_connect: bool,
+    verify_report_all: bool,
+    write_delay: Duration,
+    // What page writes wait, write_delay raised by part defaults or adjusted by
+    // page delay scaling; back to write_delay on every connect()
+    effective_write_delay: Duration,
+    adaptive_write_delay: bool,
+    adaptive_streak: usize,
+    skip_unchanged: bool,
+    quiet: bool,
+    radix: Radix,
+    read_block_size: usize,
+    write_page_size: Option<u32>,
+    part: Option<&'static Part>,
+    use_part_defaults: bool,
+    verify_matches_write: bool,
+    mask_version_msb: bool,
+    interleave_verify: bool,
+    no_pad: bool,
+    no_final_pad: bool,
+    write_status: bool,
+    chip_info: Option<ChipInfo>,
+    lenient_erased: bool,
+    show_raw_chipinfo: bool,
+    connect_timeout: Option<Duration>,
+    output: Option<Box<OutputFn>>,
+    verify_read_retries: usize,
+    verify_read_retry_delay: Duration,
+    verify_order: VerifyOrder,
+    switch_retries: usize,
+    switch_on_drop: bool,
+    flash_fill: u8,
+    connected: bool,
+    write_incomplete: bool,
+    // Set by every flash page write, cleared once the write is verified (or
+    // declared good without); release() doesn't start an application before
+    image_untrusted: bool,
 }
 
-impl TwiBootloader {
-    pub fn new(i2c: TwiI2CDevice) -> Self {
+impl<T: I2CTransport> TwiBootloader<T> {
+    pub fn new(i2c: T) -> Self {
         Self {
             i2c,
             pagesize: 0,
             flashsize: 0,
             address_width: AddressWidth::Bits16,
+            address_endian: AddressEndian::Big,
+            switch_to_bootloader_delay: Duration::from_millis(
+                DEFAULT_SWITCH_TO_BOOTLOADER_DELAY_MS,
+            ),
+            reentry_delay: Duration::from_millis(DEFAULT_REENTRY_DELAY_MS),
+            reset_vector_last: false,
+            max_flash_size: DEFAULT_MAX_FLASH_SIZE,
+            version_length: DEFAULT_VERSION_LENGTH,
+            expect_version: None,
+            version: String::new(),
+            application_started: false,
+            fuses: None,
+            fuses_on_connect: false,
+            verify_report_all: false,
+            write_delay: Duration::from_millis(DEFAULT_WRITE_DELAY_MS),
+            effective_write_delay: Duration::from_millis(DEFAULT_WRITE_DELAY_MS),
+            adaptive_write_delay: false,
+            adaptive_streak: 0,
+            skip_unchanged: false,
+            quiet: false,
+            radix: Radix::Mixed,
+            read_block_size: DEFAULT_READ_BLOCK_SIZE,
+            write_page_size: None,
+            part: None,
+            use_part_defaults: false,
+            verify_matches_write: false,
+            mask_version_msb: true,
+            interleave_verify: false,
+            no_pad: false,
+            no_final_pad: false,
+            write_status: false,
+            chip_info: None,
+            lenient_erased: false,
+            show_raw_chipinfo: false,
+            connect_timeout: None,
+            output: None,
+            verify_read_retries: DEFAULT_VERIFY_READ_RETRIES,
+            verify_read_retry_delay: Duration::from_millis(DEFAULT_VERIFY_READ_RETRY_DELAY_MS),
+            verify_order: VerifyOrder::Ascending,
+            switch_retries: DEFAULT_SWITCH_RETRIES,
+            switch_on_drop: true,
+            flash_fill: 0xFF,
+            connected: false,
+            write_incomplete: false,
+            image_untrusted: false,
+        }
+    }
+
+    /// Configure a bootloader fluently instead of with the setters, e.g.
+    /// `TwiBootloader::builder(i2c).write_delay(Duration::from_millis(10)).build()`
+    pub fn builder(i2c: T) -> TwiBootloaderBuilder<T> {
+        TwiBootloaderBuilder::new(i2c)
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.i2c
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.i2c
+    }
+
+    /// Time to wait after switching a running application into the bootloader
+    /// (watchdog reset + bootloader startup). Used by `connect()` and when
+    /// `verify_flash()` has to recover from the device dropping into the application.
+    pub fn set_switch_to_bootloader_delay(&mut self, delay: Duration) {
+        self.switch_to_bootloader_delay = delay;
+    }
+
+    /// Upper bound for `connect()`, also ends the wait loop of `connect(true)`.
+    /// Checked between the connect stages, a transfer in progress isn't cut
+    /// short (the adapter timeout bounds those).
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Time to wait after re-asserting bootloader mode on a device that should
+    /// already be in it, before `verify_flash()` starts reading back.
+    pub fn set_reentry_delay(&mut self, delay: Duration) {
+        self.reentry_delay = delay;
+    }
+
+    /// Erase page 0 before writing and program it as the very last page, so an
+    /// interrupted `write_flash()` never leaves a valid reset vector pointing
+    /// into a partially written application.
+    pub fn set_reset_vector_last(&mut self, enabled: bool) {
+        self.reset_vector_last = enabled;
+    }
+
+    /// Largest flash size `connect()` accepts from chipinfo and the largest
+    /// image `write_flash()` will send (default 256KB).
+    pub fn set_max_flash_size(&mut self, max_flash_size: u32) {
+        self.max_flash_size = max_flash_size;
+    }
+
+    /// Time to wait after each page write for the flash programming to finish
+    /// (default 5ms).
+    pub fn set_write_delay(&mut self, delay: Duration) {
+        self.write_delay = delay;
+        self.effective_write_delay = delay;
+    }
+
+    /// Page delay scaling: read every page back right after writing it. On a
+    /// mismatch the write delay is doubled and the page rewritten; after a run of
+    /// good pages the delay is lowered by 1ms again (never below 1ms), so it
+    /// settles at what the device really needs.
+    pub fn set_adaptive_write_delay(&mut self, enabled: bool) {
+        self.adaptive_write_delay = enabled;
+    }
+
+    /// Ignore what the device reads back past the end of the image in the last
+    /// page. `verify_flash()` never looks there, this is for the checks that
+    /// compare whole pages (page checksums, interleaved verify, skip unchanged),
+    /// on devices whose never written bytes don't read as 0xFF.
+    pub fn set_lenient_erased(&mut self, enabled: bool) {
+        self.lenient_erased = enabled;
+    }
+
+    /// Refuse to write data that doesn't end on a page boundary instead of
+    /// filling the last page with 0xFF, so truncated or odd sized images surface
+    pub fn set_no_pad(&mut self, enabled: bool) {
+        self.no_pad = enabled;
+    }
+
+    /// Send a short last page with only its real bytes instead of filling it up
+    /// to a full page. Only for bootloader variants that accept partial page
+    /// writes: stock twiboot expects every write to be a full page.
+    pub fn set_no_final_pad(&mut self, enabled: bool) {
+        self.no_final_pad = enabled;
+    }
+
+    /// Read a status byte back after each page write and fail with the code the
+    /// device reports when it isn't 0x00. Only for bootloader variants that
+    /// answer with one: stock twiboot has nothing to read after a write.
+    pub fn set_write_status(&mut self, enabled: bool) {
+        self.write_status = enabled;
+    }
+
+    fn check_padding(&self, len: usize) -> Result<()> {
+        if self.no_pad && !len.is_multiple_of(self.pagesize as usize) {
+            return Err(anyhow::anyhow!(
+                "Image of {} bytes is not a multiple of the {} byte page size ({} bytes short of the next page)",
+                len,
+                self.pagesize,
+                len.next_multiple_of(self.pagesize as usize) - len
+            ));
         }
+
+        Ok(())
+    }
+
+    /// Read every page back right after writing it and stop at the first one
+    /// that still differs after a rewrite, naming that page. Catches a failing
+    /// write before the rest of the image goes out, unlike the verify pass.
+    pub fn set_interleave_verify(&mut self, enabled: bool) {
+        self.interleave_verify = enabled;
+    }
+
+    /// Bytes per read transaction for verify, dumps and EEPROM reads (default 128)
+    pub fn set_read_block_size(&mut self, size: usize) {
+        self.read_block_size = size.max(1);
+    }
+
+    /// How often a failed verify read is repeated (command included) before the
+    /// bootloader is re-entered (default 2). The transport's own read retries
+    /// only repeat the read part.
+    pub fn set_verify_read_retries(&mut self, retries: usize) {
+        self.verify_read_retries = retries;
+    }
+
+    /// Start the application when the bootloader is dropped without `disconnect()`,
+    /// e.g. after an error (default on). Best effort, errors are ignored. Never
+    /// done before `connect()`, while a flash write was cut short or after a
+    /// write that wasn't verified (see `accept_unverified()`), so a half-written
+    /// or corrupted application isn't started.
+    pub fn set_switch_on_drop(&mut self, enabled: bool) {
+        self.switch_on_drop = enabled;
+    }
+
+    /// Byte the last page is padded with and `erase_flash()` writes (default 0xFF,
+    /// what erased flash reads as)
+    pub fn set_flash_fill(&mut self, fill: u8) {
+        self.flash_fill = fill;
+    }
+
+    /// Pause before each verify read retry (default 10ms)
+    pub fn set_verify_read_retry_delay(&mut self, delay: Duration) {
+        self.verify_read_retry_delay = delay;
+    }
+
+    /// Verify in page sized, page aligned reads, the same transactions
+    /// `write_flash()` uses, instead of read block size chunks
+    pub fn set_verify_matches_write(&mut self, enabled: bool) {
+        self.verify_matches_write = enabled;
+    }
+
+    /// Use this page size for writes instead of the one from chipinfo. It has
+    /// to be a power of two and a multiple or divisor of the reported page size,
+    /// `connect()` fails otherwise. Meant for experiments with odd devices.
+    pub fn set_write_page_size(&mut self, size: Option<u32>) {
+        self.write_page_size = size;
+    }
+
+    /// When chipinfo disagrees with the known part for its signature, use the
+    /// part's page size instead of the reported one, and raise the write delay to
+    /// the part's typical page write time. Without it the mismatch is only warned about.
+    pub fn set_use_part_defaults(&mut self, enabled: bool) {
+        self.use_part_defaults = enabled;
+    }
+
+    /// Don't print anything (device info, resume and delay notes), errors are
+    /// still returned as usual
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
     }
 
+    /// Hand every message (device info from `connect()`, resume and delay notes,
+    /// warnings) to `output` instead of printing it to stdout (warnings to stderr),
+    /// one line per call. The same
+    /// data is available from the getters (`version()`, `chip_info()`, `fuses()`,
+    /// `part()`), so callers that present it themselves can pass `|_| {}`.
+    pub fn set_output(&mut self, output: impl Fn(&str) + Send + 'static) {
+        self.output = Some(Box::new(output));
+    }
+
+    fn say(&self, message: fmt::Arguments) {
+        match (&self.output, self.quiet) {
+            (_, true) => {}
+            (Some(output), false) => output(&message.to_string()),
+            (None, false) => println!("{}", message),
+        }
+    }
+
+    // Like say(), but on stderr when there's no output function
+    fn warn(&self, message: fmt::Arguments) {
+        match (&self.output, self.quiet) {
+            (_, true) => {}
+            (Some(output), false) => output(&message.to_string()),
+            (None, false) => eprintln!("{}", message),
+        }
+    }
+
+    /// How often `connect()` repeats switching into the bootloader and reading
+    /// the version when that fails (default 1), waiting 100ms, 200ms, 400ms, ...
+    /// up to 2s in between, but never past the connect timeout. Covers
+    /// applications that only service TWI now and then. `connect(true)` keeps
+    /// trying on its own and doesn't use them.
+    pub fn set_switch_retries(&mut self, retries: usize) {
+        self.switch_retries = retries;
+    }
+
+    /// Read the blocks of `verify_flash()` back in this order (default ascending),
+    /// independent of the order they were written in. Descending always reads
+    /// everything, so `VerifyError::first_mismatch` is the lowest bad address.
+    pub fn set_verify_order(&mut self, order: VerifyOrder) {
+        self.verify_order = order;
+    }
+
+    /// Byte order of command addresses (default big endian like twiboot). The
+    /// width still follows the version string.
+    pub fn set_address_endian(&mut self, endian: AddressEndian) {
+        self.address_endian = endian;
+    }
+
+    /// Number format of the device info printed by `connect()`
+    pub fn set_radix(&mut self, radix: Radix) {
+        self.radix = radix;
+    }
+
+    /// Incremental flashing: read every page before writing and skip the ones
+    /// that already hold the right content. Saves flash wear and time when only
+    /// a small part of the image changed. Not used by resumable writes.
+    pub fn set_skip_unchanged(&mut self, enabled: bool) {
+        self.skip_unchanged = enabled;
+    }
+
+    /// Keep verifying after the first mismatch and collect every differing byte
+    /// instead of stopping at the first bad read block.
+    pub fn set_verify_report_all(&mut self, enabled: bool) {
+        self.verify_report_all = enabled;
+    }
+
+    /// Number of bytes read for the version string (default 12, enough for
+    /// "TWIBOOT vX.Y"). Stock twiboot answers with up to 16 bytes.
+    pub fn set_version_length(&mut self, length: usize) {
+        self.version_length = length;
+    }
+
+    /// Make `connect()` fail unless the version string contains `expected`
+    /// (default: any version). A different bootloader build may speak another
+    /// protocol variant, so nothing else is sent to it.
+    pub fn set_expect_version(&mut self, expected: Option<String>) {
+        self.expect_version = expected;
+    }
+
+    /// Print the chipinfo bytes as read, before they're interpreted, to see
+    /// what an odd device really returns
+    pub fn set_show_raw_chipinfo(&mut self, enabled: bool) {
+        self.show_raw_chipinfo = enabled;
+    }
+
+    /// Read fuse and lock bytes while connecting (default off). The fuse memtype
+    /// is a variant extension, stock twiboot never sees it unless this is on.
+    pub fn set_read_fuses(&mut self, enabled: bool) {
+        self.fuses_on_connect = enabled;
+    }
+
+    /// Clear bit 7 of every version byte before decoding (default on, like the
+    /// reference host tool). Off, bytes with bit 7 set are dropped as non-ASCII
+    /// instead of being turned into different characters.
+    pub fn set_mask_version_msb(&mut self, enabled: bool) {
+        self.mask_version_msb = enabled;
+    }
+
+    #[cfg_attr(feature = "trace", tracing::instrument(name = "connect", skip_all, fields(address = self.i2c.address(), wait = wait), err))]
     pub fn connect(&mut self, wait: bool) -> Result<()> {
+        // Whatever an earlier run adjusted the delay to, start from the configured one
+        self.effective_write_delay = self.write_delay;
+        self.adaptive_streak = 0;
+
+        let deadline = self.connect_timeout.map(|timeout| Instant::now() + timeout);
         if wait {
             loop {
-                match self.try_connect() {
+                // This loop already repeats the switch, no need for the retries on top
+                match self.try_connect(deadline, 0) {
                     Ok(()) => break,
+                    // Waiting won't change the build that answered
+                    Err(e) if e.is::<UnexpectedVersion>() => return Err(e),
+                    Err(e) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                        return Err(e.context(self.connect_timeout_message()));
+                    }
                     Err(e) => {
-                        println!(
+                        self.say(format_args!(
                             "Connection failed: {}. Retrying in 100ms... (Ctrl+C to cancel)",
                             e
-                        );
+                        ));
                         thread::sleep(Duration::from_millis(100));
                     }
                 }
             }
         } else {
-            self.try_connect()?;
+            self.try_connect(deadline, self.switch_retries)?;
         }
         Ok(())
     }
 
-    fn address_to_bytes(&self, addr: u32) -> Vec<u8> {
-        match self.address_width {
-            AddressWidth::Bits16 => vec![(addr >> 8) as u8, (addr & 0xFF) as u8],
-            AddressWidth::Bits32 => vec![
-                ((addr >> 24) & 0xFF) as u8,
-                ((addr >> 16) & 0xFF) as u8,
-                ((addr >> 8) & 0xFF) as u8,
-                (addr & 0xFF) as u8,
-            ],
+    fn connect_timeout_message(&self) -> String {
+        format!(
+            "No bootloader connection within {}ms",
+            self.connect_timeout.unwrap_or_default().as_millis()
+        )
+    }
+
+    // Fails once the connect timeout has passed, checked between the connect stages
+    fn check_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(anyhow::anyhow!(self.connect_timeout_message()))
+            }
+            _ => Ok(()),
         }
     }
 
-    fn try_connect(&mut self) -> Result<()> {
-        // Switch to bootloader mode
-        self.switch_application(BOOTTYPE_BOOTLOADER)?;
+    /// Check whether a bootloader answers on the bus.
+    ///
+    /// Does a single version read without retries, doesn't switch modes and prints nothing.
+    pub fn ping(&mut self) -> bool {
+        let cmd = [CMD_READ_VERSION];
+        let mut buffer = [0u8; 1];
 
-        // Wait for watchdog and startup time
-        thread::sleep(Duration::from_millis(100));
+        self.i2c.write_then_read_once(&cmd, &mut buffer).is_ok()
+    }
 
-        // Read version
-        let version = self.read_version()?;
-        println!("Version: {}", version);
+    /// Version string of a bootloader answering on the bus, `None` if nothing
+    /// (or something that isn't twiboot) answers.
+    ///
+    /// Like `ping()` a single attempt that doesn't switch modes, so devices
+    /// running their application aren't found.
+    pub fn probe(&mut self) -> Option<String> {
+        let cmd = [CMD_READ_VERSION];
+        let mut buffer = vec![0u8; self.version_length];
+
+        self.i2c.write_then_read_once(&cmd, &mut buffer).ok()?;
+        Some(self.decode_version(&mut buffer)).filter(|version| version.starts_with("TWIBOOT"))
+    }
+
+    fn address_to_bytes(&self, addr: u32) -> Vec<u8> {
+        encode_address(addr, self.address_width, self.address_endian)
+    }
+
+    // Each stage fails with its own message, so it's clear how far the device got
+    fn try_connect(&mut self, deadline: Option<Instant>, switch_retries: usize) -> Result<()> {
+        // An application that only polls TWI now and then can NAK the switch for
+        // longer than the transport retries cover, so the whole step is repeated
+        let mut attempt = 0;
+        let version = loop {
+            match self.enter_bootloader() {
+                Ok(version) => break version,
+                Err(e) if attempt < switch_retries && !retry_is_pointless(&e) => {
+                    let mut delay = Duration::from_millis(
+                        (SWITCH_RETRY_DELAY_MS << attempt.min(5)).min(SWITCH_RETRY_MAX_DELAY_MS),
+                    );
+                    if let Some(deadline) = deadline {
+                        delay = delay.min(deadline.saturating_duration_since(Instant::now()));
+                    }
+                    self.say(format_args!("{}, retrying in {}ms", e, delay.as_millis()));
+                    attempt += 1;
+                    thread::sleep(delay);
+                    self.check_deadline(deadline)?;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.say(format_args!("Version: {}", version));
+        if let Some(expected) = self
+            .expect_version
+            .as_ref()
+            .filter(|e| !version.contains(e.as_str()))
+        {
+            return Err(UnexpectedVersion {
+                expected: expected.clone(),
+                version,
+            }
+            .into());
+        }
+        self.check_deadline(deadline)?;
 
         // Set addressing mode from version string
         self.set_address_width_from_version(&version);
+        self.version = version;
 
         // Read chip info
-        let chipinfo = self.read_chipinfo()?;
+        let chipinfo = self.read_chipinfo().context(
+            "Chipinfo read failed: the bootloader answered its version but not the chipinfo (unstable bus? try --read-block-size or --link-test)",
+        )?;
+        if self.show_raw_chipinfo {
+            // All 12 bytes read, AVR bootloaders only fill the first 8
+            let raw: Vec<String> = chipinfo.iter().map(|b| format!("{:02X}", b)).collect();
+            self.say(format_args!("Chipinfo (raw): {}", raw.join(" ")));
+        }
         self.parse_chipinfo(&chipinfo)?;
+        self.apply_write_page_size()?;
+
+        self.fuses = if self.fuses_on_connect {
+            self.read_fuses()?
+        } else {
+            None
+        };
+        if let Some(fuses) = self.fuses {
+            self.say(format_args!(
+                "Fuses: low 0x{:02X} high 0x{:02X} extended 0x{:02X} lock 0x{:02X}",
+                fuses.low, fuses.high, fuses.extended, fuses.lock
+            ));
+        }
 
-        println!("Device: I2C address 0x{:02X}", self.i2c.address);
+        let address = match self.radix {
+            Radix::Mixed => Radix::Hex,
+            radix => radix,
+        };
+        self.say(format_args!(
+            "Device: I2C address {}",
+            address.format(self.i2c.address() as u32, 2)
+        ));
 
+        self.application_started = false;
+        self.connected = true;
         Ok(())
     }
 
+    // Switch to bootloader mode and read the version once it had time to start
+    fn enter_bootloader(&mut self) -> Result<String> {
+        self.switch_application(BOOTTYPE_BOOTLOADER).context(
+            "Switch command not acknowledged: nothing answers at this address (check the address, wiring and pull-ups)",
+        )?;
+
+        // Wait for watchdog and startup time
+        thread::sleep(self.switch_to_bootloader_delay);
+
+        self.read_version().context(
+            "No version response: the switch was acknowledged, but no bootloader answers (application without bootloader entry, or try a longer switch delay)",
+        )
+    }
+
+    /// Read version and chipinfo `rounds` times and compare them with the first
+    /// read, to catch an unstable bus before anything is written.
+    ///
+    /// Fails on the first read error. Differing answers are counted, as are the
+    /// retries the transport needed; judging them is up to the caller.
+    pub fn link_test(&mut self, rounds: usize) -> Result<LinkTestSummary> {
+        let retries_before = self.i2c.retries();
+        let version = self.read_version()?;
+        let chipinfo = self.read_chipinfo()?;
+
+        let mut mismatches = 0;
+        for round in 1..rounds {
+            let consistent = self
+                .read_version()
+                .with_context(|| format!("Link test round {}", round + 1))?
+                == version
+                && self
+                    .read_chipinfo()
+                    .with_context(|| format!("Link test round {}", round + 1))?
+                    == chipinfo;
+            if !consistent {
+                mismatches += 1;
+            }
+        }
+
+        Ok(LinkTestSummary {
+            rounds,
+            mismatches,
+            retries: self.i2c.retries() - retries_before,
+        })
+    }
+
+    /// Estimate the effective bus clock by timing `rounds` one byte flash reads
+    /// and `rounds` reads of the read block size.
+    ///
+    /// The difference between the two is pure data transfer, 9 clocks per byte
+    /// (8 bits and the ACK), the rest of a short read is per transaction overhead.
+    pub fn measure_clock(&mut self, rounds: usize) -> Result<ClockEstimate> {
+        let long = self
+            .read_block_size
+            .clamp(2, self.flashsize.max(2) as usize);
+        let mut time_reads = |len: usize| -> Result<Duration> {
+            let mut buffer = vec![0u8; len];
+            let start = Instant::now();
+            for _ in 0..rounds {
+                self.read_memory(MEMTYPE_FLASH, 0, &mut buffer)
+                    .context("Clock measurement read failed")?;
+            }
+            Ok(start.elapsed() / rounds.max(1) as u32)
+        };
+        let short_read = time_reads(1)?;
+        let long_read = time_reads(long)?;
+
+        let per_byte = long_read.saturating_sub(short_read).as_secs_f64() / (long - 1) as f64;
+        let clock_hz = (per_byte > 0.0).then(|| 9.0 / per_byte);
+
+        Ok(ClockEstimate {
+            rounds,
+            clock_hz,
+            overhead: short_read.saturating_sub(Duration::from_secs_f64(per_byte)),
+        })
+    }
+
+    /// Version string read by the last successful `connect()`
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Start the application and confirm the bootloader is gone.
+    ///
+    /// The switch command is only sent once per session: calling `disconnect()`
+    /// again after a confirmed switch is a no-op, so a retry in the caller can't
+    /// reset the freshly started application a second time.
     pub fn disconnect(&mut self) -> Result<()> {
-        self.switch_application(BOOTTYPE_APPLICATION)
+        if self.application_started {
+            return Ok(());
+        }
+
+        self.start_application()
+    }
+
+    #[cfg_attr(feature = "trace", tracing::instrument(name = "disconnect", skip_all, fields(address = self.i2c.address()), err))]
+    fn start_application(&mut self) -> Result<()> {
+        self.switch_application(BOOTTYPE_APPLICATION)?;
+        thread::sleep(Duration::from_millis(APPLICATION_START_DELAY_MS));
+
+        if self.bootloader_answers() {
+            return Err(anyhow::anyhow!(
+                "Device still answers as bootloader after switching to application (no valid application?)"
+            ));
+        }
+
+        self.application_started = true;
+        Ok(())
+    }
+
+    // Single version read, compared against the one from connect() if we have it.
+    // An application may ACK the read, but it won't answer with our version string.
+    fn bootloader_answers(&mut self) -> bool {
+        let cmd = [CMD_READ_VERSION];
+        let mut buffer = vec![0u8; self.version_length];
+
+        if self.i2c.write_then_read_once(&cmd, &mut buffer).is_err() {
+            return false;
+        }
+
+        self.version.is_empty() || self.decode_version(&mut buffer) == self.version
     }
 
     fn switch_application(&mut self, app_type: u8) -> Result<()> {
@@ -124,51 +1025,226 @@ impl TwiBootloader {
 
     fn read_version(&mut self) -> Result<String> {
         let cmd = [CMD_READ_VERSION];
-        let mut buffer = [0u8; 12];
+        let mut buffer = vec![0u8; self.version_length];
 
         self.i2c
             .write_then_read(&cmd, &mut buffer)
             .context("Failed to read version")?;
 
-        // Clear MSB from each byte (as per original code)
-        for byte in &mut buffer {
-            *byte &= 0x7F;
+        Ok(self.decode_version(&mut buffer))
+    }
+
+    fn decode_version(&self, buffer: &mut [u8]) -> String {
+        // The reference twiboot host tool clears bit 7 of every version byte, so
+        // that's what existing devices are known to work with
+        if self.mask_version_msb {
+            for byte in buffer.iter_mut() {
+                *byte &= 0x7F;
+            }
         }
 
-        // Convert to string - protocol spec says "ASCII, not null terminated"
-        // So we read all 16 bytes and trim trailing nulls/spaces
-        let version = String::from_utf8_lossy(&buffer)
-            .trim_end_matches('\0')
-            .trim_end()
-            .to_string();
+        // Protocol spec says "ASCII, not null terminated", so we read a fixed
+        // number of bytes. Stop at the first NUL and drop whatever isn't printable,
+        // shorter strings are padded with NULs or garbage depending on the build.
+        let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+        let version: String = buffer[..end]
+            .iter()
+            .filter(|b| b.is_ascii_graphic() || **b == b' ')
+            .map(|&b| b as char)
+            .collect();
 
-        Ok(version)
+        version.trim().to_string()
     }
 
     fn read_chipinfo(&mut self) -> Result<[u8; 12]> {
-        let mut cmd = Vec::with_capacity(2 + self.address_width.bytes());
-        cmd.push(CMD_READ_MEMORY);
-        cmd.push(MEMTYPE_CHIPINFO);
-        cmd.extend_from_slice(&self.address_to_bytes(0));
         let mut chipinfo = [0u8; 12];
 
-        self.i2c
-            .write_then_read(&cmd, &mut chipinfo)
+        self.read_memory(MEMTYPE_CHIPINFO, 0, &mut chipinfo)
             .context("Failed to read chip info")?;
 
         Ok(chipinfo)
     }
 
+    fn read_memory(&mut self, memtype: u8, addr: u32, buffer: &mut [u8]) -> Result<()> {
+        let mut cmd = Vec::with_capacity(2 + self.address_width.bytes());
+        cmd.push(CMD_READ_MEMORY);
+        cmd.push(memtype);
+        cmd.extend_from_slice(&self.address_to_bytes(addr));
+
+        self.i2c.write_then_read(&cmd, buffer)?;
+        Ok(())
+    }
+
+    /// Read fuse and lock bytes, `None` if the bootloader doesn't expose them.
+    ///
+    /// Only AVR (16-bit) bootloaders can have them. Stock twiboot either NAKs the
+    /// unknown memtype or answers with 0xFF, both mean "not supported".
+    pub fn read_fuses(&mut self) -> Result<Option<Fuses>> {
+        if self.address_width != AddressWidth::Bits16 {
+            return Ok(None);
+        }
+
+        let mut cmd = Vec::with_capacity(2 + self.address_width.bytes());
+        cmd.push(CMD_READ_MEMORY);
+        cmd.push(MEMTYPE_FUSES);
+        cmd.extend_from_slice(&self.address_to_bytes(0));
+        let mut buffer = [0u8; 4];
+
+        if self.i2c.write_then_read_once(&cmd, &mut buffer).is_err() || buffer == [0xFF; 4] {
+            return Ok(None);
+        }
+
+        Ok(Some(Fuses {
+            low: buffer[0],
+            high: buffer[1],
+            extended: buffer[2],
+            lock: buffer[3],
+        }))
+    }
+
+    /// Read `len` bytes of EEPROM starting at `offset`
+    pub fn read_eeprom(&mut self, offset: u32, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        for (i, chunk) in data.chunks_mut(self.read_block_size).enumerate() {
+            let addr = offset + (i * self.read_block_size) as u32;
+            self.read_memory(MEMTYPE_EEPROM, addr, chunk)
+                .with_context(|| format!("Failed to read EEPROM at 0x{:04X}", addr))?;
+        }
+
+        Ok(data)
+    }
+
+    /// Write `data` to EEPROM starting at `offset`.
+    ///
+    /// twiboot programs EEPROM byte by byte while receiving, so there's no page
+    /// alignment. Writes are split into small chunks with a delay long enough
+    /// for every byte in them (about 3.4ms each on AVR).
+    pub fn write_eeprom(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        if let Some(ChipInfo::Avr { eeprom_size, .. }) = self.chip_info {
+            let end = offset as usize + data.len();
+            if end > eeprom_size as usize {
+                return Err(anyhow::anyhow!(
+                    "EEPROM data up to 0x{:04X} exceeds the EEPROM size of {} bytes",
+                    end,
+                    eeprom_size
+                ));
+            }
+        }
+
+        for (i, chunk) in data.chunks(EEPROM_WRITE_CHUNK).enumerate() {
+            let addr = offset + (i * EEPROM_WRITE_CHUNK) as u32;
+            let mut cmd = vec![CMD_WRITE_MEMORY, MEMTYPE_EEPROM];
+            cmd.extend_from_slice(&self.address_to_bytes(addr));
+            cmd.extend_from_slice(chunk);
+
+            self.i2c
+                .write_large_data(&cmd)
+                .with_context(|| format!("Failed to write EEPROM at 0x{:04X}", addr))?;
+            thread::sleep(EEPROM_BYTE_WRITE_TIME * chunk.len() as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Where the device is after the last operation, see `FlashReport::final_mode`
+    pub fn mode(&self) -> DeviceMode {
+        match (self.connected, self.application_started) {
+            (_, true) => DeviceMode::Application,
+            (true, false) => DeviceMode::Bootloader,
+            (false, false) => DeviceMode::Unknown,
+        }
+    }
+
+    /// Connect, write `data`, verify it unless `verify` is off and start the
+    /// application, the whole default flow in one call. On failure the error is
+    /// a `FlashError` carrying the report up to the failed step.
+    pub fn flash(&mut self, data: &[u8], wait: bool, verify: bool) -> Result<FlashReport> {
+        let start = Instant::now();
+        let retries_before = self.i2c.retries();
+        let losses_before = self.i2c.arbitration_losses();
+        let mut report = FlashReport::default();
+
+        let result = self.flash_steps(data, wait, verify, &mut report);
+        if result.is_err() {
+            self.release();
+        }
+        report.retries = self.i2c.retries() - retries_before;
+        report.arbitration_losses = self.i2c.arbitration_losses() - losses_before;
+        report.elapsed = start.elapsed();
+        report.final_mode = self.mode();
+
+        match result {
+            Ok(()) => Ok(report),
+            Err(error) => Err(FlashError { report, error }.into()),
+        }
+    }
+
+    fn flash_steps(
+        &mut self,
+        data: &[u8],
+        wait: bool,
+        verify: bool,
+        report: &mut FlashReport,
+    ) -> Result<()> {
+        self.connect(wait)?;
+        report.connected = true;
+        report.bytes_written = self.write_flash(data)?.bytes_written;
+        if verify {
+            self.verify_flash(data)?;
+            report.verified = true;
+        } else {
+            self.accept_unverified();
+        }
+        self.disconnect()
+    }
+
+    /// Best effort application start for a run that ends early, what dropping
+    /// the bootloader does (see `set_switch_on_drop()`). Errors are ignored.
+    pub fn release(&mut self) {
+        if self.switch_on_drop
+            && self.connected
+            && !self.application_started
+            && !self.write_incomplete
+            && !self.image_untrusted
+            && self.switch_application(BOOTTYPE_APPLICATION).is_ok()
+        {
+            self.application_started = true;
+        }
+    }
+
+    /// Take what was written as good without verifying it, for runs with
+    /// verification turned off. Otherwise only a successful verify (or a write
+    /// with interleaved verify) lets `release()` start the application.
+    pub fn accept_unverified(&mut self) {
+        self.image_untrusted = false;
+    }
+
+    /// Fuses read during `connect()`, if the bootloader supports it
+    pub fn fuses(&self) -> Option<Fuses> {
+        self.fuses
+    }
+
     fn parse_chipinfo(&mut self, chipinfo: &[u8; 12]) -> Result<()> {
         match self.address_width {
             AddressWidth::Bits16 => {
                 // AVR legacy format: 8-byte chipinfo (byte 3 is pagesize)
                 self.pagesize = chipinfo[3] as u32;
                 self.flashsize = ((chipinfo[4] as u32) << 8) | (chipinfo[5] as u32);
-                println!(
+                self.chip_info = Some(ChipInfo::Avr {
+                    signature: [chipinfo[0], chipinfo[1], chipinfo[2]],
+                    page_size: chipinfo[3],
+                    boot_start: u16::from_be_bytes([chipinfo[4], chipinfo[5]]),
+                    eeprom_size: u16::from_be_bytes([chipinfo[6], chipinfo[7]]),
+                });
+                self.say(format_args!(
                     "Chip signature: 0x{:02X} 0x{:02X} 0x{:02X}",
                     chipinfo[0], chipinfo[1], chipinfo[2]
-                );
+                ));
+                self.part = parts::lookup(&chipinfo[..3]);
+                if let Some(part) = self.part {
+                    self.say(format_args!("Part: {}", part.name));
+                    self.check_part(part);
+                }
             }
             AddressWidth::Bits32 => {
                 // v4 CH32V format: 4-byte chip ID, 4-byte flash size, 4-byte data
@@ -186,22 +1262,155 @@ impl TwiBootloader {
                     | ((chipinfo[5] as u32) << 16)
                     | ((chipinfo[6] as u32) << 8)
                     | (chipinfo[7] as u32);
+                self.chip_info = Some(ChipInfo::Ch32v {
+                    chip_id,
+                    flash_size: self.flashsize,
+                    page_size: u16::from_be_bytes([chipinfo[8], chipinfo[9]]),
+                });
+
+                self.say(format_args!("Chip signature: 0x{:08X}", chip_id));
+            }
+        }
+
+        match self.radix {
+            radix @ Radix::Mixed => self.say(format_args!(
+                "Flash size: {} bytes [{}KB] ({} bytes/page)",
+                radix.format(self.flashsize, 8),
+                self.flashsize / 1024,
+                self.pagesize
+            )),
+            radix => self.say(format_args!(
+                "Flash size: {} bytes ({} bytes/page)",
+                radix.format(self.flashsize, 8),
+                radix.format(self.pagesize, 4)
+            )),
+        }
 
-                println!("Chip signature: 0x{:08X}", chip_id);
+        // The flash size above is really the bootloader start, the part tells the rest
+        if let Some(part) = self.part.filter(|part| part.flash_size >= self.flashsize) {
+            let boot_size = part.flash_size - self.flashsize;
+            match self.radix {
+                radix @ Radix::Mixed => self.say(format_args!(
+                    "Physical flash: {} bytes [{}KB], application {} bytes, bootloader {} bytes",
+                    radix.format(part.flash_size, 8),
+                    part.flash_size / 1024,
+                    self.flashsize,
+                    boot_size
+                )),
+                radix => self.say(format_args!(
+                    "Physical flash: {} bytes, application {} bytes, bootloader {} bytes",
+                    radix.format(part.flash_size, 8),
+                    radix.format(self.flashsize, 8),
+                    radix.format(boot_size, 8)
+                )),
             }
         }
 
-        println!(
-            "Flash size: 0x{:08X} / {} bytes [{}KB] ({} bytes/page)",
-            self.flashsize,
-            self.flashsize,
-            self.flashsize / 1024,
-            self.pagesize
-        );
+        self.check_page_geometry();
+
+        if self.flashsize > self.max_flash_size {
+            return Err(anyhow::anyhow!(
+                "Device reports {} bytes of flash, more than the allowed maximum of {} bytes (chipinfo misread? see --max-flash-size)",
+                self.flashsize,
+                self.max_flash_size
+            ));
+        }
 
         Ok(())
     }
 
+    // Cross-check chipinfo against the datasheet values of the recognized part
+    fn check_part(&mut self, part: &Part) {
+        if self.pagesize != part.pagesize {
+            if self.use_part_defaults {
+                self.warn(format_args!(
+                    "Warning: device reports {} byte pages, using the {} byte pages of the {}",
+                    self.pagesize, part.pagesize, part.name
+                ));
+                self.pagesize = part.pagesize;
+            } else {
+                self.warn(format_args!(
+                    "Warning: device reports {} byte pages, the {} has {} (see --part-defaults)",
+                    self.pagesize, part.name, part.pagesize
+                ));
+            }
+        }
+
+        if self.flashsize > part.flash_size {
+            self.warn(format_args!(
+                "Warning: bootloader start 0x{:04X} lies beyond the {} bytes of flash of the {}",
+                self.flashsize, part.flash_size, part.name
+            ));
+        }
+
+        let part_delay = Duration::from_millis(part.write_delay_ms);
+        if self.use_part_defaults && self.effective_write_delay < part_delay {
+            self.effective_write_delay = part_delay;
+        }
+    }
+
+    // Pages are always a power of two and the bootloader starts on a page
+    // boundary, anything else means a misread chipinfo that would misalign writes
+    fn check_page_geometry(&self) {
+        let pagesize = self.pagesize;
+        if !pagesize.is_power_of_two() {
+            self.warn(format_args!(
+                "Warning: device reports a page size of {} bytes, which is not a power of two (chipinfo misread?)",
+                pagesize
+            ));
+        } else if pagesize > self.flashsize || !self.flashsize.is_multiple_of(pagesize) {
+            self.warn(format_args!(
+                "Warning: flash size of {} bytes is not a multiple of the {} byte page size (chipinfo misread?)",
+                self.flashsize, pagesize
+            ));
+        }
+    }
+
+    /// Chipinfo fields as reported, set by `connect()`. `flash_size()` and
+    /// `page_size()` give the values in effect after fallbacks and overrides.
+    pub fn chip_info(&self) -> Option<ChipInfo> {
+        self.chip_info
+    }
+
+    /// Physical flash of the recognized part, bootloader included. `flash_size()`
+    /// is only the application region below the bootloader.
+    pub fn physical_flash_size(&self) -> Option<u32> {
+        self.part.map(|part| part.flash_size)
+    }
+
+    /// Known part matching the chip signature, set by `connect()` (AVR only)
+    pub fn part(&self) -> Option<&'static Part> {
+        self.part
+    }
+
+    fn apply_write_page_size(&mut self) -> Result<()> {
+        let Some(size) = self.write_page_size else {
+            return Ok(());
+        };
+        if size == self.pagesize {
+            return Ok(());
+        }
+
+        let related = size
+            .max(self.pagesize)
+            .is_multiple_of(size.min(self.pagesize));
+        if !size.is_power_of_two() || !related || !self.flashsize.is_multiple_of(size) {
+            return Err(anyhow::anyhow!(
+                "Write page size {} doesn't fit the device (page size {}, flash size {})",
+                size,
+                self.pagesize,
+                self.flashsize
+            ));
+        }
+
+        self.say(format_args!(
+            "Writing with {} byte pages instead of the reported {}",
+            size, self.pagesize
+        ));
+        self.pagesize = size;
+        Ok(())
+    }
+
     fn set_address_width_from_version(&mut self, version: &str) {
         // Expect version string like "TWIBOOT v4.0" or "TWIBOOT v3.2"
         if let Some(vpos) = version.find('v') {
@@ -227,49 +1436,735 @@ impl TwiBootloader {
         self.flashsize
     }
 
-    pub fn write_flash(&mut self, data: &[u8]) -> Result<()> {
+    pub fn page_size(&self) -> u32 {
+        self.pagesize
+    }
+
+    /// Read `len` bytes of flash starting at `start`.
+    ///
+    /// Nothing stops you from reading past the bootloader start, but whether the
+    /// bootloader region reads back real content depends on the device: some
+    /// variants protect it (lock bits, read protection) and return 0xFF or NAK.
+    pub fn read_flash(&mut self, start: u32, len: usize) -> Result<Vec<u8>> {
+        let end = start as u64 + len as u64;
+        if end > self.max_flash_size as u64 {
+            return Err(anyhow::anyhow!(
+                "Read up to 0x{:08X} exceeds the maximum flash size of {} bytes",
+                end,
+                self.max_flash_size
+            ));
+        }
+
+        let mut data = vec![0u8; len];
+        for (i, chunk) in data.chunks_mut(self.read_block_size).enumerate() {
+            let addr = start + (i * self.read_block_size) as u32;
+            self.read_memory(MEMTYPE_FLASH, addr, chunk)
+                .with_context(|| format!("Failed to read flash at 0x{:08X}", addr))?;
+        }
+
+        Ok(data)
+    }
+
+    /// Read flash page number `page_index` in a single transaction
+    pub fn read_page(&mut self, page_index: u16) -> Result<Vec<u8>> {
+        self.check_connected()?;
+        let addr = self.page_address(page_index)?;
+        let mut data = vec![0u8; self.pagesize as usize];
+        self.read_memory(MEMTYPE_FLASH, addr, &mut data)
+            .with_context(|| format!("Failed to read page {} at 0x{:08X}", page_index, addr))?;
+
+        Ok(data)
+    }
+
+    /// Write flash page number `page_index`. `data` may be shorter than a page,
+    /// the rest is filled with 0xFF like the last page of `write_flash()`
+    /// (unless `set_no_pad()` or `set_no_final_pad()` is on).
+    pub fn write_page(&mut self, page_index: u16, data: &[u8]) -> Result<()> {
+        self.check_connected()?;
+        let addr = self.page_address(page_index)?;
+        self.check_padding(data.len())?;
+        if data.len() > self.pagesize as usize {
+            return Err(anyhow::anyhow!(
+                "{} bytes don't fit into a {} byte page",
+                data.len(),
+                self.pagesize
+            ));
+        }
+
+        self.write_page_checked(addr, data)?;
+        self.write_incomplete = false;
+        Ok(())
+    }
+
+    // Page geometry only exists after connect(), everything page based needs it
+    fn check_connected(&self) -> Result<()> {
+        if self.pagesize == 0 {
+            return Err(anyhow::anyhow!(
+                "Not connected: connect() has to read the page size first"
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Start address of an application page, pages from the bootloader start up are refused
+    fn page_address(&self, page_index: u16) -> Result<u32> {
+        let addr = page_index as u32 * self.pagesize;
+        if addr + self.pagesize > self.flashsize {
+            return Err(anyhow::anyhow!(
+                "Page {} at 0x{:08X} lies outside the application flash (0x{:08X} bytes)",
+                page_index,
+                addr,
+                self.flashsize
+            ));
+        }
+
+        Ok(addr)
+    }
+
+    pub fn write_flash(&mut self, data: &[u8]) -> Result<WriteSummary> {
+        self.write_flash_with_progress(data, |_, _| {})
+    }
+
+    /// `write_flash()` reporting `(bytes done, bytes total)` after every page,
+    /// so frontends can render their own progress display.
+    pub fn write_flash_with_progress(
+        &mut self,
+        data: &[u8],
+        progress: impl FnMut(usize, usize),
+    ) -> Result<WriteSummary> {
+        let whole = 0..data.len();
+        self.write_flash_ranges_with_progress(data, std::slice::from_ref(&whole), progress)
+    }
+
+    /// Like `write_flash()`, but only writes the pages touching the given
+    /// address ranges of `data`. Pages that lie completely in a gap are skipped.
+    pub fn write_flash_ranges(
+        &mut self,
+        data: &[u8],
+        ranges: &[Range<usize>],
+    ) -> Result<WriteSummary> {
+        self.write_flash_ranges_with_progress(data, ranges, |_, _| {})
+    }
+
+    /// Write `bytes` at `start` and leave the rest of the flash as it is.
+    ///
+    /// Pages are always written whole, so the pages around the bytes are read
+    /// first and written back unchanged apart from the patched bytes. The bytes
+    /// have to end below the bootloader start.
+    pub fn patch_flash(&mut self, start: u32, bytes: &[u8]) -> Result<WriteSummary> {
+        self.check_connected()?;
+        let flash_size = self.flashsize as usize;
+        let start = start as usize;
+        let end = start + bytes.len();
+        self.check_app_region(start..end)?;
+
+        let pagesize = self.pagesize as usize;
+        let page_start = start / pagesize * pagesize;
+        let page_end = end.next_multiple_of(pagesize).min(flash_size);
+
+        let mut data = vec![0xFF; page_start];
+        data.extend(self.read_flash(page_start as u32, page_end - page_start)?);
+        data[start..end].copy_from_slice(bytes);
+
+        let pages = page_start..page_end;
+        self.write_flash_ranges(&data, std::slice::from_ref(&pages))
+    }
+
+    /// Fail unless the flash addresses `range` end below the bootloader start
+    pub fn check_app_region(&self, range: Range<usize>) -> Result<()> {
+        let flash_size = self.flashsize as usize;
+        if range.end > flash_size {
+            return Err(anyhow::anyhow!(
+                "Data at 0x{:04X}..0x{:04X} exceeds available flash space (limit: 0x{:04X}).",
+                range.start,
+                range.end,
+                flash_size
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// `write_flash_ranges()` with the progress callback of `write_flash_with_progress()`
+    pub fn write_flash_ranges_with_progress(
+        &mut self,
+        data: &[u8],
+        ranges: &[Range<usize>],
+        progress: impl FnMut(usize, usize),
+    ) -> Result<WriteSummary> {
+        self.check_connected()?;
+        self.write_pages(data, ranges, progress)
+    }
+
+    #[cfg_attr(feature = "trace", tracing::instrument(name = "write_flash", skip_all, fields(address = self.i2c.address(), bytes = data.len()), err))]
+    fn write_pages(
+        &mut self,
+        data: &[u8],
+        ranges: &[Range<usize>],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<WriteSummary> {
+        self.check_padding(data.len())?;
+        if data.len() > self.max_flash_size as usize {
+            return Err(anyhow::anyhow!(
+                "Image of {} bytes exceeds the maximum flash size of {} bytes",
+                data.len(),
+                self.max_flash_size
+            ));
+        }
+
+        let start = Instant::now();
+        let retries_before = self.i2c.retries();
+        let mut pages_written = 0;
+        let mut bytes_written = 0;
+
+        let pagesize = self.pagesize as usize;
+        let mut pages: Vec<usize> = ranges
+            .iter()
+            .filter(|range| !range.is_empty())
+            .flat_map(|range| {
+                let end = range.end.min(data.len());
+                (range.start / pagesize * pagesize..end).step_by(pagesize)
+            })
+            .collect();
+        pages.sort_unstable();
+        pages.dedup();
+
+        let mut padding_bytes = 0;
+        let mut pages_skipped = 0;
+        if self.skip_unchanged {
+            let mut changed = Vec::with_capacity(pages.len());
+            for pos in pages {
+                if self.page_matches(pos as u32, &data[pos..(pos + pagesize).min(data.len())])? {
+                    pages_skipped += 1;
+                } else {
+                    changed.push(pos);
+                }
+            }
+            pages = changed;
+        }
+        let mut written_pages: Vec<u32> = pages.iter().map(|&pos| pos as u32).collect();
+        written_pages.sort_unstable();
+
+        if self.reset_vector_last && pages.first() == Some(&0) {
+            // Erase page 0 first: if we get interrupted, the device finds no reset
+            // vector and stays in the bootloader instead of running a half-written app
+            // Not counted, page 0 is counted once it holds its data
+            self.write_page_at(0, &[])?;
+            pages.rotate_left(1);
+        }
+
+        let total = pages
+            .iter()
+            .map(|&pos| (data.len() - pos).min(pagesize))
+            .sum();
+        progress(0, total);
+
+        for pos in pages {
+            let len = (data.len() - pos).min(pagesize);
+
+            let page_retries_before = self.i2c.retries();
+            self.write_page_checked(pos as u32, &data[pos..pos + len])?;
+            trace_event!(
+                "page written",
+                page = pos / pagesize,
+                addr = pos,
+                retries = self.i2c.retries() - page_retries_before,
+            );
+            pages_written += 1;
+            bytes_written += len;
+            // See write_page_at(), a short last page goes out as is without final padding
+            if !self.no_final_pad {
+                padding_bytes += pagesize - len;
+            }
+            progress(bytes_written, total);
+        }
+        self.write_incomplete = false;
+        // Every page was read back right after writing it (page delay scaling
+        // gives up on a page without an error, so it doesn't count)
+        if self.interleave_verify && !self.adaptive_write_delay {
+            self.image_untrusted = false;
+        }
+
+        Ok(WriteSummary {
+            bytes_written,
+            pages_written,
+            padding_bytes,
+            pages_skipped,
+            written_pages,
+            retries: self.i2c.retries() - retries_before,
+            elapsed: start.elapsed(),
+            write_delay: self.effective_write_delay,
+        })
+    }
+
+    /// Erase the whole application region (everything below the bootloader).
+    ///
+    /// Twiboot has no erase command: every page write erases the page itself
+    /// before programming it, so this isn't needed for normal flashing. It writes
+    /// erased (0xFF) pages up to the bootloader start, which clears leftovers of
+    /// a previous larger application and works on every variant.
+    pub fn erase_flash(&mut self) -> Result<WriteSummary> {
+        self.check_connected()?;
+        let start = Instant::now();
+        let retries_before = self.i2c.retries();
+        let mut pages_written = 0;
+
         let mut pos = 0;
+        while pos + self.pagesize <= self.flashsize {
+            self.write_page_at(pos, &[])
+                .with_context(|| format!("Failed to erase page at 0x{:08X}", pos))?;
+            pages_written += 1;
+            pos += self.pagesize;
+        }
+        self.write_incomplete = false;
+
+        Ok(WriteSummary {
+            bytes_written: pos as usize,
+            pages_written,
+            retries: self.i2c.retries() - retries_before,
+            elapsed: start.elapsed(),
+            write_delay: self.effective_write_delay,
+            ..Default::default()
+        })
+    }
 
+    /// `write_flash()` that can pick up where an interrupted run stopped.
+    ///
+    /// After every page the next address to write is recorded in `state_path`
+    /// together with the image's length and CRC-32. If a matching state file
+    /// exists, writing resumes at that (page aligned) address; the last page
+    /// before it is read back first and rewritten if it doesn't match. The state
+    /// file is removed once the whole image is written.
+    pub fn write_flash_resumable(
+        &mut self,
+        data: &[u8],
+        state_path: &Path,
+    ) -> Result<WriteSummary> {
+        self.check_connected()?;
+        if self.reset_vector_last {
+            return Err(anyhow::anyhow!(
+                "Resumable writes can't be combined with reset-vector-last"
+            ));
+        }
+        self.check_padding(data.len())?;
+        if data.len() > self.max_flash_size as usize {
+            return Err(anyhow::anyhow!(
+                "Image of {} bytes exceeds the maximum flash size of {} bytes",
+                data.len(),
+                self.max_flash_size
+            ));
+        }
+
+        let start = Instant::now();
+        let retries_before = self.i2c.retries();
+        let mut pages_written = 0;
+
+        let pagesize = self.pagesize as usize;
+        let checksum = crc32(data);
+
+        let resume_pos = self.resume_position(data, state_path, checksum)?;
+        if resume_pos > 0 {
+            self.say(format_args!("Resuming at address 0x{:08X}", resume_pos));
+        }
+
+        let mut written_pages = Vec::new();
+        let mut pos = resume_pos;
         while pos < data.len() {
-            let remaining = data.len() - pos;
-            let len = remaining.min(self.pagesize as usize);
+            let len = (data.len() - pos).min(pagesize);
 
-            // The bootloader expects exactly one full page in a single I2C transaction
-            let addr_bytes = self.address_to_bytes(pos as u32);
-            let mut cmd = Vec::with_capacity(2 + addr_bytes.len() + self.pagesize as usize);
-            cmd.push(CMD_WRITE_MEMORY);
-            cmd.push(MEMTYPE_FLASH);
-            cmd.extend_from_slice(&addr_bytes);
+            self.write_page_checked(pos as u32, &data[pos..pos + len])?;
+            pages_written += 1;
+            written_pages.push(pos as u32);
+            pos += len;
+
+            fs::write(
+                state_path,
+                format!(
+                    "crc32={:08X} length={} next={}\n",
+                    checksum,
+                    data.len(),
+                    pos
+                ),
+            )
+            .with_context(|| format!("Failed to write resume state: {}", state_path.display()))?;
+        }
+
+        self.write_incomplete = false;
+
+        match fs::remove_file(state_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).context("Failed to remove resume state");
+            }
+            _ => {}
+        }
+
+        Ok(WriteSummary {
+            bytes_written: data.len() - resume_pos,
+            pages_written,
+            written_pages,
+            retries: self.i2c.retries() - retries_before,
+            elapsed: start.elapsed(),
+            write_delay: self.effective_write_delay,
+            ..Default::default()
+        })
+    }
+
+    fn resume_position(&mut self, data: &[u8], state_path: &Path, checksum: u32) -> Result<usize> {
+        let Ok(state) = fs::read_to_string(state_path) else {
+            return Ok(0);
+        };
+
+        let field = |name: &str| {
+            state
+                .split_whitespace()
+                .find_map(|kv| kv.strip_prefix(name)?.strip_prefix('='))
+                .map(str::to_string)
+        };
+        let state_crc = field("crc32").and_then(|v| u32::from_str_radix(&v, 16).ok());
+        let state_len = field("length").and_then(|v| v.parse::<usize>().ok());
+        let state_next = field("next").and_then(|v| v.parse::<usize>().ok());
+
+        let (Some(state_crc), Some(state_len), Some(next)) = (state_crc, state_len, state_next)
+        else {
+            self.say(format_args!(
+                "Ignoring unreadable resume state, starting from scratch"
+            ));
+            return Ok(0);
+        };
+        if state_crc != checksum || state_len != data.len() {
+            self.say(format_args!(
+                "Resume state belongs to a different image, starting from scratch"
+            ));
+            return Ok(0);
+        }
+
+        let pagesize = self.pagesize as usize;
+        let next = (next.min(data.len()) / pagesize) * pagesize;
+        if next == 0 {
+            return Ok(0);
+        }
+
+        // The interruption may have hit while the boundary page was programmed
+        let boundary = next - pagesize;
+        let mut buffer = vec![0u8; pagesize];
+        self.read_memory(MEMTYPE_FLASH, boundary as u32, &mut buffer)
+            .context("Failed to read back the last written page")?;
+
+        if buffer[..] != data[boundary..next] {
+            self.say(format_args!(
+                "Page at 0x{:08X} is incomplete, rewriting it",
+                boundary
+            ));
+            return Ok(boundary);
+        }
+
+        Ok(next)
+    }
+
+    fn write_page_at(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        // Cleared by the write functions once everything they were asked for is written
+        self.write_incomplete = true;
+        self.image_untrusted = true;
+
+        // The bootloader expects exactly one full page in a single I2C transaction
+        let addr_bytes = self.address_to_bytes(addr);
+        let mut cmd = Vec::with_capacity(2 + addr_bytes.len() + self.pagesize as usize);
+        cmd.push(CMD_WRITE_MEMORY);
+        cmd.push(MEMTYPE_FLASH);
+        cmd.extend_from_slice(&addr_bytes);
 
-            // Add actual data
-            cmd.extend_from_slice(&data[pos..pos + len]);
+        // Add actual data
+        cmd.extend_from_slice(data);
 
-            // Pad with 0xFF to reach exactly pagesize bytes
+        // Pad with the fill byte to reach exactly pagesize bytes. Empty data is an
+        // erased page, that one is always sent in full.
+        if !self.no_final_pad || data.is_empty() {
             let overhead = 2 + addr_bytes.len();
-            cmd.resize(overhead + self.pagesize as usize, 0xFF);
+            cmd.resize(overhead + self.pagesize as usize, self.flash_fill);
+        }
 
-            self.i2c
-                .write_large_data(&cmd)
-                .context("Failed to write flash page")?;
+        self.i2c
+            .write_large_data(&cmd)
+            .context("Failed to write flash page")?;
 
-            // Wait for flash programming to complete
-            thread::sleep(Duration::from_millis(5));
+        // Wait for flash programming to complete
+        thread::sleep(self.effective_write_delay);
 
-            pos += len; // Advance by actual data length, not page size
+        if self.write_status {
+            self.check_write_status(addr)?;
         }
 
         Ok(())
     }
 
-    pub fn verify_flash(&mut self, expected_data: &[u8]) -> Result<()> {
+    // Status byte the device answers after a page write, anything but 0x00 is its error code
+    fn check_write_status(&mut self, addr: u32) -> Result<()> {
+        let mut status = [0u8; 1];
+        self.i2c
+            .read(&mut status)
+            .with_context(|| format!("Failed to read write status of page at 0x{:08X}", addr))?;
+
+        if status[0] != WRITE_STATUS_OK {
+            return Err(anyhow::anyhow!(
+                "Device reported error 0x{:02X} writing page at 0x{:08X}",
+                status[0],
+                addr
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Whether the page at `addr` already holds `data`, padded like write_page_at() does
+    fn page_matches(&mut self, addr: u32, data: &[u8]) -> Result<bool> {
+        let mut current = vec![0u8; self.pagesize as usize];
+        self.read_memory(MEMTYPE_FLASH, addr, &mut current)
+            .with_context(|| format!("Failed to read page at 0x{:08X}", addr))?;
+
+        let (head, padding) = current.split_at(data.len());
+        Ok(head == data
+            && (self.ignores_page_tail() || padding.iter().all(|&b| b == self.flash_fill)))
+    }
+
+    // Whether the bytes of a short page past the data are left out of page compares:
+    // with --lenient-erased they may read anything, without final padding they aren't written
+    fn ignores_page_tail(&self) -> bool {
+        self.lenient_erased || self.no_final_pad
+    }
+
+    // Read the page at `addr` back and fail with the first differing byte
+    fn check_page(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let mut current = vec![0u8; self.pagesize as usize];
+        self.read_memory(MEMTYPE_FLASH, addr, &mut current)
+            .with_context(|| format!("Failed to read back page at 0x{:08X}", addr))?;
+
+        if self.ignores_page_tail() {
+            current.truncate(data.len());
+        }
+
+        let expected = data
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(self.flash_fill));
+        match current.iter().zip(expected).position(|(&a, b)| a != b) {
+            None => Ok(()),
+            Some(i) => Err(anyhow::anyhow!(
+                "Page {} at 0x{:08X} failed verification after rewrite: 0x{:08X} reads 0x{:02X}, expected 0x{:02X}",
+                addr / self.pagesize,
+                addr,
+                addr as usize + i,
+                current[i],
+                data.get(i).copied().unwrap_or(self.flash_fill)
+            )),
+        }
+    }
+
+    // write_page_at() plus read back when page delay scaling or interleaved verify is enabled
+    fn write_page_checked(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        if !self.adaptive_write_delay {
+            self.write_page_at(addr, data)?;
+            if self.interleave_verify && !self.page_matches(addr, data)? {
+                // One rewrite for a page that didn't take, then give up before touching the next
+                self.write_page_at(addr, data)?;
+                self.check_page(addr, data)?;
+            }
+            return Ok(());
+        }
+
+        let mut readback = vec![0u8; data.len()];
+        for _ in 0..ADAPTIVE_MAX_ATTEMPTS {
+            self.write_page_at(addr, data)?;
+            self.read_memory(MEMTYPE_FLASH, addr, &mut readback)
+                .context("Failed to read back flash page")?;
+
+            if readback == data {
+                self.adaptive_streak += 1;
+                if self.adaptive_streak >= ADAPTIVE_DECREASE_AFTER {
+                    self.adaptive_streak = 0;
+                    self.effective_write_delay = self
+                        .effective_write_delay
+                        .saturating_sub(Duration::from_millis(1))
+                        .max(Duration::from_millis(ADAPTIVE_MIN_DELAY_MS));
+                }
+                return Ok(());
+            }
+
+            self.adaptive_streak = 0;
+            self.effective_write_delay = (self.effective_write_delay * 2).clamp(
+                Duration::from_millis(ADAPTIVE_MIN_DELAY_MS),
+                Duration::from_millis(ADAPTIVE_MAX_DELAY_MS),
+            );
+            self.say(format_args!(
+                "Page at 0x{:08X} didn't read back correctly, write delay raised to {}ms",
+                addr,
+                self.effective_write_delay.as_millis()
+            ));
+        }
+
+        Err(anyhow::anyhow!(
+            "Page at 0x{:08X} still wrong after {} attempts (write delay {}ms)",
+            addr,
+            ADAPTIVE_MAX_ATTEMPTS,
+            self.effective_write_delay.as_millis()
+        ))
+    }
+
+    /// Like `verify_flash()`, but compares a CRC-32 per page computed by the
+    /// device instead of reading the pages back, 4 bytes per page on the bus.
+    ///
+    /// Needs a bootloader that answers page checksum reads (memtype 0x08), which
+    /// stock twiboot doesn't; nothing else sends that memtype. If the first one
+    /// fails or reads as open bus, this falls back to `verify_flash()`. Pages are compared including the 0xFF padding the
+    /// writer adds; only a page whose checksum differs is read back in full to
+    /// find the differing bytes.
+    #[cfg_attr(feature = "trace", tracing::instrument(name = "verify_flash_pagesum", skip_all, fields(address = self.i2c.address(), bytes = expected_data.len()), err))]
+    pub fn verify_flash_pagesum(&mut self, expected_data: &[u8]) -> Result<VerifySummary> {
+        self.check_connected()?;
+        let start = Instant::now();
+
+        self.switch_application(BOOTTYPE_BOOTLOADER)?;
+        thread::sleep(self.reentry_delay);
+
+        let pagesize = self.pagesize as usize;
+        let mut cmd = vec![CMD_READ_MEMORY, MEMTYPE_PAGESUM];
+        cmd.extend_from_slice(&self.address_to_bytes(0));
+        let mut sum = [0u8; 4];
+        if self.i2c.write_then_read_once(&cmd, &mut sum).is_err() || sum == [0xFF; 4] {
+            self.say(format_args!(
+                "Device has no page checksums, verifying by read back"
+            ));
+            return self.verify_flash(expected_data);
+        }
+
+        let mut details: Vec<Mismatch> = Vec::new();
+        for (i, chunk) in expected_data.chunks(pagesize).enumerate() {
+            let addr = (i * pagesize) as u32;
+            let mut page = chunk.to_vec();
+            page.resize(pagesize, self.flash_fill);
+
+            if i > 0 {
+                self.read_memory(MEMTYPE_PAGESUM, addr, &mut sum)
+                    .with_context(|| format!("Failed to read page checksum at 0x{:08X}", addr))?;
+            }
+            if u32::from_be_bytes(sum) == crc32(&page) {
+                continue;
+            }
+
+            let mut current = vec![0u8; pagesize];
+            self.read_memory(MEMTYPE_FLASH, addr, &mut current)
+                .with_context(|| format!("Failed to read page at 0x{:08X}", addr))?;
+            details.extend(
+                current
+                    .iter()
+                    .zip(&page)
+                    .enumerate()
+                    .take(if self.ignores_page_tail() {
+                        chunk.len()
+                    } else {
+                        pagesize
+                    })
+                    .filter(|(_, (actual, expected))| actual != expected)
+                    .map(|(j, (&actual, &expected))| Mismatch {
+                        addr: addr + j as u32,
+                        expected,
+                        actual,
+                    }),
+            );
+
+            if !details.is_empty() && !self.verify_report_all {
+                break;
+            }
+        }
+
+        let summary = VerifySummary {
+            bytes_verified: expected_data.len(),
+            mismatches: details.len(),
+            details,
+            elapsed: start.elapsed(),
+        };
+
+        if let Some(first) = summary.details.first() {
+            return Err(VerifyError {
+                first_mismatch: first.addr,
+                summary,
+            }
+            .into());
+        }
+
+        self.image_untrusted = false;
+        Ok(summary)
+    }
+
+    /// Read back only the reset vector (the first 4 bytes) and compare it with
+    /// `data`. The cheapest check that the device will boot what was written.
+    pub fn check_reset_vector(&mut self, data: &[u8]) -> Result<()> {
+        let expected = &data[..data.len().min(4)];
+        let actual = self.read_flash(0, expected.len())?;
+        if actual != expected {
+            return Err(anyhow::anyhow!(
+                "Reset vector check failed: expected {:02X?}, read {:02X?}",
+                expected,
+                actual
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn verify_flash(&mut self, expected_data: &[u8]) -> Result<VerifySummary> {
+        let whole = 0..expected_data.len();
+        self.verify_flash_ranges(expected_data, std::slice::from_ref(&whole))
+    }
+
+    /// Like `verify_flash()`, but only compares the given address ranges of
+    /// `expected_data`, e.g. to skip fill bytes between HEX records.
+    pub fn verify_flash_ranges(
+        &mut self,
+        expected_data: &[u8],
+        ranges: &[Range<usize>],
+    ) -> Result<VerifySummary> {
+        self.check_connected()?;
+        let summary = self.verify_blocks(expected_data, ranges)?;
+        self.image_untrusted = false;
+        Ok(summary)
+    }
+
+    #[cfg_attr(feature = "trace", tracing::instrument(name = "verify_flash", skip_all, fields(address = self.i2c.address(), bytes = expected_data.len()), err))]
+    fn verify_blocks(
+        &mut self,
+        expected_data: &[u8],
+        ranges: &[Range<usize>],
+    ) -> Result<VerifySummary> {
+        let start = Instant::now();
+
         // Ensure we're still in bootloader mode before verification
         self.switch_application(BOOTTYPE_BOOTLOADER)?;
-        thread::sleep(Duration::from_millis(50));
+        thread::sleep(self.reentry_delay);
 
-        let mut pos = 0;
+        let mut blocks = Vec::new();
+        for range in ranges {
+            let mut pos = range.start;
+            while pos < range.end {
+                let block = if self.verify_matches_write {
+                    let pagesize = self.pagesize as usize;
+                    pagesize - pos % pagesize
+                } else {
+                    self.read_block_size
+                };
+                let len = block.min(range.end - pos);
+                blocks.push((pos, len));
+                pos += len;
+            }
+        }
+        if self.verify_order == VerifyOrder::Descending {
+            blocks.reverse();
+        }
+
+        let mut bytes_verified = 0;
+        let mut details: Vec<Mismatch> = Vec::new();
 
-        while pos < expected_data.len() {
-            let len = READ_BLOCK_SIZE.min(expected_data.len() - pos);
+        for (pos, len) in blocks {
             let mut buffer = vec![0u8; len];
 
             let mut cmd = Vec::with_capacity(2 + self.address_width.bytes());
@@ -277,29 +2172,90 @@ impl TwiBootloader {
             cmd.push(MEMTYPE_FLASH);
             cmd.extend_from_slice(&self.address_to_bytes(pos as u32));
 
-            // Try to read, if it fails, the device might have switched modes
-            match self.i2c.write_then_read(&cmd, &mut buffer) {
+            // Try to read (the transport already retried timeouts), then repeat the
+            // whole transaction a few times. Only if it still fails the device might
+            // have switched modes.
+            let retries_before = self.i2c.retries();
+            let mut read_retries = 0;
+            let mut result = self.i2c.write_then_read(&cmd, &mut buffer);
+            for _ in 0..self.verify_read_retries {
+                if result.is_ok() || result.as_ref().is_err_and(retry_is_pointless) {
+                    break;
+                }
+                thread::sleep(self.verify_read_retry_delay);
+                read_retries += 1;
+                result = self.i2c.write_then_read(&cmd, &mut buffer);
+            }
+            match result {
                 Ok(_) => {}
+                Err(e) if retry_is_pointless(&e) => return Err(e),
                 Err(_) => {
                     // Device might have switched to application mode, try to switch back
                     self.switch_application(BOOTTYPE_BOOTLOADER)?;
-                    thread::sleep(Duration::from_millis(100));
+                    thread::sleep(self.switch_to_bootloader_delay);
                     self.i2c.write_then_read(&cmd, &mut buffer).context(
                         "Failed to read flash for verification after bootloader re-entry",
                     )?;
+                    read_retries += 1;
                 }
             }
+            trace_event!(
+                "block read back",
+                addr = pos,
+                len = len,
+                retries = self.i2c.retries() - retries_before + read_retries,
+            );
 
-            if &buffer[..len] != &expected_data[pos..pos + len] {
-                return Err(anyhow::anyhow!(
-                    "Verification failed at address 0x{:08X}",
-                    pos
-                ));
+            let expected = &expected_data[pos..pos + len];
+            let mismatches: Vec<Mismatch> = buffer
+                .iter()
+                .zip(expected)
+                .enumerate()
+                .filter(|(_, (actual, expected))| actual != expected)
+                .map(|(i, (&actual, &expected))| Mismatch {
+                    addr: (pos + i) as u32,
+                    expected,
+                    actual,
+                })
+                .collect();
+
+            bytes_verified += len;
+
+            if mismatches.is_empty() || self.verify_report_all {
+                details.extend(mismatches);
+                continue;
             }
+            // Only the lowest bad block is reported. Descending reads get to it last,
+            // so they go on to the end and keep the one found last.
+            details = mismatches;
+            if self.verify_order == VerifyOrder::Ascending {
+                break;
+            }
+        }
+        details.sort_by_key(|mismatch| mismatch.addr);
 
-            pos += len;
+        let summary = VerifySummary {
+            bytes_verified,
+            mismatches: details.len(),
+            details,
+            elapsed: start.elapsed(),
+        };
+
+        if let Some(first) = summary.details.first() {
+            return Err(VerifyError {
+                first_mismatch: first.addr,
+                summary,
+            }
+            .into());
         }
 
-        Ok(())
+        Ok(summary)
+    }
+}
+
+impl<T: I2CTransport> Drop for TwiBootloader<T> {
+    // A run that bails out with `?` shouldn't strand the device in the bootloader
+    fn drop(&mut self) {
+        self.release();
     }
 }
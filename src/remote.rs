@@ -0,0 +1,125 @@
+//! Client side of the remote I2C agent.
+//!
+//! The agent runs on the host the programmer hardware is attached to and
+//! proxies transactions to its local I2C bus. Every request is a frame
+//! `[op: u8][len: u16 BE][payload]`, every response `[status: u8][len: u16 BE][payload]`.
+//! A status of 0 means success and the payload holds the bytes read (if any),
+//! anything else is a failure with a UTF-8 error message as payload.
+//!
+//! | op  | request payload                    | response payload |
+//! |-----|------------------------------------|------------------|
+//! | `A` | slave address (1 byte)             | -                |
+//! | `W` | data, written with retries         | -                |
+//! | `R` | read length (u16 BE), with retries | data read        |
+//! | `O` | read length (u16 BE) + write data  | data read        |
+//!
+//! `O` is a single write+read attempt without retries (presence checks).
+
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::i2c::I2CTransport;
+
+const OP_ADDRESS: u8 = b'A';
+const OP_WRITE: u8 = b'W';
+const OP_READ: u8 = b'R';
+const OP_WRITE_THEN_READ_ONCE: u8 = b'O';
+
+const STATUS_OK: u8 = 0x00;
+
+// Covers the agent's own retry loops plus network latency
+const IO_TIMEOUT_MS: u64 = 5000;
+
+pub struct TcpI2CDevice {
+    stream: TcpStream,
+    address: u8,
+}
+
+impl TcpI2CDevice {
+    pub fn new(remote: &str, address: u8) -> Result<Self> {
+        let stream = TcpStream::connect(remote)
+            .with_context(|| format!("Failed to connect to remote agent: {}", remote))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS)))?;
+        stream.set_write_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS)))?;
+
+        let mut device = TcpI2CDevice { stream, address };
+        device
+            .request(OP_ADDRESS, &[address])
+            .context("Remote agent rejected the slave address")?;
+
+        Ok(device)
+    }
+
+    fn request(&mut self, op: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let len = u16::try_from(payload.len()).context("Remote request too large")?;
+
+        let mut frame = Vec::with_capacity(3 + payload.len());
+        frame.push(op);
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.stream
+            .write_all(&frame)
+            .context("Failed to send request to remote agent")?;
+
+        let mut header = [0u8; 3];
+        self.stream
+            .read_exact(&mut header)
+            .context("No response from remote agent")?;
+        let mut response = vec![0u8; u16::from_be_bytes([header[1], header[2]]) as usize];
+        self.stream
+            .read_exact(&mut response)
+            .context("Truncated response from remote agent")?;
+
+        if header[0] != STATUS_OK {
+            return Err(anyhow::anyhow!(
+                "Remote I2C error: {}",
+                String::from_utf8_lossy(&response)
+            ));
+        }
+
+        Ok(response)
+    }
+
+    fn read_into(&mut self, op: u8, payload: &[u8], buffer: &mut [u8]) -> Result<()> {
+        let data = self.request(op, payload)?;
+        if data.len() != buffer.len() {
+            return Err(anyhow::anyhow!(
+                "Remote agent returned {} bytes, expected {}",
+                data.len(),
+                buffer.len()
+            ));
+        }
+
+        buffer.copy_from_slice(&data);
+        Ok(())
+    }
+}
+
+impl I2CTransport for TcpI2CDevice {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> Result<()> {
+        self.request(OP_WRITE, data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let len = u16::try_from(buffer.len()).context("Remote read too large")?;
+        self.read_into(OP_READ, &len.to_be_bytes(), buffer)?;
+        Ok(buffer.len())
+    }
+
+    fn write_then_read_once(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<()> {
+        let len = u16::try_from(read_buffer.len()).context("Remote read too large")?;
+
+        let mut payload = Vec::with_capacity(2 + write_data.len());
+        payload.extend_from_slice(&len.to_be_bytes());
+        payload.extend_from_slice(write_data);
+        self.read_into(OP_WRITE_THEN_READ_ONCE, &payload, read_buffer)
+    }
+}
@@ -0,0 +1,236 @@
+//! Minimal HTTP/JSON programming server on top of one bootloader connection.
+//!
+//! Requests are handled one at a time, the device is a single resource anyway.
+//!
+//! | request       | response                                                           |
+//! |---------------|--------------------------------------------------------------------|
+//! | `GET /info`   | `{"version":V,"flash_size":N,"page_size":N,"mode":M,"ok":true}`    |
+//! | `GET /flash`  | the application region as `application/octet-stream`               |
+//! | `POST /flash` | body is a raw binary image, written and verified: `{"bytes_written":N,"pages_written":N,"elapsed_ms":T,"ok":true}` |
+//! | `POST /start` | starts the application: `{"ok":true}`                              |
+//!
+//! Failures answer with a 4xx/5xx status and `{"ok":false,"error":E}`. After
+//! `/start` the next request that needs the bootloader connects again.
+
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::i2c::I2CTransport;
+use crate::json::json_string;
+use crate::protocol::{DeviceMode, TwiBootloader};
+
+// Larger than any twiboot device's flash, keeps a bogus Content-Length from allocating
+const MAX_BODY: usize = 1024 * 1024;
+
+// A client that stops sending mustn't block the device for everyone else
+const IO_TIMEOUT_MS: u64 = 10_000;
+
+/// HTTP response before it's serialized
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(
+            status,
+            format!(r#"{{"ok":false,"error":{}}}"#, json_string(message)),
+        )
+    }
+}
+
+/// Listen on `addr` (e.g. `127.0.0.1:8080`) and serve requests until the process
+/// ends, see `listen()`
+pub fn serve<T: I2CTransport>(
+    bootloader: &mut TwiBootloader<T>,
+    addr: &str,
+    allow_remote: bool,
+) -> Result<()> {
+    let listener = listen(addr, allow_remote)?;
+    serve_on(bootloader, &listener)
+}
+
+/// Bind the listener for `serve_on()`. There's no authentication, so anything
+/// but a loopback address is refused unless `allow_remote` is set.
+pub fn listen(addr: &str, allow_remote: bool) -> Result<TcpListener> {
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid listen address: {}", addr))?
+        .collect();
+    if !allow_remote && addrs.iter().any(|addr| !addr.ip().is_loopback()) {
+        return Err(anyhow::anyhow!(
+            "Refusing to serve on {}: anyone who can reach it could flash the device, use a loopback address or --serve-allow-remote",
+            addr
+        ));
+    }
+
+    TcpListener::bind(&addrs[..]).with_context(|| format!("Failed to listen on {}", addr))
+}
+
+/// `serve()` on an already bound listener
+pub fn serve_on<T: I2CTransport>(
+    bootloader: &mut TwiBootloader<T>,
+    listener: &TcpListener,
+) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream.context("Failed to accept connection")?;
+        // A client that hangs up or sends garbage only loses its own request
+        let _ = handle(bootloader, stream);
+    }
+
+    Ok(())
+}
+
+fn handle<T: I2CTransport>(bootloader: &mut TwiBootloader<T>, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(IO_TIMEOUT_MS)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().context("Bad Content-Length")?;
+            }
+        }
+    }
+
+    let response = if content_length > MAX_BODY {
+        Response::error(413, "Image too large")
+    } else {
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+        respond(bootloader, method, path, &body)
+    };
+
+    write_response(stream, &response)
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    Ok(())
+}
+
+/// Answer one request, what the server does for every connection
+pub fn respond<T: I2CTransport>(
+    bootloader: &mut TwiBootloader<T>,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Response {
+    let result = match (method, path) {
+        ("GET", "/info") => info(bootloader),
+        ("GET", "/flash") => dump(bootloader),
+        ("POST", "/flash") => flash(bootloader, body),
+        ("POST", "/start") => start(bootloader),
+        (_, "/info" | "/flash" | "/start") => return Response::error(405, "Method not allowed"),
+        _ => return Response::error(404, "Not found"),
+    };
+
+    result.unwrap_or_else(|e| Response::error(500, &format!("{:#}", e)))
+}
+
+// After /start the device runs its application, the next request needs the bootloader back
+fn ensure_bootloader<T: I2CTransport>(bootloader: &mut TwiBootloader<T>) -> Result<()> {
+    if bootloader.mode() != DeviceMode::Bootloader {
+        bootloader.connect(false)?;
+    }
+    Ok(())
+}
+
+fn info<T: I2CTransport>(bootloader: &mut TwiBootloader<T>) -> Result<Response> {
+    Ok(Response::json(
+        200,
+        format!(
+            r#"{{"version":{},"flash_size":{},"page_size":{},"mode":"{}","ok":true}}"#,
+            json_string(bootloader.version()),
+            bootloader.flash_size(),
+            bootloader.page_size(),
+            bootloader.mode().name()
+        ),
+    ))
+}
+
+fn dump<T: I2CTransport>(bootloader: &mut TwiBootloader<T>) -> Result<Response> {
+    ensure_bootloader(bootloader)?;
+    let data = bootloader.read_flash(0, bootloader.flash_size() as usize)?;
+    Ok(Response {
+        status: 200,
+        content_type: "application/octet-stream",
+        body: data,
+    })
+}
+
+fn flash<T: I2CTransport>(bootloader: &mut TwiBootloader<T>, image: &[u8]) -> Result<Response> {
+    if image.is_empty() {
+        return Ok(Response::error(400, "Empty image"));
+    }
+    ensure_bootloader(bootloader)?;
+    let flash_size = bootloader.flash_size() as usize;
+    if image.len() > flash_size {
+        return Ok(Response::error(
+            400,
+            &format!(
+                "Image of {} bytes exceeds the application region of {} bytes",
+                image.len(),
+                flash_size
+            ),
+        ));
+    }
+
+    let start = Instant::now();
+    let written = bootloader.write_flash(image)?;
+    bootloader.verify_flash(image)?;
+    Ok(Response::json(
+        200,
+        format!(
+            r#"{{"bytes_written":{},"pages_written":{},"elapsed_ms":{},"ok":true}}"#,
+            written.bytes_written,
+            written.pages_written,
+            start.elapsed().as_millis()
+        ),
+    ))
+}
+
+fn start<T: I2CTransport>(bootloader: &mut TwiBootloader<T>) -> Result<Response> {
+    bootloader.disconnect()?;
+    Ok(Response::json(200, r#"{"ok":true}"#.to_string()))
+}
@@ -0,0 +1,360 @@
+use anyhow::{anyhow, Result};
+
+use crate::checksum::crc32;
+use crate::i2c::I2CTransport;
+
+// Command bytes as seen by the device, see protocol.rs
+const CMD_VERSION_OR_SWITCH: u8 = 0x01;
+const CMD_MEMORY: u8 = 0x02;
+
+const BOOTTYPE_BOOTLOADER: u8 = 0x00;
+const BOOTTYPE_APPLICATION: u8 = 0x80;
+
+const MEMTYPE_CHIPINFO: u8 = 0x00;
+const MEMTYPE_FLASH: u8 = 0x01;
+const MEMTYPE_EEPROM: u8 = 0x02;
+const MEMTYPE_FUSES: u8 = 0x04;
+const MEMTYPE_PAGESUM: u8 = 0x08;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimulatedMode {
+    Bootloader,
+    Application,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PendingRead {
+    None,
+    Version,
+    Memory { memtype: u8, addr: u32 },
+    WriteStatus(u8),
+}
+
+/// In-memory model of a twiboot device.
+///
+/// Answers version and chipinfo reads, takes page writes into a flash array
+/// (refusing anything that isn't exactly one aligned page below the bootloader
+/// start) and serves flash reads back. Any command sent while the application
+/// is "running" is NAK'd, except the switch back into the bootloader.
+pub struct SimulatedBootloader {
+    address: u8,
+    version: String,
+    chipinfo: Vec<u8>,
+    address_bytes: usize,
+    pagesize: usize,
+    bootloader_start: usize,
+    flash: Vec<u8>,
+    eeprom: Vec<u8>,
+    mode: SimulatedMode,
+    pending: PendingRead,
+    page_writes: usize,
+    fuses: Option<[u8; 4]>,
+    page_checksums: bool,
+    little_endian_addresses: bool,
+    partial_pages: bool,
+    write_status: bool,
+    write_errors: Vec<(usize, u8)>,
+}
+
+impl SimulatedBootloader {
+    /// Classic AVR twiboot (v3.x): 16-bit addresses, 8-byte chipinfo.
+    /// `flash_size` is the physical flash, the bootloader occupies everything from
+    /// `bootloader_start` up.
+    pub fn avr(
+        address: u8,
+        signature: [u8; 3],
+        pagesize: u8,
+        bootloader_start: u16,
+        flash_size: usize,
+    ) -> Self {
+        let chipinfo = vec![
+            signature[0],
+            signature[1],
+            signature[2],
+            pagesize,
+            (bootloader_start >> 8) as u8,
+            (bootloader_start & 0xFF) as u8,
+            0x02, // EEPROM size (512 bytes)
+            0x00,
+        ];
+
+        let mut sim = Self::with_layout(
+            address,
+            "TWIBOOT v3.2",
+            chipinfo,
+            2,
+            pagesize as usize,
+            bootloader_start as usize,
+            flash_size,
+        );
+        sim.eeprom = vec![0xFF; 512];
+        sim
+    }
+
+    /// CH32V twiboot (v4.x): 32-bit addresses, 4-byte chip ID, 4-byte flash size
+    /// and a 16-bit page size.
+    pub fn ch32v(address: u8, chip_id: u32, pagesize: u16, flash_size: u32) -> Self {
+        let mut chipinfo = Vec::with_capacity(12);
+        chipinfo.extend_from_slice(&chip_id.to_be_bytes());
+        chipinfo.extend_from_slice(&flash_size.to_be_bytes());
+        chipinfo.extend_from_slice(&pagesize.to_be_bytes());
+        chipinfo.extend_from_slice(&[0x00, 0x00]);
+
+        Self::with_layout(
+            address,
+            "TWIBOOT v4.0",
+            chipinfo,
+            4,
+            pagesize as usize,
+            flash_size as usize,
+            flash_size as usize,
+        )
+    }
+
+    fn with_layout(
+        address: u8,
+        version: &str,
+        chipinfo: Vec<u8>,
+        address_bytes: usize,
+        pagesize: usize,
+        bootloader_start: usize,
+        flash_size: usize,
+    ) -> Self {
+        Self {
+            address,
+            version: version.to_string(),
+            chipinfo,
+            address_bytes,
+            pagesize,
+            bootloader_start,
+            flash: vec![0xFF; flash_size.max(bootloader_start)],
+            eeprom: Vec::new(),
+            mode: SimulatedMode::Application,
+            pending: PendingRead::None,
+            page_writes: 0,
+            fuses: None,
+            page_checksums: false,
+            little_endian_addresses: false,
+            partial_pages: false,
+            write_status: false,
+            write_errors: Vec::new(),
+        }
+    }
+
+    /// Expose low/high/extended fuse and lock byte like some AVR variants do
+    pub fn with_fuses(mut self, fuses: [u8; 4]) -> Self {
+        self.fuses = Some(fuses);
+        self
+    }
+
+    /// Answer page checksum reads (CRC-32 of the page at the read address)
+    pub fn with_page_checksums(mut self) -> Self {
+        self.page_checksums = true;
+        self
+    }
+
+    /// Take command addresses least significant byte first, like some variants do
+    pub fn with_little_endian_addresses(mut self) -> Self {
+        self.little_endian_addresses = true;
+        self
+    }
+
+    /// Accept page writes shorter than a page and program only the bytes sent,
+    /// like variants that support an unpadded last page
+    pub fn with_partial_pages(mut self) -> Self {
+        self.partial_pages = true;
+        self
+    }
+
+    /// Answer a 1-byte read after each page write with the write's status,
+    /// 0x00 for success
+    pub fn with_write_status(mut self) -> Self {
+        self.write_status = true;
+        self
+    }
+
+    /// Fail every write of the page at `addr` with the status `code`, the flash
+    /// is left as it was. Without `with_write_status()` the failure goes unnoticed.
+    pub fn with_write_error(mut self, addr: usize, code: u8) -> Self {
+        self.write_errors.push((addr, code));
+        self
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Direct access to the flash array, e.g. to corrupt it behind the protocol's back
+    pub fn flash_mut(&mut self) -> &mut [u8] {
+        &mut self.flash
+    }
+
+    /// EEPROM contents, empty on devices without EEPROM (CH32V)
+    pub fn eeprom_mut(&mut self) -> &mut [u8] {
+        &mut self.eeprom
+    }
+
+    pub fn mode(&self) -> SimulatedMode {
+        self.mode
+    }
+
+    pub fn page_writes(&self) -> usize {
+        self.page_writes
+    }
+
+    fn decode_address(&self, bytes: &[u8]) -> u32 {
+        let push = |acc: u32, &b: &u8| (acc << 8) | b as u32;
+        if self.little_endian_addresses {
+            bytes.iter().rev().fold(0, push)
+        } else {
+            bytes.iter().fold(0, push)
+        }
+    }
+
+    fn handle_write(&mut self, data: &[u8]) -> Result<()> {
+        match data {
+            [CMD_VERSION_OR_SWITCH, boottype] => {
+                self.mode = match *boottype {
+                    BOOTTYPE_BOOTLOADER => SimulatedMode::Bootloader,
+                    BOOTTYPE_APPLICATION => SimulatedMode::Application,
+                    _ => self.mode,
+                };
+                self.pending = PendingRead::None;
+                Ok(())
+            }
+            _ if self.mode == SimulatedMode::Application => {
+                Err(anyhow!("NAK: application is running"))
+            }
+            [CMD_VERSION_OR_SWITCH] => {
+                self.pending = PendingRead::Version;
+                Ok(())
+            }
+            [CMD_MEMORY, memtype, rest @ ..] if rest.len() >= self.address_bytes => {
+                let (addr_bytes, payload) = rest.split_at(self.address_bytes);
+                let addr = self.decode_address(addr_bytes);
+
+                if payload.is_empty() {
+                    self.pending = PendingRead::Memory {
+                        memtype: *memtype,
+                        addr,
+                    };
+                    return Ok(());
+                }
+
+                let status = self.write_page(*memtype, addr as usize, payload)?;
+                if self.write_status && *memtype == MEMTYPE_FLASH {
+                    self.pending = PendingRead::WriteStatus(status);
+                }
+                Ok(())
+            }
+            _ => Err(anyhow!("NAK: unknown command {:02X?}", data)),
+        }
+    }
+
+    // Ok carries the status byte of a flash page write
+    fn write_page(&mut self, memtype: u8, addr: usize, payload: &[u8]) -> Result<u8> {
+        if memtype == MEMTYPE_EEPROM {
+            // twiboot writes EEPROM byte by byte, any length and alignment goes
+            if self.eeprom.is_empty() || addr + payload.len() > self.eeprom.len() {
+                return Err(anyhow!(
+                    "NAK: EEPROM write past 0x{:04X}",
+                    self.eeprom.len()
+                ));
+            }
+            self.eeprom[addr..addr + payload.len()].copy_from_slice(payload);
+            return Ok(0x00);
+        }
+        if memtype != MEMTYPE_FLASH {
+            return Err(anyhow!("NAK: memtype 0x{:02X} is not writable", memtype));
+        }
+        let short_ok = self.partial_pages && !payload.is_empty() && payload.len() < self.pagesize;
+        if payload.len() != self.pagesize && !short_ok {
+            return Err(anyhow!(
+                "NAK: page write of {} bytes, expected {}",
+                payload.len(),
+                self.pagesize
+            ));
+        }
+        if !addr.is_multiple_of(self.pagesize) {
+            return Err(anyhow!("NAK: unaligned page address 0x{:04X}", addr));
+        }
+        if addr + self.pagesize > self.bootloader_start {
+            return Err(anyhow!("NAK: page 0x{:04X} overlaps the bootloader", addr));
+        }
+
+        if let Some(&(_, code)) = self.write_errors.iter().find(|&&(a, _)| a == addr) {
+            return Ok(code);
+        }
+
+        self.flash[addr..addr + payload.len()].copy_from_slice(payload);
+        self.page_writes += 1;
+        Ok(0x00)
+    }
+}
+
+impl I2CTransport for SimulatedBootloader {
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> Result<()> {
+        self.handle_write(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if self.mode == SimulatedMode::Application {
+            return Err(anyhow!("NAK: application is running"));
+        }
+
+        match self.pending {
+            PendingRead::None => return Err(anyhow!("NAK: no read command pending")),
+            PendingRead::WriteStatus(status) => {
+                buffer.fill(status);
+                self.pending = PendingRead::None;
+            }
+            PendingRead::Version => {
+                buffer.fill(0x00);
+                let len = self.version.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&self.version.as_bytes()[..len]);
+            }
+            PendingRead::Memory {
+                memtype: MEMTYPE_PAGESUM,
+                addr,
+            } if self.page_checksums => {
+                let start = (addr as usize).min(self.flash.len());
+                let end = (start + self.pagesize).min(self.flash.len());
+                let sum = crc32(&self.flash[start..end]).to_be_bytes();
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    *byte = sum.get(i).copied().unwrap_or(0xFF);
+                }
+            }
+            PendingRead::Memory { memtype, addr } => {
+                let source: &[u8] = match (memtype, &self.fuses) {
+                    (MEMTYPE_CHIPINFO, _) => &self.chipinfo,
+                    (MEMTYPE_FLASH, _) => &self.flash,
+                    (MEMTYPE_EEPROM, _) if !self.eeprom.is_empty() => &self.eeprom,
+                    (MEMTYPE_FUSES, Some(fuses)) => fuses,
+                    _ => return Err(anyhow!("NAK: memtype 0x{:02X} is not readable", memtype)),
+                };
+
+                // Reads past the end of a memory return 0xFF like an open bus
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    *byte = source.get(addr as usize + i).copied().unwrap_or(0xFF);
+                }
+
+                self.pending = PendingRead::Memory {
+                    memtype,
+                    addr: addr + buffer.len() as u32,
+                };
+            }
+        }
+
+        Ok(buffer.len())
+    }
+
+    fn write_then_read_once(&mut self, write_data: &[u8], read_buffer: &mut [u8]) -> Result<()> {
+        self.handle_write(write_data)?;
+        self.read(read_buffer)?;
+        Ok(())
+    }
+}
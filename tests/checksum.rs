@@ -0,0 +1,27 @@
+use twiboot_flasher::checksum::{crc32, sha256, to_hex_string};
+
+#[test]
+fn known_digests() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    assert_eq!(
+        to_hex_string(&sha256(b"")),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        to_hex_string(&sha256(b"abc")),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    // Two blocks after padding
+    assert_eq!(
+        to_hex_string(&sha256(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+    // Long input, many blocks and a length beyond 2^20 bits
+    assert_eq!(
+        to_hex_string(&sha256(&vec![b'a'; 1_000_000])),
+        "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+}
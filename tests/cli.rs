@@ -0,0 +1,133 @@
+use std::process::{Command, Output};
+
+// The binary against a simulated ATtiny84, legacy form without subcommand
+fn run(args: &[&str]) -> Output {
+    run_with_env(args, &[])
+}
+
+fn run_with_env(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_twiboot-flasher"))
+        .args(["1", "0x29", "--simulate", "avr", "--switch-delay-ms", "0"])
+        .args(args)
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn info_only_leaves_the_device_in_the_bootloader() {
+    let output = run(&["--info"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("Version: TWIBOOT v3.2"), "{}", stdout);
+    assert!(!stdout.contains("switched to application"), "{}", stdout);
+
+    // A flash run does start the application at the end
+    let image = std::env::temp_dir().join(format!("twiboot-cli-{}.bin", std::process::id()));
+    std::fs::write(&image, [0x55; 100]).unwrap();
+    let output = run(&[image.to_str().unwrap(), "--force"]);
+    std::fs::remove_file(&image).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("switched to application"), "{}", stdout);
+}
+
+#[test]
+fn info_only_refuses_other_actions() {
+    for args in [
+        &["image.bin", "--info"][..],
+        &["--info", "--dump", "out.bin"],
+    ] {
+        let output = run(args);
+        assert!(!output.status.success(), "{:?} was accepted", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("cannot be used with"), "{}", stderr);
+    }
+}
+
+#[test]
+fn environment_sets_the_retries() {
+    // The variables are read: an invalid value is rejected like the options' would be
+    for (name, option) in [
+        ("TWIBOOT_RETRIES", "--i2c-retries <N>"),
+        ("TWIBOOT_RETRY_DELAY_MS", "--i2c-retry-delay-ms <MS>"),
+    ] {
+        let output = run_with_env(&["--info"], &[(name, "many")]);
+        assert!(!output.status.success(), "{} was ignored", name);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("invalid value 'many' for '{}'", option)),
+            "{}",
+            stderr
+        );
+    }
+
+    // The options on the command line win over them
+    let env = [
+        ("TWIBOOT_RETRIES", "many"),
+        ("TWIBOOT_RETRY_DELAY_MS", "many"),
+    ];
+    let output = run_with_env(
+        &[
+            "--info",
+            "--i2c-retries",
+            "5",
+            "--verify-read-retries",
+            "1",
+            "--i2c-retry-delay-ms",
+            "1",
+            "--verify-read-retry-delay-ms",
+            "1",
+        ],
+        &env,
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Valid values are taken
+    let env = [("TWIBOOT_RETRIES", "5"), ("TWIBOOT_RETRY_DELAY_MS", "1")];
+    let output = run_with_env(&["--info"], &env);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn pagesum_verify_is_refused_for_partial_images() {
+    for args in [
+        &["image.bin", "--offset", "0x100", "--verify-pagesum"][..],
+        &["--data-hex", "AA55", "--verify-pagesum"],
+        &["image.hex", "--skip-gaps", "--verify-pagesum"],
+    ] {
+        let output = run(args);
+        assert!(!output.status.success(), "{:?} was accepted", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("cannot be used with"), "{}", stderr);
+    }
+}
+
+#[test]
+fn simulator_answers_write_status() {
+    let image = std::env::temp_dir().join(format!("twiboot-cli-status-{}.bin", std::process::id()));
+    std::fs::write(&image, [0x55; 100]).unwrap();
+    let output = run(&[image.to_str().unwrap(), "--force", "--write-status"]);
+    std::fs::remove_file(&image).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,28 @@
+use twiboot_flasher::diff::{diff, Change};
+
+#[test]
+fn reports_changed_runs_inside_defined_ranges() {
+    let current = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+    let image = [0x00, 0xAA, 0xBB, 0x33, 0xCC, 0xFF, 0xFF, 0x77];
+
+    // 5..7 is a gap in the image, the 0xFF fill there isn't a change
+    let changes = diff(&current, &image, &[0..5, 7..8]);
+    assert_eq!(
+        changes,
+        vec![
+            Change {
+                addr: 1,
+                old: vec![0x11, 0x22],
+                new: vec![0xAA, 0xBB],
+            },
+            Change {
+                addr: 4,
+                old: vec![0x44],
+                new: vec![0xCC],
+            },
+        ]
+    );
+
+    let whole = 0..current.len();
+    assert!(diff(&current, &current, std::slice::from_ref(&whole)).is_empty());
+}
@@ -0,0 +1,320 @@
+use std::fs;
+use std::path::PathBuf;
+
+use twiboot_flasher::file_ops::{
+    image_warnings, parse_hex_segments, read_file_with_bootloader_info, to_hex, FileFormat,
+    FirmwareImage, Segment,
+};
+
+const LIMIT: u32 = 0x1C00;
+
+fn temp_file(name: &str, content: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("twiboot-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn read_auto(name: &str, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+    read_image(name, content).map(|image| image.data)
+}
+
+fn read_image(name: &str, content: &[u8]) -> anyhow::Result<FirmwareImage> {
+    let path = temp_file(name, content);
+    let result = read_file_with_bootloader_info(&path, FileFormat::Auto, LIMIT, LIMIT);
+    fs::remove_file(&path).unwrap();
+    result
+}
+
+#[test]
+fn detects_hex_after_comment_line() {
+    let hex = b"# built by make\n:0400000001020304F2\n:00000001FF\n";
+    assert_eq!(read_auto("comment.img", hex).unwrap(), vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn detects_binary() {
+    let bin = [0x0C, 0x94, 0x34, 0x00, 0xFF];
+    assert_eq!(read_auto("raw.img", &bin).unwrap(), bin.to_vec());
+}
+
+#[test]
+fn rejects_srec() {
+    let srec = b"S00600004844521B\nS1070000010203046E\n";
+    assert!(read_auto("srec.img", srec).is_err());
+}
+
+#[test]
+fn rejects_ambiguous_text() {
+    assert!(read_auto("notes.img", b"this is not firmware\n").is_err());
+}
+
+#[test]
+fn tracks_padding_between_records() {
+    let hex = b":020000000102FB\n:020006000304F1\n:00000001FF\n";
+    let image = read_image("gap.hex", hex).unwrap();
+
+    assert_eq!(image.data, vec![1, 2, 0xFF, 0xFF, 0xFF, 0xFF, 3, 4]);
+    assert_eq!(image.defined, vec![0..2, 6..8]);
+    assert_eq!(image.padding_bytes(), 4);
+}
+
+#[test]
+fn parses_start_linear_address() {
+    let hex = b":0400000001020304F2\n:0400000500000100F6\n:00000001FF\n";
+    let image = read_image("start.hex", hex).unwrap();
+
+    assert_eq!(image.data, vec![1, 2, 3, 4]);
+    assert_eq!(image.start_address, Some(0x100));
+}
+
+#[test]
+fn separates_eeprom_data() {
+    let hex = b":0400000001020304F2\n:02000004008179\n:02001000AABB89\n:00000001FF\n";
+    let image = read_image("eeprom.hex", hex).unwrap();
+
+    assert_eq!(image.data, vec![1, 2, 3, 4]);
+    assert_eq!(
+        image.eeprom,
+        vec![Segment {
+            start: 0x10,
+            bytes: vec![0xAA, 0xBB]
+        }]
+    );
+}
+
+#[test]
+fn eeprom_block_fills_gaps() {
+    let image = FirmwareImage {
+        eeprom: vec![
+            Segment {
+                start: 4,
+                bytes: vec![1, 2],
+            },
+            Segment {
+                start: 8,
+                bytes: vec![3],
+            },
+        ],
+        ..Default::default()
+    };
+
+    assert_eq!(
+        image.eeprom_block(0x00),
+        Some(Segment {
+            start: 4,
+            bytes: vec![1, 2, 0, 0, 3]
+        })
+    );
+    assert_eq!(FirmwareImage::default().eeprom_block(0xFF), None);
+}
+
+#[test]
+fn splits_segments_at_gaps() {
+    let hex = b":020000000102FB\n:0100020003FA\n:020100000506F2\n:00000001FF\n";
+    let segments = parse_hex_segments(hex).unwrap();
+
+    assert_eq!(
+        segments,
+        vec![
+            Segment {
+                start: 0,
+                bytes: vec![1, 2, 3]
+            },
+            Segment {
+                start: 0x100,
+                bytes: vec![5, 6]
+            },
+        ]
+    );
+}
+
+#[test]
+fn warns_about_missing_reset_vector() {
+    let image = |data: Vec<u8>| FirmwareImage {
+        data,
+        ..Default::default()
+    };
+
+    assert_eq!(image_warnings(&image(vec![0xFF, 0xFF, 0x12])).len(), 1);
+    assert_eq!(image_warnings(&image(vec![0x00, 0x00, 0x12])).len(), 1);
+    assert!(image_warnings(&image(vec![0x0E, 0xC0, 0x12])).is_empty());
+}
+
+#[test]
+fn reports_end_and_page_aligned_length() {
+    let image = read_image("short.hex", b":020000000102FB\n:00000001FF\n").unwrap();
+
+    assert_eq!(image.end_address(), 2);
+    assert_eq!(image.page_aligned_len(64), 64);
+}
+
+#[test]
+fn rejects_images_without_data() {
+    let empty = read_image("empty.bin", b"").unwrap_err();
+    assert!(empty.to_string().contains("no data"));
+
+    let eof_only = read_image("eof.hex", b":00000001FF\n").unwrap_err();
+    assert!(eof_only.to_string().contains("no data"));
+}
+
+// ELF32 little endian with one PT_LOAD segment per (paddr, content)
+fn elf32(segments: &[(u32, &[u8])]) -> Vec<u8> {
+    let phoff = 52u32;
+    let data_start = phoff + 32 * segments.len() as u32;
+
+    let mut elf = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+    elf.resize(24, 0);
+    elf.extend_from_slice(&0x34u32.to_le_bytes()); // e_entry
+    elf.extend_from_slice(&phoff.to_le_bytes());
+    elf.resize(42, 0);
+    elf.extend_from_slice(&32u16.to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    elf.resize(52, 0);
+
+    let mut offset = data_start;
+    for (paddr, content) in segments {
+        let len = content.len() as u32;
+        for field in [1, offset, paddr | 0x0080_0000, *paddr, len, len, 5, 1] {
+            elf.extend_from_slice(&field.to_le_bytes());
+        }
+        offset += len;
+    }
+    for (_, content) in segments {
+        elf.extend_from_slice(content);
+    }
+    elf
+}
+
+#[cfg(feature = "elf")]
+#[test]
+fn reads_elf_load_segments() {
+    let elf = elf32(&[(0, &[1, 2, 3, 4]), (8, &[5, 6]), (0x81_0000, &[0xEE])]);
+    let image = read_image("image.elf", &elf).unwrap();
+
+    assert_eq!(image.data, [1, 2, 3, 4, 0xFF, 0xFF, 0xFF, 0xFF, 5, 6]);
+    assert_eq!(image.defined, vec![0..4, 8..10]);
+    assert_eq!(image.start_address, Some(0x34));
+}
+
+// ELF64 little endian header with the given e_phoff and e_phnum, plus one
+// PT_LOAD program header right behind it with the given p_paddr and p_filesz
+#[cfg(feature = "elf")]
+fn elf64(phoff: u64, phnum: u16, paddr: u64, filesz: u64) -> Vec<u8> {
+    let mut elf = vec![0x7F, b'E', b'L', b'F', 2, 1, 1];
+    elf.resize(32, 0);
+    elf.extend_from_slice(&phoff.to_le_bytes());
+    elf.resize(54, 0);
+    elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    elf.extend_from_slice(&phnum.to_le_bytes());
+    elf.resize(64, 0);
+
+    elf.extend_from_slice(&1u32.to_le_bytes()); // p_type
+    elf.extend_from_slice(&5u32.to_le_bytes()); // p_flags
+    for field in [120u64, 0, paddr, filesz, filesz, 1] {
+        elf.extend_from_slice(&field.to_le_bytes());
+    }
+    elf
+}
+
+#[cfg(feature = "elf")]
+#[test]
+fn rejects_elf_offsets_that_overflow() {
+    let header = read_image("header.elf", &elf64(u64::MAX - 4, 1, 0, 4)).unwrap_err();
+    assert!(
+        header.to_string().contains("Invalid ELF file"),
+        "{}",
+        header
+    );
+
+    let segment = read_image("segment.elf", &elf64(64, 1, 0x100, u64::MAX)).unwrap_err();
+    assert!(segment.to_string().contains("invalid size"), "{}", segment);
+}
+
+#[cfg(not(feature = "elf"))]
+#[test]
+fn elf_needs_the_feature() {
+    let err = read_image("image.elf", &elf32(&[(0, &[1, 2])])).unwrap_err();
+    assert!(err.to_string().contains("--features elf"));
+}
+
+#[test]
+fn hex_digits_in_any_case() {
+    let upper = b":04000000ABCDEF01C4\n:00000001FF\n";
+    let lower = b":04000000abcdef01c4\n:00000001ff\n";
+    let mixed = b":04000000AbCdEf01c4\n:00000001Ff\n";
+
+    for (name, hex) in [
+        ("upper.img", &upper[..]),
+        ("lower.img", lower),
+        ("mixed.img", mixed),
+    ] {
+        assert_eq!(read_auto(name, hex).unwrap(), vec![0xAB, 0xCD, 0xEF, 0x01]);
+    }
+}
+
+#[test]
+fn writes_upper_case_hex() {
+    let data: Vec<u8> = (0..20u8).map(|i| i * 13).collect();
+    let hex = to_hex(&data);
+
+    assert_eq!(
+        hex.lines().next(),
+        Some(":10000000000D1A2734414E5B6875828F9CA9B6C3D8")
+    );
+    assert_eq!(hex.lines().last(), Some(":00000001FF"));
+    assert!(!hex.chars().any(|c| c.is_ascii_lowercase()));
+
+    let segments = parse_hex_segments(hex.as_bytes()).unwrap();
+    assert_eq!(
+        segments,
+        vec![Segment {
+            start: 0,
+            bytes: data
+        }]
+    );
+}
+
+#[test]
+fn hex_with_bom_and_trailing_junk() {
+    let mut hex = b"\xEF\xBB\xBF:0400000001020304F2\n:00000001FF\n".to_vec();
+    let path = temp_file("bom.hex", &hex);
+    let image = read_file_with_bootloader_info(&path, FileFormat::Hex, LIMIT, LIMIT);
+    assert_eq!(image.unwrap().data, vec![1, 2, 3, 4]);
+
+    hex.extend_from_slice(&[0x00, 0xFE, 0x80, 0x1A]);
+    fs::write(&path, &hex).unwrap();
+    let image = read_file_with_bootloader_info(&path, FileFormat::Hex, LIMIT, LIMIT);
+    assert_eq!(image.unwrap().data, vec![1, 2, 3, 4]);
+
+    fs::write(&path, b":04000000010\xC3\xA40304F2\n:00000001FF\n").unwrap();
+    let garbled = read_file_with_bootloader_info(&path, FileFormat::Hex, LIMIT, LIMIT);
+    fs::remove_file(&path).unwrap();
+    assert!(garbled.is_err());
+}
+
+#[test]
+fn fill_to_extends_the_defined_ranges() {
+    let hex = b":020000000102FB\n:020006000304F1\n:00000001FF\n";
+    let mut image = read_image("fill.hex", hex).unwrap();
+    image.fill_to(16, 0xFF);
+
+    assert_eq!(image.data.len(), 16);
+    assert!(image.data[8..].iter().all(|&b| b == 0xFF));
+    assert_eq!(image.defined, vec![0..2, 6..16]);
+
+    image.fill_gaps(0x00);
+    assert_eq!(image.data[..8], [1, 2, 0, 0, 0, 0, 3, 4]);
+}
+
+#[test]
+fn rebase_moves_binary_up() {
+    let mut image = FirmwareImage {
+        data: vec![1, 2, 3],
+        defined: std::iter::once(0..3).collect(),
+        ..Default::default()
+    };
+    image.rebase(4, 0xFF);
+
+    assert_eq!(image.data, [0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3]);
+    assert_eq!(image.defined, std::slice::from_ref(&(4..7)));
+}
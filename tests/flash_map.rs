@@ -0,0 +1,42 @@
+use twiboot_flasher::flash_map::{free_bytes, page_map, used_pages, Region};
+
+#[test]
+fn map_rounds_to_pages_and_merges_regions() {
+    // 64 byte pages, 0x200 bytes of flash, data in page 0 and touching page 5
+    let regions = page_map(&[0..10, 20..30, 0x17F..0x181], 64, 0x200);
+
+    assert_eq!(
+        regions,
+        vec![
+            Region {
+                start: 0,
+                end: 0x40,
+                used: true
+            },
+            Region {
+                start: 0x40,
+                end: 0x140,
+                used: false
+            },
+            Region {
+                start: 0x140,
+                end: 0x1C0,
+                used: true
+            },
+            Region {
+                start: 0x1C0,
+                end: 0x200,
+                used: false
+            },
+        ]
+    );
+    assert_eq!(free_bytes(&regions), 0x140);
+}
+
+#[test]
+fn erased_pages_count_as_free() {
+    let mut flash = vec![0xFF; 0x100];
+    flash[0x41] = 0x00;
+
+    assert_eq!(used_pages(&flash, 64), vec![0x40..0x80]);
+}
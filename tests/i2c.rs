@@ -0,0 +1,95 @@
+use i2cdev::linux::LinuxI2CError;
+use twiboot_flasher::i2c::{retry_is_pointless, I2CTransport, RawI2CDevice, TwiI2CDevice};
+
+// Fails every transfer with `errno`
+struct Unanswered {
+    errno: i32,
+}
+
+impl RawI2CDevice for Unanswered {
+    fn write(&mut self, _data: &[u8]) -> Result<(), LinuxI2CError> {
+        Err(LinuxI2CError::Errno(self.errno))
+    }
+
+    fn read(&mut self, _buffer: &mut [u8]) -> Result<(), LinuxI2CError> {
+        Err(LinuxI2CError::Errno(self.errno))
+    }
+}
+
+fn device(errno: i32, hexdump_on_error: bool) -> TwiI2CDevice<Unanswered> {
+    let mut device = TwiI2CDevice::with_device(Unanswered { errno }, 0x29);
+    device.set_hexdump_on_error(hexdump_on_error);
+    device
+}
+
+#[test]
+fn failed_transfers_show_what_was_sent() {
+    let mut i2c = device(libc::ENXIO, true);
+    let err = i2c.write_with_retry(&[0x02, 0x01, 0x1C, 0x00]).unwrap_err();
+    let message = format!("{:#}", err);
+    assert!(
+        message.contains("\nSent 4 bytes:\n00000000: 02 01 1C 00 "),
+        "{}",
+        message
+    );
+
+    let err = i2c.read(&mut [0u8; 8]).unwrap_err();
+    assert!(
+        format!("{:#}", err).ends_with("\nReading 8 bytes"),
+        "{:#}",
+        err
+    );
+
+    // The single attempts of ping() and probe() too
+    let err = i2c
+        .write_then_read_once(&[0x01], &mut [0u8; 16])
+        .unwrap_err();
+    let message = format!("{:#}", err);
+    assert!(
+        message.starts_with("I2C write not acknowledged"),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("\nSent 1 bytes, expected 16 back:\n00000000: 01 "),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn hexdump_only_on_request() {
+    let mut i2c = device(libc::ENXIO, false);
+    let err = i2c
+        .write_then_read_once(&[0x01], &mut [0u8; 16])
+        .unwrap_err();
+    assert!(!format!("{:#}", err).contains("Sent"), "{:#}", err);
+}
+
+#[test]
+fn fatal_errors_stay_recognizable() {
+    // The hexdump would turn the error into a plain message
+    let mut i2c = device(libc::ENODEV, true);
+    let err = i2c.write_with_retry(&[0x01, 0x00]).unwrap_err();
+    assert!(retry_is_pointless(&err), "{:#}", err);
+    assert!(!format!("{:#}", err).contains("Sent"), "{:#}", err);
+    assert_eq!(i2c.retries(), 0);
+}
+
+#[test]
+fn retry_limit_can_be_changed() {
+    let mut i2c = device(libc::ENXIO, false);
+    i2c.set_retry_delay(Some(std::time::Duration::ZERO));
+    i2c.set_retries(Some(4));
+    let err = i2c.write_with_retry(&[0x01, 0x00]).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("after 4 retries"),
+        "{:#}",
+        err
+    );
+    assert_eq!(i2c.retries(), 4);
+
+    i2c.set_retries(None);
+    i2c.read(&mut [0u8; 4]).unwrap_err();
+    assert_eq!(i2c.retries(), 4 + 3);
+}
@@ -0,0 +1,74 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use twiboot_flasher::i2c::I2CTransport;
+use twiboot_flasher::protocol::TwiBootloader;
+use twiboot_flasher::remote::TcpI2CDevice;
+use twiboot_flasher::simulator::SimulatedBootloader;
+
+// Minimal agent serving one client from a simulated device
+fn serve(mut stream: TcpStream, mut sim: SimulatedBootloader) {
+    loop {
+        let mut header = [0u8; 3];
+        if stream.read_exact(&mut header).is_err() {
+            return;
+        }
+        let mut payload = vec![0u8; u16::from_be_bytes([header[1], header[2]]) as usize];
+        stream.read_exact(&mut payload).unwrap();
+
+        let result = match header[0] {
+            b'A' => Ok(Vec::new()),
+            b'W' => sim.write_with_retry(&payload).map(|_| Vec::new()),
+            b'R' | b'O' => {
+                let mut buffer = vec![0u8; u16::from_be_bytes([payload[0], payload[1]]) as usize];
+                let result = if header[0] == b'R' {
+                    sim.read(&mut buffer).map(|_| ())
+                } else {
+                    sim.write_then_read_once(&payload[2..], &mut buffer)
+                };
+                result.map(|_| buffer)
+            }
+            op => panic!("unexpected op {}", op),
+        };
+
+        let (status, body) = match result {
+            Ok(data) => (0u8, data),
+            Err(e) => (1u8, e.to_string().into_bytes()),
+        };
+        let mut frame = vec![status];
+        frame.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&body);
+        stream.write_all(&frame).unwrap();
+    }
+}
+
+#[test]
+fn flash_through_remote_agent() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let remote = listener.local_addr().unwrap().to_string();
+
+    let agent = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        serve(
+            stream,
+            SimulatedBootloader::avr(0x29, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000),
+        );
+    });
+
+    let mut bootloader = TwiBootloader::new(TcpI2CDevice::new(&remote, 0x29).unwrap());
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+
+    let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    assert!(!bootloader.ping());
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&data).unwrap();
+    bootloader.verify_flash(&data).unwrap();
+    bootloader.disconnect().unwrap();
+
+    drop(bootloader);
+    agent.join().unwrap();
+}
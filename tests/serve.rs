@@ -0,0 +1,101 @@
+#![cfg(feature = "serve")]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use twiboot_flasher::protocol::TwiBootloader;
+use twiboot_flasher::serve::{listen, respond, serve, serve_on};
+use twiboot_flasher::simulator::SimulatedBootloader;
+
+fn attiny84() -> TwiBootloader<SimulatedBootloader> {
+    let sim = SimulatedBootloader::avr(0x29, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(sim);
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_quiet(true);
+    bootloader
+}
+
+#[test]
+fn flash_dump_and_start() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    let image: Vec<u8> = (0..100).map(|i| i as u8).collect();
+
+    let info = respond(&mut bootloader, "GET", "/info", &[]);
+    assert_eq!(info.status, 200);
+    assert!(String::from_utf8(info.body)
+        .unwrap()
+        .contains(r#""flash_size":7168"#));
+
+    let flashed = respond(&mut bootloader, "POST", "/flash", &image);
+    assert_eq!(
+        flashed.status,
+        200,
+        "{}",
+        String::from_utf8_lossy(&flashed.body)
+    );
+
+    assert_eq!(respond(&mut bootloader, "POST", "/start", &[]).status, 200);
+    // Dumping needs the bootloader again
+    let dump = respond(&mut bootloader, "GET", "/flash", &[]);
+    assert_eq!(dump.status, 200);
+    assert_eq!(&dump.body[..100], &image[..]);
+}
+
+#[test]
+fn rejects_bad_requests() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+
+    assert_eq!(respond(&mut bootloader, "GET", "/nope", &[]).status, 404);
+    assert_eq!(
+        respond(&mut bootloader, "DELETE", "/flash", &[]).status,
+        405
+    );
+    assert_eq!(respond(&mut bootloader, "POST", "/flash", &[]).status, 400);
+    assert_eq!(
+        respond(&mut bootloader, "POST", "/flash", &[0; 0x2000]).status,
+        400
+    );
+}
+
+#[test]
+fn refuses_remote_addresses_unless_allowed() {
+    let mut bootloader = attiny84();
+    let err = serve(&mut bootloader, "0.0.0.0:0", false).unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("Refusing to serve on 0.0.0.0:0"),
+        "{}",
+        err
+    );
+    let err = listen("[::]:0", false).unwrap_err();
+    assert!(err.to_string().starts_with("Refusing"), "{}", err);
+    assert!(listen("0.0.0.0:0", true).is_ok());
+    assert!(listen("127.0.0.1:0", false).is_ok());
+}
+
+#[test]
+fn answers_over_http() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut bootloader = attiny84();
+        bootloader.connect(false).unwrap();
+        serve_on(&mut bootloader, &listener).unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"POST /flash HTTP/1.1\r\nContent-Length: 4\r\n\r\n\x01\x02\x03\x04")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with(r#""ok":true}"#), "{}", response);
+}
@@ -0,0 +1,1519 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use i2cdev::linux::LinuxI2CError;
+use twiboot_flasher::i2c::{
+    is_retry_budget_exhausted, retry_is_pointless, FatalI2CError, I2CTransport, RawI2CDevice,
+    RetryBudgetExhausted, TwiI2CDevice,
+};
+use twiboot_flasher::protocol::{
+    encode_address, AddressEndian, AddressWidth, ChipInfo, DeviceMode, FlashError, Fuses,
+    TwiBootloader, UnexpectedVersion, VerifyError, VerifyOrder,
+};
+use twiboot_flasher::simulator::{SimulatedBootloader, SimulatedMode};
+
+const ADDRESS: u8 = 0x29;
+
+// ATtiny84: 8KB flash, 64 byte pages, bootloader at 0x1C00
+fn attiny84() -> TwiBootloader<SimulatedBootloader> {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    fast(TwiBootloader::new(sim))
+}
+
+fn ch32v003() -> TwiBootloader<SimulatedBootloader> {
+    let sim = SimulatedBootloader::ch32v(ADDRESS, 0x0030_0500, 64, 0x3800);
+    fast(TwiBootloader::new(sim))
+}
+
+fn fast(mut bootloader: TwiBootloader<SimulatedBootloader>) -> TwiBootloader<SimulatedBootloader> {
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader
+}
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+#[test]
+fn full_cycle_avr() {
+    let mut bootloader = attiny84();
+    let data = image(1000);
+
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.flash_size(), 0x1C00);
+    assert_eq!(bootloader.transport().mode(), SimulatedMode::Bootloader);
+
+    let written = bootloader.write_flash(&data).unwrap();
+    assert_eq!(written.bytes_written, 1000);
+    assert_eq!(written.pages_written, 16);
+    assert_eq!(written.padding_bytes, 24);
+    let verified = bootloader.verify_flash(&data).unwrap();
+    assert_eq!(verified.bytes_verified, 1000);
+    assert_eq!(verified.mismatches, 0);
+    bootloader.disconnect().unwrap();
+
+    let sim = bootloader.transport();
+    assert_eq!(&sim.flash()[..data.len()], &data[..]);
+    // Last page is padded with erased bytes
+    assert!(sim.flash()[data.len()..1024].iter().all(|&b| b == 0xFF));
+    assert_eq!(sim.page_writes(), 16);
+    assert_eq!(sim.mode(), SimulatedMode::Application);
+}
+
+#[test]
+fn full_cycle_ch32v() {
+    let mut bootloader = ch32v003();
+    let data = image(0x800);
+
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.flash_size(), 0x3800);
+
+    bootloader.write_flash(&data).unwrap();
+    bootloader.verify_flash(&data).unwrap();
+    bootloader.disconnect().unwrap();
+
+    assert_eq!(&bootloader.transport().flash()[..data.len()], &data[..]);
+}
+
+#[test]
+fn verify_detects_corruption() {
+    let mut bootloader = attiny84();
+    let data = image(512);
+
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&data).unwrap();
+    bootloader.transport_mut().flash_mut()[300] ^= 0x01;
+
+    let err = bootloader.verify_flash(&data).unwrap_err();
+    let verify = err.downcast_ref::<VerifyError>().unwrap();
+    assert_eq!(verify.first_mismatch, 300);
+    assert_eq!(verify.summary.mismatches, 1);
+}
+
+#[test]
+fn write_into_bootloader_region_fails() {
+    let mut bootloader = attiny84();
+    let data = image(0x1C00 + 64);
+
+    bootloader.connect(false).unwrap();
+    assert!(bootloader.write_flash(&data).is_err());
+    // Everything below the boundary made it before the device refused
+    assert_eq!(bootloader.transport().page_writes(), 0x1C00 / 64);
+}
+
+#[test]
+fn ping_only_answers_in_bootloader() {
+    let mut bootloader = attiny84();
+
+    assert!(!bootloader.ping());
+    bootloader.connect(false).unwrap();
+    assert!(bootloader.ping());
+    bootloader.disconnect().unwrap();
+    assert!(!bootloader.ping());
+
+    // Already confirmed, second call doesn't touch the bus
+    bootloader.disconnect().unwrap();
+}
+
+#[test]
+fn reset_vector_last_writes_page_zero_at_the_end() {
+    let mut bootloader = attiny84();
+    let data = image(200);
+
+    bootloader.set_reset_vector_last(true);
+    bootloader.connect(false).unwrap();
+    let written = bootloader.write_flash(&data).unwrap();
+    bootloader.verify_flash(&data).unwrap();
+
+    // Erase of page 0, pages 1..=3, then page 0 again; the report has each page once
+    assert_eq!(bootloader.transport().page_writes(), 5);
+    assert_eq!(written.pages_written, 4);
+    assert_eq!(&bootloader.transport().flash()[..data.len()], &data[..]);
+}
+
+#[test]
+fn connect_rejects_flash_above_cap() {
+    let mut bootloader = ch32v003();
+
+    bootloader.set_max_flash_size(0x2000);
+    assert!(bootloader.connect(false).is_err());
+}
+
+#[test]
+fn fuses_are_optional() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.fuses(), None);
+
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_fuses([0xE2, 0xDF, 0xFF, 0xFF]);
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    // The fuse memtype is only sent on request
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.fuses(), None);
+
+    bootloader.set_read_fuses(true);
+    bootloader.connect(false).unwrap();
+    assert_eq!(
+        bootloader.fuses(),
+        Some(Fuses {
+            low: 0xE2,
+            high: 0xDF,
+            extended: 0xFF,
+            lock: 0xFF
+        })
+    );
+}
+
+#[test]
+fn resumable_write_continues_after_interruption() {
+    let state = std::env::temp_dir().join(format!("twiboot-{}-resume", std::process::id()));
+    let data = image(1000);
+
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+
+    // Pretend an earlier run got through 512 bytes, but the last page didn't stick
+    bootloader.transport_mut().flash_mut()[..448].copy_from_slice(&data[..448]);
+    std::fs::write(
+        &state,
+        format!(
+            "crc32={:08X} length=1000 next=512\n",
+            twiboot_flasher::checksum::crc32(&data)
+        ),
+    )
+    .unwrap();
+
+    let written = bootloader.write_flash_resumable(&data, &state).unwrap();
+    assert_eq!(written.bytes_written, 1000 - 448);
+    assert_eq!(bootloader.transport().page_writes(), 16 - 7);
+    assert!(!state.exists());
+
+    bootloader.verify_flash(&data).unwrap();
+}
+
+#[test]
+fn verify_report_all_collects_every_mismatch() {
+    let mut bootloader = attiny84();
+    let data = image(512);
+
+    bootloader.set_verify_report_all(true);
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&data).unwrap();
+    bootloader.transport_mut().flash_mut()[10] = 0x00;
+    bootloader.transport_mut().flash_mut()[400] = 0x00;
+
+    let err = bootloader.verify_flash(&data).unwrap_err();
+    let verify = err.downcast_ref::<VerifyError>().unwrap();
+    assert_eq!(verify.summary.bytes_verified, 512);
+    let addrs: Vec<u32> = verify.summary.details.iter().map(|m| m.addr).collect();
+    assert_eq!(addrs, vec![10, 400]);
+}
+
+#[test]
+fn erase_clears_application_region() {
+    let mut bootloader = attiny84();
+
+    bootloader.connect(false).unwrap();
+    bootloader.transport_mut().flash_mut()[0x1000] = 0x12;
+
+    let erased = bootloader.erase_flash().unwrap();
+    assert_eq!(erased.pages_written, 0x1C00 / 64);
+    assert!(bootloader.transport().flash()[..0x1C00]
+        .iter()
+        .all(|&b| b == 0xFF));
+}
+
+#[test]
+fn page_delay_scaling_lowers_delay_on_good_pages() {
+    let mut bootloader = attiny84();
+    let data = image(64 * 16);
+
+    bootloader.set_write_delay(Duration::from_millis(3));
+    bootloader.set_adaptive_write_delay(true);
+    bootloader.connect(false).unwrap();
+
+    let written = bootloader.write_flash(&data).unwrap();
+    assert_eq!(written.write_delay, Duration::from_millis(1));
+    assert_eq!(&bootloader.transport().flash()[..data.len()], &data[..]);
+
+    // The scaled delay doesn't outlive the connection, the configured one is kept
+    bootloader.set_adaptive_write_delay(false);
+    bootloader.connect(false).unwrap();
+    let written = bootloader.write_flash(&image(64)).unwrap();
+    assert_eq!(written.write_delay, Duration::from_millis(3));
+}
+
+#[test]
+fn write_ranges_skips_gap_pages() {
+    let mut bootloader = attiny84();
+    let data = image(0x400);
+
+    bootloader.connect(false).unwrap();
+    // Data in the first and the last page, everything in between is a gap
+    let written = bootloader
+        .write_flash_ranges(&data, &[0..10, 0x3F0..0x400])
+        .unwrap();
+    assert_eq!(written.pages_written, 2);
+
+    let sim = bootloader.transport();
+    assert_eq!(sim.page_writes(), 2);
+    assert_eq!(&sim.flash()[..10], &data[..10]);
+    assert!(sim.flash()[0x40..0x3C0].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn write_reports_progress_per_page() {
+    let mut bootloader = attiny84();
+    let data = image(100);
+
+    bootloader.connect(false).unwrap();
+    let mut reports = Vec::new();
+    bootloader
+        .write_flash_with_progress(&data, |done, total| reports.push((done, total)))
+        .unwrap();
+
+    assert_eq!(reports, vec![(0, 100), (64, 100), (100, 100)]);
+}
+
+#[test]
+fn read_flash_includes_bootloader_region() {
+    let mut bootloader = attiny84();
+    bootloader.transport_mut().flash_mut()[0x1C00..].fill(0x42);
+
+    bootloader.connect(false).unwrap();
+    let full = bootloader.read_flash(0, 0x2000).unwrap();
+    assert_eq!(full.len(), 0x2000);
+    assert!(full[..0x1C00].iter().all(|&b| b == 0xFF));
+    assert!(full[0x1C00..].iter().all(|&b| b == 0x42));
+}
+
+#[test]
+fn skip_unchanged_only_writes_changed_pages() {
+    let mut bootloader = attiny84();
+    let mut data = image(0x200);
+
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&data).unwrap();
+
+    data[0x41] ^= 0xFF;
+    data[0x1C0] ^= 0xFF;
+    bootloader.set_skip_unchanged(true);
+    let written = bootloader.write_flash(&data).unwrap();
+
+    assert_eq!(written.written_pages, vec![0x40, 0x1C0]);
+    assert_eq!(written.pages_skipped, 6);
+    assert_eq!(bootloader.transport().page_writes(), 8 + 2);
+    bootloader.verify_flash(&data).unwrap();
+}
+
+// Records every transaction sent to the simulator
+struct Recorder {
+    sim: SimulatedBootloader,
+    writes: Vec<Vec<u8>>,
+}
+
+impl I2CTransport for Recorder {
+    fn address(&self) -> u8 {
+        self.sim.address()
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.writes.push(data.to_vec());
+        self.sim.write_with_retry(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        self.sim.read(buffer)
+    }
+
+    fn write_then_read_once(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        self.writes.push(write.to_vec());
+        self.sim.write_then_read_once(write, read)
+    }
+}
+
+#[test]
+fn write_without_verify_never_reenters_bootloader() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(Recorder {
+        sim,
+        writes: Vec::new(),
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+
+    bootloader.connect(false).unwrap();
+    bootloader.transport_mut().writes.clear();
+    bootloader.write_flash(&image(200)).unwrap();
+    bootloader.disconnect().unwrap();
+
+    let writes = &bootloader.transport().writes;
+    // 4 pages, then only the switch to the application and the "is it gone" probe
+    assert_eq!(writes.len(), 4 + 2);
+    assert!(writes[..4].iter().all(|w| w[0] == 0x02));
+    assert_eq!(writes[4], vec![0x01, 0x80]);
+    assert!(!writes.contains(&vec![0x01, 0x00]));
+}
+
+#[test]
+fn unexpected_version_is_refused_before_chipinfo() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(Recorder {
+        sim,
+        writes: Vec::new(),
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_expect_version(Some("v4.".to_string()));
+    // Waiting doesn't change the build that answered, so this doesn't hang
+    let err = bootloader.connect(true).unwrap_err();
+    let unexpected = err.downcast_ref::<UnexpectedVersion>().unwrap();
+    assert_eq!(unexpected.version, "TWIBOOT v3.2");
+    assert!(bootloader.chip_info().is_none());
+    let writes = &bootloader.transport().writes;
+    assert!(
+        !writes.iter().any(|w| w.starts_with(&[0x02, 0x00])),
+        "{:02X?}",
+        writes
+    );
+
+    bootloader.set_expect_version(Some("v3.".to_string()));
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.flash_size(), 0x1C00);
+}
+
+// Fails the next `failures` flash reads, like a glitch on the bus. With
+// `exhausted` every flash read fails like a TwiI2CDevice out of retry budget.
+struct GlitchyReads {
+    recorder: Recorder,
+    failures: usize,
+    exhausted: bool,
+}
+
+impl I2CTransport for GlitchyReads {
+    fn address(&self) -> u8 {
+        self.recorder.address()
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.recorder.write_with_retry(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        self.recorder.read(buffer)
+    }
+
+    fn write_then_read_once(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        self.recorder.write_then_read_once(write, read)
+    }
+
+    fn write_then_read(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<usize> {
+        if write.starts_with(&[0x02, 0x01]) && self.exhausted {
+            return Err(anyhow::Error::new(RetryBudgetExhausted(5)).context("I2C read failed"));
+        }
+        if write.starts_with(&[0x02, 0x01]) && self.failures > 0 {
+            self.failures -= 1;
+            return Err(anyhow::anyhow!("read glitch"));
+        }
+        self.recorder.write_with_retry(write)?;
+        self.recorder.read(read)
+    }
+}
+
+#[test]
+fn verify_retries_reads_before_reentering() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let recorder = Recorder {
+        sim,
+        writes: Vec::new(),
+    };
+    let mut bootloader = TwiBootloader::new(GlitchyReads {
+        recorder,
+        failures: 0,
+        exhausted: false,
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_verify_read_retry_delay(Duration::ZERO);
+    bootloader.connect(false).unwrap();
+    let data = image(200);
+    bootloader.write_flash(&data).unwrap();
+
+    let switches = |bootloader: &TwiBootloader<GlitchyReads>| {
+        let writes = &bootloader.transport().recorder.writes;
+        writes.iter().filter(|w| **w == [0x01, 0x00]).count()
+    };
+
+    // Two glitches are absorbed by the retries, only the usual switch at the start
+    bootloader.transport_mut().failures = 2;
+    let before = switches(&bootloader);
+    bootloader.verify_flash(&data).unwrap();
+    assert_eq!(switches(&bootloader) - before, 1);
+
+    // Without retries the first glitch re-enters the bootloader
+    bootloader.set_verify_read_retries(0);
+    bootloader.transport_mut().failures = 1;
+    let before = switches(&bootloader);
+    bootloader.verify_flash(&data).unwrap();
+    assert_eq!(switches(&bootloader) - before, 2);
+}
+
+#[test]
+fn exhausted_retry_budget_ends_verify_right_away() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let recorder = Recorder {
+        sim,
+        writes: Vec::new(),
+    };
+    let mut bootloader = TwiBootloader::new(GlitchyReads {
+        recorder,
+        failures: 0,
+        exhausted: false,
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_verify_read_retry_delay(Duration::ZERO);
+    bootloader.connect(false).unwrap();
+    let data = image(200);
+    bootloader.write_flash(&data).unwrap();
+
+    bootloader.transport_mut().exhausted = true;
+    bootloader.transport_mut().recorder.writes.clear();
+    let err = bootloader.verify_flash(&data).unwrap_err();
+    assert!(is_retry_budget_exhausted(&err), "{:#}", err);
+
+    // No verify read retries and no bootloader re-entry, only the switch at the start
+    let writes = &bootloader.transport().recorder.writes;
+    assert_eq!(writes, &[vec![0x01, 0x00]]);
+}
+
+// Fails every write, with `fatal` like a TwiI2CDevice whose adapter is gone,
+// otherwise like a slave that doesn't acknowledge. Counts the attempts.
+struct DeadBus {
+    fatal: bool,
+    writes: usize,
+}
+
+impl I2CTransport for DeadBus {
+    fn address(&self) -> u8 {
+        ADDRESS
+    }
+
+    fn write_with_retry(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+        self.writes += 1;
+        if self.fatal {
+            return Err(FatalI2CError("I2C write failed: No such device".to_string()).into());
+        }
+        Err(anyhow::anyhow!("I2C write failed: Remote I/O error"))
+    }
+
+    fn read(&mut self, _buffer: &mut [u8]) -> anyhow::Result<usize> {
+        Err(anyhow::anyhow!("I2C read failed: Remote I/O error"))
+    }
+
+    fn write_then_read_once(&mut self, _write: &[u8], _read: &mut [u8]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("I2C read failed: Remote I/O error"))
+    }
+}
+
+#[test]
+fn fatal_errors_end_connect_right_away() {
+    let mut bootloader = TwiBootloader::new(DeadBus {
+        fatal: true,
+        writes: 0,
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_switch_retries(2);
+    let err = bootloader.connect(false).unwrap_err();
+    assert!(retry_is_pointless(&err), "{:#}", err);
+    assert_eq!(bootloader.transport().writes, 1);
+
+    // Anything else is worth the switch retries
+    let mut bootloader = TwiBootloader::new(DeadBus {
+        fatal: false,
+        writes: 0,
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_switch_retries(2);
+    let err = bootloader.connect(false).unwrap_err();
+    assert!(!retry_is_pointless(&err), "{:#}", err);
+    assert_eq!(bootloader.transport().writes, 3);
+}
+
+// The simulator behind a TwiI2CDevice, losing arbitration (EAGAIN) on the first
+// `losses` writes and not acknowledging (ENXIO) the `nacks` after them
+struct ContendedBus {
+    sim: SimulatedBootloader,
+    losses: usize,
+    nacks: usize,
+}
+
+impl RawI2CDevice for ContendedBus {
+    fn write(&mut self, data: &[u8]) -> Result<(), LinuxI2CError> {
+        if self.losses > 0 {
+            self.losses -= 1;
+            return Err(LinuxI2CError::Errno(libc::EAGAIN));
+        }
+        if self.nacks > 0 {
+            self.nacks -= 1;
+            return Err(LinuxI2CError::Errno(libc::ENXIO));
+        }
+        self.sim
+            .write_with_retry(data)
+            .map_err(|_| LinuxI2CError::Errno(libc::EREMOTEIO))
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<(), LinuxI2CError> {
+        self.sim
+            .read(buffer)
+            .map(|_| ())
+            .map_err(|_| LinuxI2CError::Errno(libc::EREMOTEIO))
+    }
+}
+
+#[test]
+fn lost_arbitration_is_counted_on_its_own() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let bus = ContendedBus {
+        sim,
+        losses: 3,
+        nacks: 2,
+    };
+    let mut bootloader = TwiBootloader::new(TwiI2CDevice::with_device(bus, ADDRESS));
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_quiet(true);
+
+    let report = bootloader.flash(&image(100), false, true).unwrap();
+    assert_eq!(report.retries, 5);
+    assert_eq!(report.arbitration_losses, 3);
+    assert!(report
+        .to_json(None)
+        .contains(r#""retries":5,"arbitration_losses":3,"#));
+}
+
+// Lets a test look at the simulator after the bootloader is gone
+struct Shared(Rc<RefCell<SimulatedBootloader>>);
+
+impl I2CTransport for Shared {
+    fn address(&self) -> u8 {
+        self.0.borrow().address()
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.0.borrow_mut().write_with_retry(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        self.0.borrow_mut().read(buffer)
+    }
+
+    fn write_then_read_once(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        self.0.borrow_mut().write_then_read_once(write, read)
+    }
+}
+
+#[test]
+fn drop_starts_the_application_unless_disabled() {
+    let run = |switch_on_drop: bool| {
+        let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+        let sim = Rc::new(RefCell::new(sim));
+        let mut bootloader = TwiBootloader::new(Shared(Rc::clone(&sim)));
+        bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+        bootloader.set_switch_on_drop(switch_on_drop);
+        bootloader.connect(false).unwrap();
+        drop(bootloader);
+        let mode = sim.borrow().mode();
+        mode
+    };
+
+    assert_eq!(run(true), SimulatedMode::Application);
+    assert_eq!(run(false), SimulatedMode::Bootloader);
+}
+
+#[test]
+fn flash_reports_the_whole_run() {
+    let mut bootloader = attiny84();
+    let report = bootloader.flash(&image(200), false, true).unwrap();
+    assert!(report.connected && report.verified);
+    assert_eq!(report.bytes_written, 200);
+    assert_eq!(report.final_mode, DeviceMode::Application);
+
+    // A write cut short leaves the device in the bootloader
+    let mut bootloader = attiny84();
+    let err = bootloader
+        .flash(&image(0x1C00 + 64), false, true)
+        .unwrap_err();
+    let report = &err.downcast_ref::<FlashError>().unwrap().report;
+    assert!(report.connected && !report.verified);
+    assert_eq!(report.final_mode, DeviceMode::Bootloader);
+}
+
+#[test]
+fn failed_verify_keeps_the_device_in_the_bootloader() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    let data = image(200);
+    bootloader.write_flash(&data).unwrap();
+    bootloader.transport_mut().flash_mut()[100] ^= 0x01;
+    let err = bootloader.verify_flash(&data).unwrap_err();
+    assert!(err.downcast_ref::<VerifyError>().is_some(), "{:#}", err);
+
+    bootloader.release();
+    assert_eq!(bootloader.mode(), DeviceMode::Bootloader);
+    assert_eq!(bootloader.transport().mode(), SimulatedMode::Bootloader);
+
+    // Once what's on the device verifies, it may be started
+    bootloader.transport_mut().flash_mut()[100] ^= 0x01;
+    bootloader.verify_flash(&data).unwrap();
+    bootloader.release();
+    assert_eq!(bootloader.mode(), DeviceMode::Application);
+}
+
+#[test]
+fn unverified_write_is_only_started_when_accepted() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&image(200)).unwrap();
+    bootloader.release();
+    assert_eq!(bootloader.mode(), DeviceMode::Bootloader);
+
+    bootloader.accept_unverified();
+    bootloader.release();
+    assert_eq!(bootloader.mode(), DeviceMode::Application);
+}
+
+#[test]
+fn patch_keeps_the_rest_of_the_pages() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    let data = image(256);
+    bootloader.write_flash(&data).unwrap();
+
+    // Straddles the page boundary at 0x80
+    let summary = bootloader
+        .patch_flash(0x7E, &[0xDE, 0xAD, 0xBE, 0xEF])
+        .unwrap();
+    assert_eq!(summary.pages_written, 2);
+
+    let mut expected = data.clone();
+    expected[0x7E..0x82].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+    assert_eq!(bootloader.read_flash(0, 256).unwrap(), expected);
+}
+
+#[test]
+fn patch_stays_below_the_bootloader() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+
+    let err = bootloader.patch_flash(0x1BFE, &[1, 2, 3, 4]).unwrap_err();
+    assert!(
+        err.to_string().contains("exceeds available flash space"),
+        "{}",
+        err
+    );
+    assert_eq!(bootloader.transport().page_writes(), 0);
+}
+
+#[test]
+fn reset_vector_check_reads_only_the_vector() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    let data = image(200);
+    bootloader.write_flash(&data).unwrap();
+    bootloader.check_reset_vector(&data).unwrap();
+
+    // Damage past the vector goes unnoticed, that's what a full verify is for
+    bootloader.transport_mut().flash_mut()[4] ^= 0xFF;
+    bootloader.check_reset_vector(&data).unwrap();
+
+    bootloader.transport_mut().flash_mut()[1] ^= 0xFF;
+    let err = bootloader.check_reset_vector(&data).unwrap_err();
+    assert!(
+        err.to_string().contains("Reset vector check failed"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn reads_eeprom() {
+    let mut bootloader = attiny84();
+    bootloader.transport_mut().eeprom_mut()[0x10..0x18].copy_from_slice(b"SN001234");
+
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.read_eeprom(0x10, 8).unwrap(), b"SN001234");
+}
+
+#[test]
+fn writes_eeprom() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+
+    let data: Vec<u8> = (0..40).collect();
+    bootloader.write_eeprom(0x100, &data).unwrap();
+    assert_eq!(
+        &bootloader.transport_mut().eeprom_mut()[0x100..0x128],
+        &data[..]
+    );
+    assert!(bootloader.write_eeprom(0x1F0, &data).is_err());
+}
+
+#[test]
+fn block_sizes_are_configurable() {
+    let mut bootloader = attiny84();
+    bootloader.set_read_block_size(7);
+    bootloader.set_write_page_size(Some(128));
+
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.page_size(), 128);
+    assert!(
+        bootloader.write_flash(&image(100)).is_err(),
+        "simulator takes 64 byte pages only"
+    );
+
+    let mut bootloader = attiny84();
+    bootloader.set_read_block_size(7);
+    bootloader.connect(false).unwrap();
+    let data = image(300);
+    bootloader.write_flash(&data).unwrap();
+    assert_eq!(bootloader.verify_flash(&data).unwrap().bytes_verified, 300);
+
+    let mut bootloader = attiny84();
+    bootloader.set_write_page_size(Some(48));
+    assert!(bootloader.connect(false).is_err());
+}
+
+#[test]
+fn link_test_on_a_stable_bus() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+
+    let summary = bootloader.link_test(5).unwrap();
+    assert_eq!(summary.rounds, 5);
+    assert_eq!(summary.mismatches, 0);
+    assert_eq!(summary.retries, 0);
+}
+
+// Flips a bit in every `flip_every`th read and counts it as a transport retry,
+// like a marginal bus where not every glitch is caught
+struct NoisyBus {
+    sim: SimulatedBootloader,
+    reads: usize,
+    flip_every: usize,
+    retries: usize,
+}
+
+impl I2CTransport for NoisyBus {
+    fn address(&self) -> u8 {
+        self.sim.address()
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.sim.write_with_retry(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        let len = self.sim.read(buffer)?;
+        self.reads += 1;
+        if self.flip_every > 0 && self.reads.is_multiple_of(self.flip_every) {
+            buffer[0] ^= 0x01;
+            self.retries += 1;
+        }
+        Ok(len)
+    }
+
+    fn write_then_read_once(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        self.sim.write_then_read_once(write, read)
+    }
+
+    fn retries(&self) -> usize {
+        self.retries
+    }
+}
+
+#[test]
+fn link_test_on_an_unstable_bus() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(NoisyBus {
+        sim,
+        reads: 0,
+        flip_every: 0,
+        retries: 0,
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.connect(false).unwrap();
+
+    // Every 4th read is the chipinfo of every other round after the first
+    bootloader.transport_mut().reads = 0;
+    bootloader.transport_mut().flip_every = 4;
+    let summary = bootloader.link_test(6).unwrap();
+    assert_eq!(summary.rounds, 6);
+    assert_eq!(summary.mismatches, 3);
+    assert_eq!(summary.retries, 3);
+}
+
+#[test]
+fn measure_clock_times_reads() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    let writes_before = bootloader.transport().page_writes();
+
+    let estimate = bootloader.measure_clock(3).unwrap();
+    assert_eq!(estimate.rounds, 3);
+    assert!(estimate.clock_hz.is_none_or(|hz| hz > 0.0));
+    assert_eq!(bootloader.transport().page_writes(), writes_before);
+}
+
+#[test]
+fn known_part_overrides_bogus_page_size() {
+    // ATtiny84 signature, but chipinfo claims 32 byte pages
+    let bogus = || SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 32, 0x1C00, 0x2000);
+
+    let mut bootloader = fast(TwiBootloader::new(bogus()));
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.part().map(|part| part.name), Some("ATtiny84"));
+    assert_eq!(bootloader.page_size(), 32);
+
+    let mut bootloader = fast(TwiBootloader::new(bogus()));
+    bootloader.set_use_part_defaults(true);
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.page_size(), 64);
+
+    let mut bootloader = ch32v003();
+    bootloader.connect(false).unwrap();
+    assert!(bootloader.part().is_none());
+}
+
+#[test]
+fn verify_can_read_in_write_pages() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(Recorder {
+        sim,
+        writes: Vec::new(),
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_verify_matches_write(true);
+
+    bootloader.connect(false).unwrap();
+    let data = image(200);
+    bootloader.write_flash(&data).unwrap();
+    bootloader.transport_mut().writes.clear();
+    let tail = 10..200;
+    let verified = bootloader
+        .verify_flash_ranges(&data, std::slice::from_ref(&tail))
+        .unwrap();
+    assert_eq!(verified.bytes_verified, 190);
+
+    let reads: Vec<u16> = bootloader
+        .transport()
+        .writes
+        .iter()
+        .filter(|w| w[..2] == [0x02, 0x01])
+        .map(|w| u16::from_be_bytes([w[2], w[3]]))
+        .collect();
+    assert_eq!(reads, vec![10, 64, 128, 192]);
+}
+
+#[test]
+fn single_pages_by_index() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+
+    bootloader.write_page(3, &[0xAA; 10]).unwrap();
+    let page = bootloader.read_page(3).unwrap();
+    assert_eq!(page.len(), 64);
+    assert_eq!(&page[..10], &[0xAA; 10]);
+    assert!(page[10..].iter().all(|&b| b == 0xFF));
+    assert_eq!(
+        &bootloader.transport().flash()[3 * 64..3 * 64 + 10],
+        &[0xAA; 10]
+    );
+
+    // 0x1C00 / 64 = 112 is the first bootloader page
+    assert!(bootloader.read_page(112).is_err());
+    assert!(bootloader.write_page(112, &[0x00]).is_err());
+    assert!(bootloader.write_page(0, &[0x00; 65]).is_err());
+}
+
+// Flips a bit in every write to one page address, like a flash cell that won't program
+struct StuckPage {
+    sim: SimulatedBootloader,
+    page: [u8; 2],
+}
+
+impl I2CTransport for StuckPage {
+    fn address(&self) -> u8 {
+        self.sim.address()
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut data = data.to_vec();
+        if data.len() > 4 && data[..2] == [0x02, 0x01] && data[2..4] == self.page {
+            data[4] ^= 0x01;
+        }
+        self.sim.write_with_retry(&data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        self.sim.read(buffer)
+    }
+
+    fn write_then_read_once(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        self.sim.write_then_read_once(write, read)
+    }
+}
+
+#[test]
+fn interleaved_verify_stops_at_the_bad_page() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(StuckPage {
+        sim,
+        page: [0x00, 0x80],
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_interleave_verify(true);
+
+    bootloader.connect(false).unwrap();
+    let err = bootloader.write_flash(&image(400)).unwrap_err();
+    assert!(err.to_string().contains("Page 2 at 0x00000080"), "{}", err);
+
+    // Pages 0 and 1 plus two attempts at page 2, nothing after it
+    let sim = &bootloader.transport().sim;
+    assert_eq!(sim.page_writes(), 4);
+    assert!(sim.flash()[0xC0..0x100].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn verify_with_page_checksums() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_page_checksums();
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    bootloader.connect(false).unwrap();
+
+    let data = image(300);
+    bootloader.write_flash(&data).unwrap();
+    let verified = bootloader.verify_flash_pagesum(&data).unwrap();
+    assert_eq!(verified.bytes_verified, 300);
+
+    bootloader.transport_mut().flash_mut()[0x85] ^= 0x10;
+    let err = bootloader.verify_flash_pagesum(&data).unwrap_err();
+    let failure = err.downcast_ref::<VerifyError>().unwrap();
+    assert_eq!(failure.first_mismatch, 0x85);
+    assert_eq!(failure.summary.mismatches, 1);
+
+    // Stock bootloader: falls back to reading back
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&data).unwrap();
+    assert!(bootloader.verify_flash_pagesum(&data).is_ok());
+}
+
+#[test]
+fn page_checksums_are_only_read_on_request() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_page_checksums();
+    let mut bootloader = TwiBootloader::new(Recorder {
+        sim,
+        writes: Vec::new(),
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    bootloader.set_skip_unchanged(true);
+    bootloader.set_interleave_verify(true);
+    let pagesum_reads = |bootloader: &TwiBootloader<Recorder>| {
+        let writes = &bootloader.transport().writes;
+        writes
+            .iter()
+            .filter(|w| w.starts_with(&[0x02, 0x08]))
+            .count()
+    };
+
+    // Memtype 0x08 isn't part of twiboot, a stock device never gets it unasked
+    let data = image(300);
+    bootloader.connect(false).unwrap();
+    bootloader.write_flash(&data).unwrap();
+    bootloader.verify_flash(&data).unwrap();
+    assert_eq!(pagesum_reads(&bootloader), 0);
+
+    bootloader.verify_flash_pagesum(&data).unwrap();
+    assert_eq!(pagesum_reads(&bootloader), 5);
+}
+
+#[test]
+fn no_pad_refuses_partial_pages() {
+    let mut bootloader = attiny84();
+    bootloader.set_no_pad(true);
+    bootloader.connect(false).unwrap();
+
+    assert!(bootloader.write_flash(&image(100)).is_err());
+    assert_eq!(bootloader.transport().page_writes(), 0);
+    assert_eq!(
+        bootloader.write_flash(&image(128)).unwrap().padding_bytes,
+        0
+    );
+}
+
+#[test]
+fn chip_info_is_exposed() {
+    let mut bootloader = attiny84();
+    assert_eq!(bootloader.chip_info(), None);
+    bootloader.connect(false).unwrap();
+    assert_eq!(
+        bootloader.chip_info(),
+        Some(ChipInfo::Avr {
+            signature: [0x1E, 0x93, 0x0C],
+            page_size: 64,
+            boot_start: 0x1C00,
+            eeprom_size: 512,
+        })
+    );
+
+    let mut bootloader = ch32v003();
+    bootloader.connect(false).unwrap();
+    assert_eq!(
+        bootloader.chip_info(),
+        Some(ChipInfo::Ch32v {
+            chip_id: 0x0030_0500,
+            flash_size: 0x3800,
+            page_size: 64,
+        })
+    );
+}
+
+#[test]
+fn connect_output_can_be_captured() {
+    let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut bootloader = attiny84();
+    let sink = Arc::clone(&lines);
+    bootloader.set_output(move |line| sink.lock().unwrap().push(line.to_string()));
+
+    bootloader.connect(false).unwrap();
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines[0], "Version: TWIBOOT v3.2");
+    assert!(lines.iter().any(|line| line == "Part: ATtiny84"));
+}
+
+#[test]
+fn warnings_go_to_the_output_function() {
+    // ATtiny84 signature, but 32 byte pages
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 32, 0x1C00, 0x2000);
+    let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    let sink = Arc::clone(&lines);
+    bootloader.set_output(move |line| sink.lock().unwrap().push(line.to_string()));
+    bootloader.connect(false).unwrap();
+
+    let lines = lines.lock().unwrap();
+    assert!(
+        lines.contains(
+            &"Warning: device reports 32 byte pages, the ATtiny84 has 64 (see --part-defaults)"
+                .to_string()
+        ),
+        "{:?}",
+        lines
+    );
+}
+
+#[test]
+fn physical_flash_of_known_parts() {
+    let mut bootloader = attiny84();
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.flash_size(), 0x1C00);
+    assert_eq!(bootloader.physical_flash_size(), Some(0x2000));
+
+    let mut bootloader = ch32v003();
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.physical_flash_size(), None);
+}
+
+#[test]
+fn lenient_erased_ignores_the_tail_of_the_last_page() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_page_checksums();
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    bootloader.connect(false).unwrap();
+
+    let data = image(100);
+    bootloader.write_flash(&data).unwrap();
+    bootloader.transport_mut().flash_mut()[100..128].fill(0x00);
+    assert!(bootloader.verify_flash(&data).is_ok());
+    assert!(bootloader.verify_flash_pagesum(&data).is_err());
+
+    bootloader.set_lenient_erased(true);
+    assert!(bootloader.verify_flash_pagesum(&data).is_ok());
+    bootloader.transport_mut().flash_mut()[99] ^= 0x01;
+    assert!(bootloader.verify_flash_pagesum(&data).is_err());
+}
+
+// Nothing on the bus at all
+struct EmptyBus;
+
+impl I2CTransport for EmptyBus {
+    fn address(&self) -> u8 {
+        ADDRESS
+    }
+
+    fn write_with_retry(&mut self, _data: &[u8]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("NAK"))
+    }
+
+    fn read(&mut self, _buffer: &mut [u8]) -> anyhow::Result<usize> {
+        Err(anyhow::anyhow!("NAK"))
+    }
+
+    fn write_then_read_once(&mut self, _write: &[u8], _read: &mut [u8]) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("NAK"))
+    }
+}
+
+// Application that ignores the first `busy` switch commands
+struct BusyApp {
+    sim: SimulatedBootloader,
+    busy: usize,
+}
+
+impl I2CTransport for BusyApp {
+    fn address(&self) -> u8 {
+        ADDRESS
+    }
+
+    fn write_with_retry(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if data == [0x01, 0x00] && self.busy > 0 {
+            self.busy -= 1;
+            return Err(anyhow::anyhow!("NAK"));
+        }
+        self.sim.write_with_retry(data)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<usize> {
+        self.sim.read(buffer)
+    }
+
+    fn write_then_read_once(&mut self, write: &[u8], read: &mut [u8]) -> anyhow::Result<()> {
+        self.sim.write_then_read_once(write, read)
+    }
+}
+
+#[test]
+fn connect_retries_a_busy_application() {
+    let busy_app = || BusyApp {
+        sim: SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000),
+        busy: 1,
+    };
+
+    let mut bootloader = TwiBootloader::new(busy_app());
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_quiet(true);
+    bootloader.connect(false).unwrap();
+    assert_eq!(bootloader.version(), "TWIBOOT v3.2");
+
+    let mut bootloader = TwiBootloader::new(busy_app());
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_switch_retries(0);
+    assert!(bootloader.connect(false).is_err());
+}
+
+#[test]
+fn switch_retries_stop_at_the_connect_timeout() {
+    let mut bootloader = TwiBootloader::new(BusyApp {
+        sim: SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000),
+        busy: usize::MAX,
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_quiet(true);
+    bootloader.set_switch_retries(10);
+    bootloader.set_connect_timeout(Some(Duration::from_millis(150)));
+
+    // Uncut, the pauses would add up to more than 15s
+    let start = Instant::now();
+    let err = bootloader.connect(false).unwrap_err();
+    assert_eq!(err.to_string(), "No bootloader connection within 150ms");
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[test]
+fn connect_errors_name_the_stage() {
+    let mut bootloader = TwiBootloader::new(EmptyBus);
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_switch_retries(0);
+    let err = bootloader.connect(false).unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("Switch command not acknowledged"),
+        "{}",
+        err
+    );
+
+    bootloader.set_quiet(true);
+    bootloader.set_connect_timeout(Some(Duration::from_millis(50)));
+    let err = bootloader.connect(true).unwrap_err();
+    assert_eq!(err.to_string(), "No bootloader connection within 50ms");
+}
+
+// Spans as "name{fields}" when created, events as "  in <span>: fields"
+#[cfg(feature = "trace")]
+struct Timeline(Arc<Mutex<Vec<String>>>);
+
+#[cfg(feature = "trace")]
+struct Fields(String);
+
+#[cfg(feature = "trace")]
+impl tracing::field::Visit for Fields {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() != "message" {
+            self.0 += &format!(" {}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(feature = "trace")]
+impl<S> tracing_subscriber::Layer<S> for Timeline
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        _id: &tracing::span::Id,
+        _ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut fields = Fields(String::new());
+        attrs.record(&mut fields);
+        self.0.lock().unwrap().push(format!(
+            "{}{{{}}}",
+            attrs.metadata().name(),
+            fields.0.trim()
+        ));
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let mut fields = Fields(String::new());
+        event.record(&mut fields);
+        let span = ctx.event_span(event).map_or("-", |span| span.name());
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("  in {}:{}", span, fields.0));
+    }
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn phases_are_tracing_spans() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let timeline = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(Timeline(Arc::clone(&timeline)));
+    let mut bootloader = attiny84();
+    bootloader.set_read_block_size(64);
+    tracing::subscriber::with_default(subscriber, || {
+        bootloader.flash(&image(100), false, true).unwrap();
+    });
+
+    let timeline = timeline.lock().unwrap();
+    assert_eq!(
+        *timeline,
+        [
+            "connect{address=41 wait=false}",
+            "write_flash{address=41 bytes=100}",
+            "  in write_flash: page=0 addr=0 retries=0",
+            "  in write_flash: page=1 addr=64 retries=0",
+            "verify_flash{address=41 bytes=100}",
+            "  in verify_flash: addr=0 len=64 retries=0",
+            "  in verify_flash: addr=64 len=36 retries=0",
+            "disconnect{address=41}",
+        ]
+    );
+}
+
+#[test]
+fn address_encoding() {
+    assert_eq!(
+        encode_address(0x1234, AddressWidth::Bits16, AddressEndian::Big),
+        [0x12, 0x34]
+    );
+    assert_eq!(
+        encode_address(0x1234, AddressWidth::Bits16, AddressEndian::Little),
+        [0x34, 0x12]
+    );
+    assert_eq!(
+        encode_address(0x0800_1234, AddressWidth::Bits32, AddressEndian::Little),
+        [0x34, 0x12, 0x00, 0x08]
+    );
+}
+
+#[test]
+fn little_endian_variant_round_trip() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_little_endian_addresses();
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    bootloader.set_address_endian(AddressEndian::Little);
+    bootloader.connect(false).unwrap();
+
+    let data = image(200);
+    bootloader.write_flash(&data).unwrap();
+    bootloader.verify_flash(&data).unwrap();
+    assert_eq!(&bootloader.transport().flash()[..200], &data[..]);
+}
+
+#[test]
+fn bootloader_with_hooks_can_run_on_another_thread() {
+    let messages = Arc::new(Mutex::new(0));
+    let mut bootloader = attiny84();
+    let sink = Arc::clone(&messages);
+    bootloader.set_output(move |_| *sink.lock().unwrap() += 1);
+
+    let report = std::thread::spawn(move || bootloader.flash(&image(100), false, true))
+        .join()
+        .unwrap()
+        .unwrap();
+    assert!(report.verified);
+    assert!(*messages.lock().unwrap() > 0);
+}
+
+#[test]
+fn descending_verify_reads_from_the_top() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::new(Recorder {
+        sim,
+        writes: Vec::new(),
+    });
+    bootloader.set_switch_to_bootloader_delay(Duration::ZERO);
+    bootloader.set_reentry_delay(Duration::ZERO);
+    bootloader.set_write_delay(Duration::ZERO);
+    // Flash read commands: read memory, memtype flash, 16 bit address
+    let reads = |bootloader: &mut TwiBootloader<Recorder>| -> Vec<u16> {
+        let writes = std::mem::take(&mut bootloader.transport_mut().writes);
+        writes
+            .iter()
+            .filter(|w| w.len() == 4 && w[..2] == [0x02, 0x01])
+            .map(|w| u16::from_be_bytes([w[2], w[3]]))
+            .collect()
+    };
+    bootloader.set_read_block_size(64);
+    bootloader.set_verify_order(VerifyOrder::Descending);
+    bootloader.connect(false).unwrap();
+
+    let mut data = image(200);
+    bootloader.write_flash(&data).unwrap();
+    reads(&mut bootloader);
+    bootloader.verify_flash(&data).unwrap();
+    assert_eq!(reads(&mut bootloader), [192, 128, 64, 0]);
+
+    data[10] ^= 0xFF;
+    data[150] ^= 0xFF;
+    let err = bootloader.verify_flash(&data).unwrap_err();
+    // The block at 128 is bad too, but the reported mismatch is the lowest one
+    let err = err.downcast_ref::<VerifyError>().unwrap();
+    assert_eq!(err.first_mismatch, 10);
+    assert_eq!(err.summary.mismatches, 1);
+    assert_eq!(reads(&mut bootloader), [192, 128, 64, 0]);
+}
+
+#[test]
+fn no_final_pad_leaves_the_rest_of_the_last_page() {
+    let mut sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_partial_pages()
+        .with_page_checksums();
+    sim.flash_mut()[100..128].fill(0x55);
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    bootloader.set_no_final_pad(true);
+    bootloader.set_interleave_verify(true);
+    bootloader.connect(false).unwrap();
+
+    let data = image(100);
+    let written = bootloader.write_flash(&data).unwrap();
+    assert_eq!(written.padding_bytes, 0);
+    bootloader.verify_flash(&data).unwrap();
+    bootloader.verify_flash_pagesum(&data).unwrap();
+    assert_eq!(&bootloader.transport().flash()[..100], &data[..]);
+    assert!(bootloader.transport().flash()[100..128]
+        .iter()
+        .all(|&b| b == 0x55));
+}
+
+#[test]
+fn builder_applies_options() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000);
+    let mut bootloader = TwiBootloader::builder(sim)
+        .switch_to_bootloader_delay(Duration::ZERO)
+        .reentry_delay(Duration::ZERO)
+        .write_delay(Duration::ZERO)
+        .flash_fill(0x00)
+        .quiet(true)
+        .build();
+    bootloader.connect(false).unwrap();
+
+    bootloader.write_flash(&image(10)).unwrap();
+    assert!(bootloader.transport().flash()[10..64]
+        .iter()
+        .all(|&b| b == 0x00));
+}
+
+#[test]
+fn write_status_reports_device_errors() {
+    let sim = SimulatedBootloader::avr(ADDRESS, [0x1E, 0x93, 0x0C], 64, 0x1C00, 0x2000)
+        .with_write_status()
+        .with_write_error(128, 0x03);
+    let mut bootloader = fast(TwiBootloader::new(sim));
+    bootloader.set_write_status(true);
+    bootloader.connect(false).unwrap();
+
+    let err = bootloader.write_flash(&image(256)).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("error 0x03 writing page at 0x00000080"),
+        "{:#}",
+        err
+    );
+    assert_eq!(bootloader.transport().page_writes(), 2);
+
+    // Pages the device accepts pass the status check
+    bootloader.write_flash(&image(128)).unwrap();
+}
+
+#[test]
+fn page_operations_need_connect() {
+    let mut bootloader = attiny84();
+    let data = image(100);
+
+    for err in [
+        bootloader.write_flash(&data).unwrap_err(),
+        bootloader.verify_flash(&data).unwrap_err(),
+        bootloader.erase_flash().unwrap_err(),
+        bootloader.write_page(0, &data[..64]).unwrap_err(),
+    ] {
+        assert!(err.to_string().starts_with("Not connected"), "{}", err);
+    }
+    assert_eq!(bootloader.transport().page_writes(), 0);
+}
+
+#[test]
+fn mixed_radix_shows_hex_and_decimal() {
+    let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut bootloader = attiny84();
+    let sink = Arc::clone(&lines);
+    bootloader.set_output(move |line| sink.lock().unwrap().push(line.to_string()));
+    bootloader.connect(false).unwrap();
+
+    let lines = lines.lock().unwrap();
+    assert!(
+        lines.contains(&"Flash size: 0x00001C00 / 7168 bytes [7KB] (64 bytes/page)".to_string())
+    );
+    assert!(lines.contains(&"Device: I2C address 0x29".to_string()));
+}